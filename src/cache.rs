@@ -0,0 +1,126 @@
+//! Daemon-side caching of source metadata
+
+use fuser::FileAttr;
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// Attributes recently read from the source, keyed by inode.
+///
+/// This sits in front of the kernel's own attribute cache: when the kernel
+/// asks again, a hit within the TTL skips the stat on the source.
+pub(crate) struct AttrCache {
+    /// How long an entry stays valid, or None when caching is disabled
+    ttl: Option<Duration>,
+    /// Cached attributes and the instant they expire
+    entries: RwLock<HashMap<u64, (FileAttr, Instant)>>,
+}
+
+impl AttrCache {
+    pub(crate) fn new(ttl: Option<Duration>) -> Self {
+        AttrCache {
+            ttl,
+            entries: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Get the cached attributes of an inode if they have not expired
+    pub(crate) fn get(&self, inode: u64) -> Option<FileAttr> {
+        self.ttl?;
+        let entries = self.entries.read().unwrap();
+        entries
+            .get(&inode)
+            .filter(|(_, deadline)| Instant::now() < *deadline)
+            .map(|(attr, _)| *attr)
+    }
+
+    /// Remember freshly read attributes
+    pub(crate) fn insert(&self, attr: &FileAttr) {
+        if let Some(ttl) = self.ttl {
+            self.entries
+                .write()
+                .unwrap()
+                .insert(attr.ino, (*attr, Instant::now() + ttl));
+        }
+    }
+
+    /// Drop the cached attributes of an inode after it changed
+    pub(crate) fn invalidate(&self, inode: u64) {
+        if self.ttl.is_some() {
+            self.entries.write().unwrap().remove(&inode);
+        }
+    }
+}
+
+/// Writes made through the mount, so the source watcher can tell them from
+/// changes made behind its back. The kernel's pages already hold what the
+/// mount wrote, so only attributes need invalidating for those.
+#[derive(Default)]
+pub(crate) struct OwnWrites {
+    /// Inode to its writes in flight, and whether one finished since the
+    /// watcher last asked
+    inodes: Mutex<HashMap<u64, (usize, bool)>>,
+}
+
+impl OwnWrites {
+    /// Run `write` on `inode` through the mount, returning whether it wrote
+    /// anything. Writes are counted before they start, as the event for one
+    /// may be read while it is still in flight.
+    pub(crate) fn write<T>(&self, inode: u64, write: impl FnOnce() -> (T, bool)) -> T {
+        self.inodes.lock().unwrap().entry(inode).or_default().0 += 1;
+        let (result, wrote) = write();
+        let mut inodes = self.inodes.lock().unwrap();
+        if let Some((in_flight, finished)) = inodes.get_mut(&inode) {
+            *in_flight -= 1;
+            *finished |= wrote;
+            if *in_flight == 0 && !*finished {
+                inodes.remove(&inode);
+            }
+        }
+        result
+    }
+
+    /// Whether a modification seen on `inode` may have come from the mount,
+    /// forgetting finished writes
+    pub(crate) fn take(&self, inode: u64) -> bool {
+        let mut inodes = self.inodes.lock().unwrap();
+        let Some((in_flight, finished)) = inodes.get_mut(&inode) else {
+            return false;
+        };
+        let own = *in_flight > 0 || *finished;
+        *finished = false;
+        if *in_flight == 0 {
+            inodes.remove(&inode);
+        }
+        own
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_own_writes_cover_their_events_once() {
+        let own_writes = OwnWrites::default();
+        assert!(!own_writes.take(1));
+
+        own_writes.write(1, || ((), true));
+        assert!(own_writes.take(1));
+        // A later change is someone else's
+        assert!(!own_writes.take(1));
+
+        // Failed writes cause no event to cover
+        own_writes.write(1, || ((), false));
+        assert!(!own_writes.take(1));
+
+        // Nor does a write still in flight lose its cover to an earlier one
+        own_writes.write(1, || {
+            assert!(own_writes.take(1));
+            assert!(own_writes.take(1));
+            ((), true)
+        });
+        assert!(own_writes.take(1));
+        assert!(!own_writes.take(1));
+    }
+}
@@ -0,0 +1,196 @@
+//! SHA-256 checksums of file contents, kept in an extended attribute
+//!
+//! A file changed through the mount gets the hex digest of its contents
+//! stored in `user.passthrough.sha256` once its last handle is released.
+//! Sequential writes from the start are hashed as they happen; any other
+//! change, like a truncate or a write out of order, has the file hashed
+//! again at release. Each handle checks the file against its stored digest
+//! before its first read, so contents changed behind the mount's back fail
+//! with EIO. Files without a digest are read unchecked.
+
+use crate::{retry_eintr, xattr};
+use log::error;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Attribute holding the digest
+pub(crate) const XATTR_NAME: &str = "user.passthrough.sha256";
+
+/// Hex digest length, which is all a stored value is read up to
+const HEX_LEN: usize = 64;
+
+/// Chunk size for hashing whole files
+const CHUNK: usize = 1024 * 1024;
+
+/// Digest of the contents written so far, while the writes run in order
+struct Pending {
+    hasher: Option<Sha256>,
+    next: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct Checksums {
+    /// Inodes changed through the mount since their digest was stored
+    pending: Mutex<HashMap<u64, Pending>>,
+    /// Handles already checked, and whether their file matched
+    checked: Mutex<HashMap<u64, bool>>,
+}
+
+impl Checksums {
+    /// Note `data` written to `ino` at `offset`
+    pub(crate) fn wrote(&self, ino: u64, offset: u64, data: &[u8]) {
+        let mut pending = self.pending.lock().unwrap();
+        let pending = pending.entry(ino).or_insert_with(|| Pending {
+            hasher: Some(Sha256::new()),
+            next: 0,
+        });
+        match &mut pending.hasher {
+            Some(hasher) if offset == pending.next => {
+                hasher.update(data);
+                pending.next += data.len() as u64;
+            }
+            _ => pending.hasher = None,
+        }
+    }
+
+    /// Note a change to `ino` other than a write, so it is hashed in full
+    pub(crate) fn changed(&self, ino: u64) {
+        let mut pending = self.pending.lock().unwrap();
+        pending
+            .entry(ino)
+            .or_insert(Pending {
+                hasher: None,
+                next: 0,
+            })
+            .hasher = None;
+    }
+
+    /// Store the digest of `ino`, found at `path`, if it changed since the
+    /// last one was stored
+    pub(crate) fn store(&self, ino: u64, path: &Path) {
+        let Some(pending) = self.pending.lock().unwrap().remove(&ino) else {
+            return;
+        };
+        let result = File::open(path).and_then(|file| {
+            let digest = match pending.hasher {
+                Some(hasher) if file.metadata()?.len() == pending.next => hasher.finalize().into(),
+                _ => digest_file(&file)?,
+            };
+            xattr::set(path, OsStr::new(XATTR_NAME), hex(&digest).as_bytes(), 0, 0)
+        });
+        if let Err(e) = result {
+            error!("Failed to store the checksum of {:?}: {:?}", path, e);
+        }
+    }
+
+    /// Check the file open as `fh` against its stored digest, once per
+    /// handle. Files being changed through the mount are not checked.
+    pub(crate) fn verify(&self, ino: u64, fh: u64, file: &File) -> io::Result<()> {
+        if self.pending.lock().unwrap().contains_key(&ino) {
+            return Ok(());
+        }
+        let checked = self.checked.lock().unwrap().get(&fh).copied();
+        let matched = match checked {
+            Some(matched) => matched,
+            None => {
+                let matched = matches(file)?;
+                if !matched {
+                    error!("Checksum mismatch on inode {}", ino);
+                }
+                self.checked.lock().unwrap().insert(fh, matched);
+                matched
+            }
+        };
+        if matched {
+            Ok(())
+        } else {
+            Err(io::Error::from_raw_os_error(libc::EIO))
+        }
+    }
+
+    /// Forget a released handle
+    pub(crate) fn forget(&self, fh: u64) {
+        self.checked.lock().unwrap().remove(&fh);
+    }
+}
+
+/// Whether `file` matches its stored digest, or has none
+fn matches(file: &File) -> io::Result<bool> {
+    let mut stored = [0u8; HEX_LEN];
+    let len = match xattr::fget(file, OsStr::new(XATTR_NAME), &mut stored) {
+        Ok(len) => len,
+        Err(e) if is_missing(&e) => return Ok(true),
+        // A value too long to be a digest is not one this mount stored
+        Err(e) if e.raw_os_error() == Some(libc::ERANGE) => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    Ok(stored[..len] == *hex(&digest_file(file)?).as_bytes())
+}
+
+fn is_missing(e: &io::Error) -> bool {
+    #[cfg(target_os = "linux")]
+    let missing = libc::ENODATA;
+    #[cfg(target_os = "macos")]
+    let missing = libc::ENOATTR;
+    e.raw_os_error() == Some(missing)
+}
+
+fn digest_file(file: &File) -> io::Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; CHUNK];
+    let mut offset = 0;
+    loop {
+        let n = match retry_eintr(|| file.read_at(&mut buffer, offset))? {
+            0 => return Ok(hasher.finalize().into()),
+            n => n,
+        };
+        hasher.update(&buffer[..n]);
+        offset += n as u64;
+    }
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sha256(chunks: &[&[u8]]) -> String {
+        let mut hasher = Sha256::new();
+        for chunk in chunks {
+            hasher.update(chunk);
+        }
+        hex(&hasher.finalize())
+    }
+
+    #[test]
+    fn test_sha256_vectors() {
+        assert_eq!(
+            sha256(&[]),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256(&[b"abc"]),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            sha256(&[b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"]),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        // Split across blocks in uneven pieces
+        let million = vec![b'a'; 1_000_000];
+        let (head, tail) = million.split_at(333_333);
+        assert_eq!(
+            sha256(&[head, tail]),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+    }
+}
@@ -0,0 +1,209 @@
+//! Mount configuration for the passthrough filesystem
+
+use fuser::MountOption;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Tunables for a passthrough mount
+#[derive(Debug, Clone)]
+pub struct PassthroughConfig {
+    /// Source directory to mirror, which should be an absolute path
+    pub source: PathBuf,
+    /// Read-only directories layered under `source`, topmost first. With
+    /// any set, `source` becomes the writable upper layer of an overlay.
+    pub lower: Vec<PathBuf>,
+    /// Glob patterns of entry names to hide: they are left out of
+    /// listings and cannot be looked up, opened or created. Mounting fails
+    /// unless each passes [`check_glob`](crate::check_glob).
+    pub exclude: Vec<String>,
+    /// Glob patterns of names to show even when an `exclude` pattern
+    /// matches them
+    pub include: Vec<String>,
+    /// Mount read-only
+    pub read_only: bool,
+    /// Name shown as the mount's source in `mount` and `df`, the source
+    /// directory's basename when None
+    pub fsname: Option<String>,
+    /// Filesystem subtype, shown as `fuse.<subtype>`
+    pub subtype: Option<String>,
+    /// Allow users other than the mounter to access the filesystem
+    pub allow_other: bool,
+    /// Allow root as well as the mounting user, ignored with `allow_other`
+    pub allow_root: bool,
+    /// How long the kernel may cache attributes returned by getattr/setattr
+    pub attr_ttl: Duration,
+    /// How long the kernel may cache lookups; fuser applies this to the
+    /// attributes returned with an entry as well
+    pub entry_ttl: Duration,
+    /// How long the kernel may remember that a name does not exist. Zero
+    /// disables negative caching, so every miss reaches the source.
+    pub negative_ttl: Duration,
+    /// Let the kernel check permissions against the reported mode bits
+    pub default_permissions: bool,
+    /// Check each caller's uid and gid against the mode bits in the daemon,
+    /// instead of serving every request with the daemon's own privileges
+    pub enforce_permissions: bool,
+    /// Return attributes with directory listings, saving a lookup per
+    /// entry for `ls -l` and `find`
+    pub readdirplus: bool,
+    /// Turn writes of nothing but zeros into holes, so copying a sparse
+    /// file through the mount keeps it sparse
+    pub detect_zero_holes: bool,
+    /// Let the kernel buffer writes and send them in large chunks. Data
+    /// reaches the source on flush, fsync or when the kernel evicts pages,
+    /// so changes made directly to the source while a file is being
+    /// written may be overwritten.
+    pub writeback_cache: bool,
+    /// Bypass the kernel page cache for every file, so reads always see
+    /// the source as it is now. Shared mmap of files through the mount
+    /// then fails with ENODEV.
+    pub direct_io: bool,
+    /// Open source files with O_NOATIME, so reading through the mount does
+    /// not write access times to the source. Files the daemon does not own
+    /// are opened normally, since only their owner may use O_NOATIME.
+    pub noatime: bool,
+    /// Store a SHA-256 digest of every file changed through the mount in
+    /// the `user.passthrough.sha256` xattr, and fail reads with EIO once a
+    /// file no longer matches its digest
+    pub verify_checksums: bool,
+    /// Resolve names that do not exist as spelled to an entry matching them
+    /// ignoring case. Misses are then never cached by the kernel, since
+    /// creating a name would not clear misses under its other spellings.
+    pub case_insensitive: bool,
+    /// File keeping the inode number and generation of every backing file
+    /// seen, so files keep their numbers and NFS handles across mounts.
+    /// Created if it does not exist; mounting fails if it cannot be.
+    pub inode_db: Option<PathBuf>,
+    /// Refuse, with EACCES, anything that would resolve outside the source
+    /// through a symlink: symlinks whose targets lie outside, and paths
+    /// through source directories replaced by symlinks
+    pub no_follow_escapes: bool,
+    /// (source, mount) uid pairs: files owned by the source uid appear
+    /// owned by the mount uid, and chown translates back. Unlisted ids pass
+    /// through unchanged.
+    pub uid_map: Vec<(u32, u32)>,
+    /// (source, mount) gid pairs, translated like `uid_map`
+    pub gid_map: Vec<(u32, u32)>,
+    /// Most backing files kept open at once. Beyond this, the least
+    /// recently used are closed and transparently reopened on next use.
+    /// None keeps every handle's file open until release.
+    pub max_open_files: Option<usize>,
+    /// Close the backing file of a handle unused for this long, reopening
+    /// it on next use. None keeps idle handles' files open.
+    pub idle_handle_timeout: Option<Duration>,
+    /// How often to look for idle handles when `idle_handle_timeout` is set
+    pub idle_handle_scan_interval: Duration,
+    /// Fail reads and writes that take longer than this on the source with
+    /// ETIMEDOUT, so a hung source cannot stall the whole mount. None waits
+    /// as long as the source takes.
+    pub op_timeout: Option<Duration>,
+    /// Threads serving reads and writes, so I/O on different files
+    /// overlaps. Every other request, lookups and getattr included, is
+    /// still served one at a time in the session thread. Defaults to 1,
+    /// which serves reads and writes in the session thread too, so a read
+    /// or write delayed by a rate limit holds up all the others.
+    pub io_threads: usize,
+    /// Delay reads beyond this many bytes per second, None for no limit
+    pub read_bps: Option<u64>,
+    /// Delay writes beyond this many bytes per second, None for no limit
+    pub write_bps: Option<u64>,
+    /// Delay reads and writes beyond this many per second, None for no limit
+    pub iops: Option<u64>,
+    /// Descriptor to write a newline to and close once the kernel has
+    /// initialized the mount, so a parent process can wait for it instead
+    /// of polling the mountpoint
+    pub ready_fd: Option<i32>,
+    /// Watch the source with inotify (Linux only) and have the kernel drop
+    /// cached attributes, pages and names as soon as they change there.
+    /// Costs one watch per source directory.
+    pub watch_source: bool,
+    /// How long the daemon reuses attributes before stat'ing the source
+    /// again. None disables the cache, so changes made directly to the
+    /// source show up as soon as the kernel's own `attr_ttl` runs out.
+    pub attr_cache_ttl: Option<Duration>,
+}
+
+impl PassthroughConfig {
+    /// Default configuration for mirroring `source`: read-write, one second
+    /// kernel caching, no daemon attribute cache, and visible only to the mounting user
+    pub fn new(source: impl Into<PathBuf>) -> Self {
+        PassthroughConfig {
+            source: source.into(),
+            lower: Vec::new(),
+            exclude: Vec::new(),
+            include: Vec::new(),
+            read_only: false,
+            fsname: None,
+            subtype: None,
+            allow_other: false,
+            allow_root: false,
+            attr_ttl: Duration::from_secs(1),
+            entry_ttl: Duration::from_secs(1),
+            negative_ttl: Duration::ZERO,
+            default_permissions: false,
+            enforce_permissions: false,
+            readdirplus: true,
+            detect_zero_holes: false,
+            writeback_cache: false,
+            direct_io: false,
+            noatime: false,
+            verify_checksums: false,
+            case_insensitive: false,
+            inode_db: None,
+            no_follow_escapes: false,
+            uid_map: Vec::new(),
+            gid_map: Vec::new(),
+            max_open_files: None,
+            idle_handle_timeout: None,
+            idle_handle_scan_interval: Duration::from_secs(60),
+            op_timeout: None,
+            io_threads: 1,
+            read_bps: None,
+            write_bps: None,
+            iops: None,
+            ready_fd: None,
+            watch_source: false,
+            attr_cache_ttl: None,
+        }
+    }
+
+    /// Mount options matching this configuration
+    pub fn mount_options(&self) -> Vec<MountOption> {
+        let mut options = vec![
+            if self.read_only {
+                MountOption::RO
+            } else {
+                MountOption::RW
+            },
+            MountOption::FSName(self.fsname()),
+            MountOption::AutoUnmount,
+        ];
+
+        if let Some(subtype) = &self.subtype {
+            options.push(MountOption::Subtype(subtype.clone()));
+        }
+
+        if self.allow_other {
+            options.push(MountOption::AllowOther);
+        } else if self.allow_root {
+            options.push(MountOption::AllowRoot);
+        }
+        if self.default_permissions {
+            options.push(MountOption::DefaultPermissions);
+        }
+
+        options
+    }
+
+    /// Name to mount with, falling back to "passthrough" for a source without one
+    fn fsname(&self) -> String {
+        match &self.fsname {
+            Some(fsname) => fsname.clone(),
+            None => self
+                .source
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "passthrough".to_string()),
+        }
+    }
+}
@@ -0,0 +1,87 @@
+//! Buffers for handles opened with O_DIRECT
+//!
+//! Direct I/O on the source needs the memory it reads into or writes from
+//! to be aligned to the backing device's logical block size, which request
+//! buffers and plain vectors are not. Reads and writes go through aligned
+//! buffers whatever the handle, which costs no more than a Vec. Offsets and
+//! lengths are passed on as the kernel sent them, so the source rejects
+//! unaligned requests itself.
+
+use std::alloc::{self, Layout};
+use std::ops::{Deref, DerefMut};
+
+/// O_DIRECT where the platform has it, which macOS does not
+#[cfg(target_os = "linux")]
+pub(crate) const O_DIRECT: i32 = libc::O_DIRECT;
+#[cfg(not(target_os = "linux"))]
+pub(crate) const O_DIRECT: i32 = 0;
+
+/// Alignment covering the logical block sizes of common devices
+const ALIGN: usize = 4096;
+
+/// Zeroed heap buffer starting on an ALIGN boundary
+pub(crate) struct AlignedBuf {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// The buffer owns its memory like a Vec<u8> does
+unsafe impl Send for AlignedBuf {}
+
+impl AlignedBuf {
+    pub(crate) fn zeroed(len: usize) -> Self {
+        let ptr = unsafe { alloc::alloc_zeroed(Self::layout(len)) };
+        if ptr.is_null() {
+            alloc::handle_alloc_error(Self::layout(len));
+        }
+        AlignedBuf { ptr, len }
+    }
+
+    pub(crate) fn copy_from(data: &[u8]) -> Self {
+        let mut buffer = Self::zeroed(data.len());
+        buffer.copy_from_slice(data);
+        buffer
+    }
+
+    /// At least one byte is allocated, since zero-sized allocations are
+    /// not allowed
+    fn layout(len: usize) -> Layout {
+        Layout::from_size_align(len.max(1), ALIGN).expect("Buffer size overflows")
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr, Self::layout(self.len)) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffers_are_aligned() {
+        for len in [0, 1, 512, 4096, 1024 * 1024 + 3] {
+            let buffer = AlignedBuf::zeroed(len);
+            assert_eq!(buffer.as_ptr() as usize % ALIGN, 0);
+            assert_eq!(buffer.len(), len);
+            assert!(buffer.iter().all(|&byte| byte == 0));
+        }
+        assert_eq!(&*AlignedBuf::copy_from(b"data"), b"data");
+    }
+}
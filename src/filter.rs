@@ -0,0 +1,211 @@
+//! Names hidden from the mount by glob patterns
+//!
+//! Patterns are matched against a single directory entry name, never a
+//! path. `*` matches any run of characters, dots included, `?` a single
+//! character and `[...]` a class, negated by a leading `!` or `^`. Every
+//! pattern is compiled into one regex set when the filesystem is created.
+
+use regex::bytes::{Regex, RegexSet, RegexSetBuilder};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+
+pub(crate) struct NameFilter {
+    exclude: Option<RegexSet>,
+    include: Option<RegexSet>,
+}
+
+impl NameFilter {
+    /// Hide names matching any of `exclude` unless they also match one of
+    /// `include`, failing with a description of the first invalid pattern
+    pub(crate) fn new(exclude: &[String], include: &[String]) -> Result<Self, String> {
+        Ok(NameFilter {
+            exclude: compile(exclude)?,
+            include: compile(include)?,
+        })
+    }
+
+    /// Whether `name` is kept out of listings and lookups
+    pub(crate) fn hides(&self, name: &OsStr) -> bool {
+        let matches = |set: &Option<RegexSet>| {
+            set.as_ref()
+                .is_some_and(|set| set.is_match(name.as_bytes()))
+        };
+        matches(&self.exclude) && !matches(&self.include)
+    }
+}
+
+/// Check that `glob` can be used as a name pattern in
+/// [`PassthroughConfig::exclude`](crate::PassthroughConfig::exclude) or
+/// `include`. Classes with a range running backwards, such as `[z-a]`,
+/// are refused.
+pub fn check_glob(glob: &str) -> Result<(), String> {
+    Regex::new(&glob_to_regex(glob))
+        .map(|_| ())
+        .map_err(|e| describe(&e))
+}
+
+fn compile(globs: &[String]) -> Result<Option<RegexSet>, String> {
+    if globs.is_empty() {
+        return Ok(None);
+    }
+    for glob in globs {
+        check_glob(glob).map_err(|e| format!("pattern '{}': {}", glob, e))?;
+    }
+    RegexSetBuilder::new(globs.iter().map(|glob| glob_to_regex(glob)))
+        .build()
+        .map(Some)
+        .map_err(|e| describe(&e))
+}
+
+/// The reason a translated glob was refused, without the regex it was
+/// translated to, which the user never wrote
+fn describe(e: &regex::Error) -> String {
+    match e {
+        regex::Error::Syntax(message) => message
+            .lines()
+            .find_map(|line| line.strip_prefix("error: "))
+            .unwrap_or(message)
+            .to_string(),
+        e => e.to_string(),
+    }
+}
+
+/// Anchored regex matching the same names as `glob`. A `[` without a
+/// closing `]` is taken literally, so every glob translates, though not
+/// every class makes a valid regex.
+fn glob_to_regex(glob: &str) -> String {
+    let chars: Vec<char> = glob.chars().collect();
+    let mut regex = String::from("^");
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            // Also matches bytes that are not valid UTF-8
+            '*' => regex.push_str("(?s-u:.)*"),
+            '?' => regex.push_str("(?s:.)"),
+            '[' => match class(&chars[i + 1..]) {
+                Some((class, len)) => {
+                    regex.push_str(&class);
+                    i += len;
+                }
+                None => regex.push_str(r"\["),
+            },
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+        i += 1;
+    }
+    regex.push('$');
+    regex
+}
+
+/// Regex class for the glob class starting just after a `[`, and how many
+/// characters it took up including the closing `]`
+fn class(chars: &[char]) -> Option<(String, usize)> {
+    let mut regex = String::from("[");
+    let mut i = 0;
+    if matches!(chars.first(), Some('!' | '^')) {
+        regex.push('^');
+        i += 1;
+    }
+    // A `]` right at the start is part of the class
+    let start = i;
+    // Whether the last character can start a range
+    let mut endpoint = false;
+    while i < chars.len() {
+        match chars[i] {
+            ']' if i > start => {
+                regex.push(']');
+                return Some((regex, i + 1));
+            }
+            // Only a `-` between two endpoints makes a range; any other is
+            // escaped, as regex classes give `--` and the like a meaning
+            '-' if endpoint && chars.get(i + 1).is_some_and(|&next| next != ']') => {
+                regex.push('-');
+                i += 1;
+                regex.push_str(&regex::escape(&chars[i].to_string()));
+                endpoint = false;
+            }
+            c => {
+                regex.push_str(&regex::escape(&c.to_string()));
+                endpoint = true;
+            }
+        }
+        i += 1;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hides(exclude: &[&str], include: &[&str], name: &str) -> bool {
+        let strings = |globs: &[&str]| {
+            globs
+                .iter()
+                .map(|glob| glob.to_string())
+                .collect::<Vec<_>>()
+        };
+        NameFilter::new(&strings(exclude), &strings(include))
+            .unwrap()
+            .hides(OsStr::new(name))
+    }
+
+    #[test]
+    fn test_globs_match_whole_names() {
+        assert!(hides(&["*.secret"], &[], "key.secret"));
+        assert!(hides(&["*.secret"], &[], ".secret"));
+        assert!(!hides(&["*.secret"], &[], "key.secret.txt"));
+        assert!(hides(&["file?.txt"], &[], "file1.txt"));
+        assert!(!hides(&["file?.txt"], &[], "file10.txt"));
+        assert!(hides(&["[a-c]*"], &[], "bin"));
+        assert!(!hides(&["[!a-c]*"], &[], "bin"));
+        assert!(hides(&["[]x]"], &[], "]"));
+        assert!(hides(&["a[b"], &[], "a[b"));
+        assert!(hides(&["(a|b).+"], &[], "(a|b).+"));
+        assert!(!hides(&["(a|b).+"], &[], "a.txt"));
+        assert!(!hides(&[], &[], "anything"));
+    }
+
+    #[test]
+    fn test_include_overrides_exclude() {
+        assert!(hides(&[".*"], &[".gitignore"], ".env"));
+        assert!(!hides(&[".*"], &[".gitignore"], ".gitignore"));
+        assert!(!hides(&[".*"], &[".gitignore"], "visible"));
+    }
+
+    #[test]
+    fn test_star_matches_invalid_utf8() {
+        let filter = NameFilter::new(&["*.secret".to_string()], &[]).unwrap();
+        assert!(filter.hides(OsStr::from_bytes(b"\xff\xfe.secret")));
+    }
+
+    #[test]
+    fn test_reversed_ranges_are_refused() {
+        assert!(check_glob("[a-z]*").is_ok());
+        assert_eq!(
+            check_glob("[z-a]").unwrap_err(),
+            "invalid character class range, the start must be <= the end"
+        );
+        assert_eq!(
+            NameFilter::new(&["*.txt".to_string()], &["[!9-0]".to_string()]).err(),
+            Some(
+                "pattern '[!9-0]': invalid character class range, the start must be <= the end"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_dashes_outside_ranges_are_literal() {
+        assert!(hides(&["[a-]"], &[], "-"));
+        assert!(hides(&["[-a]"], &[], "-"));
+        // Not regex class set operations
+        assert!(hides(&["[a-c-e]"], &[], "-"));
+        assert!(hides(&["[a-c-e]"], &[], "e"));
+        assert!(!hides(&["[a-c-e]"], &[], "d"));
+        assert!(hides(&["[a-z--c]"], &[], "-"));
+        assert!(hides(&["[a-z--c]"], &[], "m"));
+        assert!(hides(&["[a&&b]"], &[], "&"));
+        assert!(hides(&["[a~~b]"], &[], "~"));
+    }
+}
@@ -0,0 +1,211 @@
+//! Open file handles, with an optional cap on backing descriptors
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// State kept for every handle returned by open or create
+pub(crate) struct OpenHandle {
+    /// Inode the handle was opened on
+    pub(crate) ino: u64,
+    /// Flags from the open request, reused when reopening
+    pub(crate) flags: i32,
+    /// Backing file, or None while evicted
+    file: Mutex<Option<Arc<File>>>,
+    /// Nanoseconds from the table's epoch to the last use, for picking
+    /// what to evict
+    last_used: AtomicU64,
+}
+
+impl OpenHandle {
+    /// Whether the handle was opened for writing, or for reading if not `write`
+    pub(crate) fn allows(&self, write: bool) -> bool {
+        match self.flags & libc::O_ACCMODE {
+            libc::O_RDWR => true,
+            libc::O_WRONLY => write,
+            _ => !write,
+        }
+    }
+}
+
+/// Handle id to open handle mapping.
+///
+/// With a limit set, the least recently used backing files are closed once
+/// more than `max_open` are open. Their handles stay valid and are reopened
+/// by path on next use. [`HandleTable::reap`] closes idle files the same way.
+pub(crate) struct HandleTable {
+    handles: RwLock<HashMap<u64, Arc<OpenHandle>>>,
+    /// Most backing files kept open at once, or None for no limit
+    max_open: Option<usize>,
+    /// Number of handles whose backing file is currently open
+    open: AtomicUsize,
+    /// Start of the `last_used` clock
+    epoch: Instant,
+}
+
+impl HandleTable {
+    pub(crate) fn new(max_open: Option<usize>) -> Self {
+        HandleTable {
+            handles: RwLock::new(HashMap::new()),
+            max_open,
+            open: AtomicUsize::new(0),
+            epoch: Instant::now(),
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.epoch.elapsed().as_nanos() as u64
+    }
+
+    /// Register an open file under `fh`
+    pub(crate) fn insert(&self, fh: u64, ino: u64, flags: i32, file: File) {
+        let handle = OpenHandle {
+            ino,
+            flags,
+            file: Mutex::new(Some(Arc::new(file))),
+            last_used: AtomicU64::new(self.now()),
+        };
+        self.handles.write().unwrap().insert(fh, Arc::new(handle));
+        self.open.fetch_add(1, Ordering::SeqCst);
+        self.evict(fh);
+    }
+
+    /// Drop a handle, returning whether it existed
+    pub(crate) fn remove(&self, fh: u64) -> bool {
+        match self.handles.write().unwrap().remove(&fh) {
+            Some(handle) => {
+                if handle.file.lock().unwrap().take().is_some() {
+                    self.open.fetch_sub(1, Ordering::SeqCst);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Look up a handle, marking it as used
+    pub(crate) fn get(&self, fh: u64) -> Option<Arc<OpenHandle>> {
+        let handle = self.handles.read().unwrap().get(&fh).cloned()?;
+        handle.last_used.store(self.now(), Ordering::Relaxed);
+        Some(handle)
+    }
+
+    /// Backing file of a handle, calling `reopen` if it was evicted. The
+    /// table lock is released before the caller does any I/O.
+    pub(crate) fn file(
+        &self,
+        fh: u64,
+        handle: &OpenHandle,
+        reopen: impl FnOnce() -> io::Result<File>,
+    ) -> io::Result<Arc<File>> {
+        let mut guard = handle.file.lock().unwrap();
+        if let Some(file) = guard.as_ref() {
+            return Ok(Arc::clone(file));
+        }
+
+        let file = Arc::new(reopen()?);
+        *guard = Some(Arc::clone(&file));
+        drop(guard);
+        self.open.fetch_add(1, Ordering::SeqCst);
+        self.evict(fh);
+        Ok(file)
+    }
+
+    /// Close least recently used backing files other than `keep` until the
+    /// limit is met. Callers still holding a file keep it open until done.
+    fn evict(&self, keep: u64) {
+        let Some(max_open) = self.max_open else {
+            return;
+        };
+
+        while self.open.load(Ordering::SeqCst) > max_open {
+            let handles = self.handles.read().unwrap();
+            let victim = handles
+                .iter()
+                .filter(|(&fh, handle)| fh != keep && handle.file.lock().unwrap().is_some())
+                .min_by_key(|(_, handle)| handle.last_used.load(Ordering::Relaxed))
+                .map(|(_, handle)| Arc::clone(handle));
+            drop(handles);
+
+            match victim {
+                Some(victim) => {
+                    if victim.file.lock().unwrap().take().is_some() {
+                        self.open.fetch_sub(1, Ordering::SeqCst);
+                    }
+                }
+                None => return,
+            }
+        }
+    }
+
+    /// Close the backing files of handles unused for at least `idle`,
+    /// returning how many were closed
+    pub(crate) fn reap(&self, idle: Duration) -> usize {
+        let Some(cutoff) = self.now().checked_sub(idle.as_nanos() as u64) else {
+            return 0;
+        };
+
+        let handles = self.handles.read().unwrap();
+        let mut closed = 0;
+        for handle in handles.values() {
+            if handle.last_used.load(Ordering::Relaxed) > cutoff {
+                continue;
+            }
+            if handle.file.lock().unwrap().take().is_some() {
+                self.open.fetch_sub(1, Ordering::SeqCst);
+                closed += 1;
+            }
+        }
+        closed
+    }
+
+    /// Number of handles whose backing file is open
+    #[cfg(test)]
+    pub(crate) fn open_count(&self) -> usize {
+        self.open.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let path = temp_dir.path().join("file");
+        std::fs::write(&path, "content").unwrap();
+
+        let table = HandleTable::new(Some(2));
+        for fh in 1..=3 {
+            table.insert(fh, 0, libc::O_RDONLY, File::open(&path).unwrap());
+        }
+        assert_eq!(table.open_count(), 2);
+
+        // Handle 1 was the oldest, so only it needs reopening
+        let reopened = std::cell::Cell::new(0);
+        for fh in [2, 3, 1] {
+            let handle = table.get(fh).unwrap();
+            let file = table
+                .file(fh, &handle, || {
+                    reopened.set(reopened.get() + 1);
+                    File::open(&path)
+                })
+                .unwrap();
+            let mut content = String::new();
+            (&*file).read_to_string(&mut content).unwrap();
+            assert_eq!(content, "content");
+        }
+        assert_eq!(reopened.get(), 1);
+        assert_eq!(table.open_count(), 2);
+
+        for fh in 1..=3 {
+            assert!(table.remove(fh));
+        }
+        assert_eq!(table.open_count(), 0);
+    }
+}
@@ -0,0 +1,605 @@
+//! Inode bookkeeping shared by every filesystem request
+
+use crate::inodedb::{Entry, InodeDb};
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use fuser::FUSE_ROOT_ID;
+
+/// Move `path` from under `old` to the same place under `new`
+fn rebase_path(path: &Path, old: &Path, new: &Path) -> PathBuf {
+    // Joining an empty suffix would add a trailing separator
+    let suffix = path.strip_prefix(old).unwrap();
+    if suffix.as_os_str().is_empty() {
+        new.to_path_buf()
+    } else {
+        new.join(suffix)
+    }
+}
+
+/// A generation no earlier table has used, taken from the clock
+fn new_generation() -> u64 {
+    static LAST: AtomicU64 = AtomicU64::new(0);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(1, |d| d.as_nanos() as u64);
+    // Tables created within one clock tick still get distinct generations
+    let previous = LAST
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| {
+            Some(now.max(last + 1))
+        })
+        .unwrap();
+    now.max(previous + 1)
+}
+
+/// Creation time of a backing file in nanoseconds, 0 where the source
+/// filesystem does not keep one
+pub(crate) fn birth(metadata: &Metadata) -> u64 {
+    metadata
+        .created()
+        .ok()
+        .and_then(|created| created.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_nanos() as u64)
+}
+
+/// State tracked for every inode handed out to the kernel
+struct InodeInfo {
+    /// Relative path used for reverse lookups
+    path: PathBuf,
+    /// Backing (st_dev, st_ino) of the file
+    backing: (u64, u64),
+    /// Generation reported along with the number
+    generation: u64,
+    /// Number of lookups the kernel has not forgotten yet
+    nlookup: u64,
+}
+
+/// The three mappings, which only ever change together
+#[derive(Default)]
+struct Tables {
+    /// Inode to path, backing key and lookup count
+    inodes: HashMap<u64, InodeInfo>,
+    /// Path to inode mapping
+    paths: HashMap<PathBuf, u64>,
+    /// Backing (st_dev, st_ino) to inode mapping
+    backing: HashMap<(u64, u64), u64>,
+}
+
+/// Bidirectional inode/path table.
+///
+/// Lookups only take the read lock, so concurrent getattr and lookup
+/// requests never wait on each other; the write lock is held just long
+/// enough to keep all three mappings consistent.
+pub(crate) struct InodeTable {
+    tables: RwLock<Tables>,
+    /// Next available inode number
+    next_inode: AtomicU64,
+    /// Numbers kept from earlier mounts, when they are persisted
+    db: Option<InodeDb>,
+    /// Generation of the numbers this table hands out fresh, so handles
+    /// from earlier mounts to a number now given to another file go stale.
+    /// Numbers kept in the database come back with the generation they
+    /// were recorded with instead.
+    generation: u64,
+}
+
+impl InodeTable {
+    /// Create a table holding only the root directory, which is never forgotten
+    pub(crate) fn new(root_backing: (u64, u64)) -> Self {
+        Self::with_db(root_backing, None)
+    }
+
+    /// Like [`Self::new`], giving backing files the numbers they had in
+    /// earlier mounts using the inode database at `db_path`
+    pub(crate) fn persistent(root_backing: (u64, u64), db_path: &Path) -> io::Result<Self> {
+        Ok(Self::with_db(root_backing, Some(InodeDb::open(db_path)?)))
+    }
+
+    fn with_db(root_backing: (u64, u64), db: Option<InodeDb>) -> Self {
+        let mut tables = Tables::default();
+        tables.inodes.insert(
+            FUSE_ROOT_ID,
+            InodeInfo {
+                path: PathBuf::from(""),
+                backing: root_backing,
+                // Matching the root inode the kernel creates at mount
+                generation: 0,
+                nlookup: 0,
+            },
+        );
+        tables.paths.insert(PathBuf::from(""), FUSE_ROOT_ID);
+        tables.backing.insert(root_backing, FUSE_ROOT_ID);
+
+        let next_inode = db
+            .as_ref()
+            .and_then(InodeDb::max_inode)
+            .map_or(FUSE_ROOT_ID, |max| max.max(FUSE_ROOT_ID))
+            + 1;
+        InodeTable {
+            tables: RwLock::new(tables),
+            next_inode: AtomicU64::new(next_inode),
+            db,
+            generation: new_generation(),
+        }
+    }
+
+    /// Generation to report along with an inode
+    pub(crate) fn generation(&self, inode: u64) -> u64 {
+        self.tables
+            .read()
+            .unwrap()
+            .inodes
+            .get(&inode)
+            .map_or(self.generation, |info| info.generation)
+    }
+
+    /// Get relative path by inode
+    pub(crate) fn get_path(&self, inode: u64) -> Option<PathBuf> {
+        self.tables
+            .read()
+            .unwrap()
+            .inodes
+            .get(&inode)
+            .map(|info| info.path.clone())
+    }
+
+    /// Get the inode currently mapped to a relative path, if any
+    pub(crate) fn get_inode(&self, path: &Path) -> Option<u64> {
+        self.tables.read().unwrap().paths.get(path).copied()
+    }
+
+    /// Get the inode already allocated for a backing file, if any
+    pub(crate) fn known(&self, backing: (u64, u64)) -> Option<u64> {
+        let tables = self.tables.read().unwrap();
+        tables
+            .backing
+            .get(&backing)
+            .copied()
+            .filter(|inode| tables.inodes.contains_key(inode))
+    }
+
+    /// Allocate or get inode for a path, adding `nlookup` to its lookup count.
+    /// Inodes are keyed on the backing file's (st_dev, st_ino), so hardlinks
+    /// share one inode and renames keep their identity; the path map only
+    /// serves reverse lookups. `birth` is the file's creation time, as
+    /// given by [`birth`].
+    pub(crate) fn get_or_create(
+        &self,
+        path: &Path,
+        backing: (u64, u64),
+        birth: u64,
+        nlookup: u64,
+    ) -> u64 {
+        let mut guard = self.tables.write().unwrap();
+        let tables = &mut *guard;
+
+        // A backing file is only reused while its inode is still known
+        let (inode, generation) = match tables.backing.get(&backing) {
+            Some(&inode) if tables.inodes.contains_key(&inode) => (inode, None),
+            _ => {
+                let (inode, generation) = self.allocate(tables, backing, birth);
+                tables.backing.insert(backing, inode);
+                (inode, Some(generation))
+            }
+        };
+
+        if let Some(previous) = tables.paths.insert(path.to_path_buf(), inode) {
+            // The path was replaced underneath us, so the old inode loses it
+            if previous != inode
+                && tables
+                    .inodes
+                    .get(&previous)
+                    .is_some_and(|info| info.path == path)
+            {
+                tables.inodes.remove(&previous);
+            }
+        }
+
+        let info = tables.inodes.entry(inode).or_insert_with(|| InodeInfo {
+            path: path.to_path_buf(),
+            backing,
+            generation: generation.unwrap_or(self.generation),
+            nlookup: 0,
+        });
+        info.path = path.to_path_buf();
+        info.nlookup += nlookup;
+        inode
+    }
+
+    /// Number and generation for a backing file not currently known: the
+    /// ones it had before when the number is free, else a fresh number. A
+    /// number coming back for what was created as another file, the
+    /// backing (st_dev, st_ino) having been reused, gets the next
+    /// generation so handles to the old file go stale.
+    fn allocate(&self, tables: &Tables, backing: (u64, u64), birth: u64) -> (u64, u64) {
+        let Some(db) = &self.db else {
+            return (
+                self.next_inode.fetch_add(1, Ordering::SeqCst),
+                self.generation,
+            );
+        };
+        match db.get(backing) {
+            Some(entry)
+                if entry.inode != FUSE_ROOT_ID && !tables.inodes.contains_key(&entry.inode) =>
+            {
+                let generation = if entry.birth == birth || entry.birth == 0 || birth == 0 {
+                    entry.generation
+                } else {
+                    entry.generation + 1
+                };
+                if generation != entry.generation || birth != entry.birth {
+                    db.record(
+                        backing,
+                        Entry {
+                            inode: entry.inode,
+                            generation,
+                            birth,
+                        },
+                    );
+                }
+                (entry.inode, generation)
+            }
+            _ => {
+                let inode = self.next_inode.fetch_add(1, Ordering::SeqCst);
+                db.record(
+                    backing,
+                    Entry {
+                        inode,
+                        generation: self.generation,
+                        birth,
+                    },
+                );
+                (inode, self.generation)
+            }
+        }
+    }
+
+    /// Add `nlookup` to the lookup count of an inode that is still known
+    pub(crate) fn add_lookups(&self, inode: u64, nlookup: u64) -> bool {
+        match self.tables.write().unwrap().inodes.get_mut(&inode) {
+            Some(info) => {
+                info.nlookup += nlookup;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drop `nlookup` references to an inode, evicting its mappings once
+    /// the kernel holds none. Returns whether the inode was evicted.
+    pub(crate) fn forget(&self, inode: u64, nlookup: u64) -> bool {
+        self.tables.write().unwrap().forget(inode, nlookup)
+    }
+
+    /// Drop many (inode, nlookup) references under a single lock, returning
+    /// the inodes that were evicted
+    pub(crate) fn forget_many(&self, nodes: impl IntoIterator<Item = (u64, u64)>) -> Vec<u64> {
+        let mut tables = self.tables.write().unwrap();
+        nodes
+            .into_iter()
+            .filter(|&(inode, nlookup)| tables.forget(inode, nlookup))
+            .map(|(inode, _)| inode)
+            .collect()
+    }
+
+    /// Drop the mapping for a removed path. When the file still has other
+    /// links, the inode is repointed at another known name if there is one.
+    pub(crate) fn remove(&self, path: &Path, has_other_links: bool) {
+        let mut tables = self.tables.write().unwrap();
+
+        let inode = match tables.paths.remove(path) {
+            Some(inode) => inode,
+            None => return,
+        };
+
+        if tables
+            .inodes
+            .get(&inode)
+            .is_some_and(|info| info.path == path)
+        {
+            let other = if has_other_links {
+                tables
+                    .paths
+                    .iter()
+                    .find(|(_, &other)| other == inode)
+                    .map(|(other_path, _)| other_path.clone())
+            } else {
+                None
+            };
+            match other {
+                Some(other_path) => tables.inodes.get_mut(&inode).unwrap().path = other_path,
+                None => {
+                    tables.inodes.remove(&inode);
+                }
+            }
+        }
+    }
+
+    /// Move the mapping of a path and all of its descendants to a new path
+    pub(crate) fn rename_subtree(&self, old: &Path, new: &Path) {
+        let mut tables = self.tables.write().unwrap();
+
+        // The previous target, if any, has been replaced
+        if let Some(inode) = tables.paths.remove(new) {
+            if tables
+                .inodes
+                .get(&inode)
+                .is_some_and(|info| info.path == new)
+            {
+                tables.inodes.remove(&inode);
+            }
+        }
+
+        tables.move_paths(
+            |path| path.starts_with(old),
+            |path| rebase_path(path, old, new),
+        );
+    }
+
+    /// Swap the mappings of two paths and all of their descendants
+    pub(crate) fn exchange_subtrees(&self, first: &Path, second: &Path) {
+        self.tables.write().unwrap().move_paths(
+            |path| path.starts_with(first) || path.starts_with(second),
+            |path| {
+                if path.starts_with(first) {
+                    rebase_path(path, first, second)
+                } else {
+                    rebase_path(path, second, first)
+                }
+            },
+        );
+    }
+
+    /// Point the inode mapped to `path` at a new backing file, as when it
+    /// was copied to another layer, so it keeps its number and generation
+    pub(crate) fn rebind(&self, path: &Path, backing: (u64, u64), birth: u64) {
+        let mut guard = self.tables.write().unwrap();
+        let tables = &mut *guard;
+        let Some(&inode) = tables.paths.get(path) else {
+            return;
+        };
+        if let Some(info) = tables.inodes.get_mut(&inode) {
+            if tables.backing.get(&info.backing) == Some(&inode) {
+                tables.backing.remove(&info.backing);
+            }
+            info.backing = backing;
+            tables.backing.insert(backing, inode);
+            if let Some(db) = &self.db {
+                let generation = info.generation;
+                db.record(
+                    backing,
+                    Entry {
+                        inode,
+                        generation,
+                        birth,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Number of inodes currently known, including the root
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.tables.read().unwrap().inodes.len()
+    }
+}
+
+impl Tables {
+    /// Drop `nlookup` references to an inode, evicting it at zero
+    fn forget(&mut self, inode: u64, nlookup: u64) -> bool {
+        if inode == FUSE_ROOT_ID {
+            return false;
+        }
+
+        let info = match self.inodes.get_mut(&inode) {
+            Some(info) => info,
+            None => return false,
+        };
+        info.nlookup = info.nlookup.saturating_sub(nlookup);
+        if info.nlookup > 0 {
+            return false;
+        }
+
+        let info = self.inodes.remove(&inode).unwrap();
+        if self.paths.get(&info.path) == Some(&inode) {
+            self.paths.remove(&info.path);
+        }
+        if self.backing.get(&info.backing) == Some(&inode) {
+            self.backing.remove(&info.backing);
+        }
+        true
+    }
+
+    /// Re-key every path selected by `matches` to `rebase(path)`
+    fn move_paths(&mut self, matches: impl Fn(&Path) -> bool, rebase: impl Fn(&Path) -> PathBuf) {
+        let moved: Vec<(PathBuf, u64)> = self
+            .paths
+            .iter()
+            .filter(|(path, _)| matches(path))
+            .map(|(path, &inode)| (path.clone(), inode))
+            .collect();
+
+        // Remove first so a swapped pair cannot overwrite each other
+        for (path, _) in &moved {
+            self.paths.remove(path);
+        }
+
+        for (path, inode) in moved {
+            let new_path = rebase(&path);
+            self.paths.insert(new_path.clone(), inode);
+            if let Some(info) = self.inodes.get_mut(&inode) {
+                info.path = new_path;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_forget_evicts_inodes() {
+        let table = InodeTable::new((0, 1));
+
+        // Look up every file twice
+        let inodes: Vec<u64> = (0..100)
+            .map(|i| table.get_or_create(&PathBuf::from(format!("file{}", i)), (0, 100 + i), 0, 2))
+            .collect();
+        assert_eq!(table.len(), 101);
+
+        // Still referenced after dropping one lookup each
+        for &inode in &inodes {
+            table.forget(inode, 1);
+        }
+        assert_eq!(table.len(), 101);
+
+        // Only the root remains once every lookup is forgotten
+        for &inode in &inodes {
+            table.forget(inode, 1);
+        }
+        let tables = table.tables.read().unwrap();
+        assert_eq!(tables.inodes.len(), 1);
+        assert_eq!(tables.paths.len(), 1);
+        assert_eq!(tables.backing.len(), 1);
+        drop(tables);
+        assert!(table.get_path(FUSE_ROOT_ID).is_some());
+    }
+
+    #[test]
+    fn test_forgetting_no_lookups_only_evicts_unreferenced_inodes() {
+        let table = InodeTable::new((0, 1));
+        let unsent = table.get_or_create(Path::new("unsent"), (0, 2), 0, 0);
+        let looked_up = table.get_or_create(Path::new("looked_up"), (0, 3), 0, 1);
+
+        assert!(table.forget(unsent, 0));
+        assert!(!table.forget(looked_up, 0));
+        assert_eq!(table.get_inode(Path::new("unsent")), None);
+        assert_eq!(table.get_inode(Path::new("looked_up")), Some(looked_up));
+    }
+
+    #[test]
+    fn test_forget_many_evicts_in_one_pass() {
+        let table = InodeTable::new((0, 1));
+        let inodes: Vec<u64> = (0..10_000)
+            .map(|i| table.get_or_create(&PathBuf::from(format!("file{}", i)), (0, 100 + i), 0, 2))
+            .collect();
+
+        // Half of the files are still looked up once afterwards
+        let nodes = inodes
+            .iter()
+            .enumerate()
+            .map(|(i, &inode)| (inode, if i % 2 == 0 { 2 } else { 1 }));
+        let evicted = table.forget_many(nodes);
+        assert_eq!(evicted.len(), 5_000);
+        assert_eq!(table.len(), 5_001);
+
+        // The root is never evicted, whatever the kernel sends
+        assert!(table.forget_many([(FUSE_ROOT_ID, 1)]).is_empty());
+        assert!(table.get_path(FUSE_ROOT_ID).is_some());
+    }
+
+    #[test]
+    fn test_reallocated_inodes_differ_in_number_or_generation() {
+        let table = InodeTable::new((0, 1));
+        let first = table.get_or_create(Path::new("file"), (0, 2), 0, 1);
+        assert!(table.forget(first, 1));
+
+        // The same name for a different file gets a fresh number
+        let second = table.get_or_create(Path::new("file"), (0, 3), 0, 1);
+        assert_ne!(first, second);
+
+        // A new mount starts numbering over, so only the generation differs
+        let remounted = InodeTable::new((0, 1));
+        let third = remounted.get_or_create(Path::new("other"), (0, 4), 0, 1);
+        assert_eq!(third, first);
+        assert_ne!(remounted.generation(third), table.generation(first));
+    }
+
+    #[test]
+    fn test_reused_numbers_get_the_next_generation() {
+        let dir = tempfile::tempdir().unwrap();
+        let table = InodeTable::persistent((0, 1), &dir.path().join("inodes")).unwrap();
+        let first = table.get_or_create(Path::new("file"), (0, 2), 10, 1);
+        let generation = table.generation(first);
+        assert!(table.forget(first, 1));
+
+        // The same file comes back as it was
+        let again = table.get_or_create(Path::new("file"), (0, 2), 10, 1);
+        assert_eq!(again, first);
+        assert_eq!(table.generation(again), generation);
+        assert!(table.forget(again, 1));
+
+        // A new file given the backing number of a removed one keeps the
+        // inode number, but handles to the removed one go stale
+        let reused = table.get_or_create(Path::new("new"), (0, 2), 20, 1);
+        assert_eq!(reused, first);
+        assert_eq!(table.generation(reused), generation + 1);
+        assert!(table.forget(reused, 1));
+        let reused = table.get_or_create(Path::new("new"), (0, 2), 20, 1);
+        assert_eq!(table.generation(reused), generation + 1);
+        drop(table);
+
+        // And keeps both across mounts
+        let table = InodeTable::persistent((0, 1), &dir.path().join("inodes")).unwrap();
+        let remounted = table.get_or_create(Path::new("new"), (0, 2), 20, 1);
+        assert_eq!(remounted, first);
+        assert_eq!(table.generation(remounted), generation + 1);
+    }
+
+    #[test]
+    fn test_concurrent_lookups_stay_consistent() {
+        let table = Arc::new(InodeTable::new((0, 1)));
+        let inodes: Vec<u64> = (0..64)
+            .map(|i| table.get_or_create(&PathBuf::from(format!("file{}", i)), (0, 100 + i), 0, 1))
+            .collect();
+
+        // Readers race a writer that keeps renaming an unrelated subtree
+        let writer = {
+            let table = Arc::clone(&table);
+            thread::spawn(move || {
+                table.get_or_create(Path::new("dir"), (0, 2), 0, 1);
+                for i in 0..1000 {
+                    let (from, to) = if i % 2 == 0 {
+                        ("dir", "moved")
+                    } else {
+                        ("moved", "dir")
+                    };
+                    table.rename_subtree(Path::new(from), Path::new(to));
+                }
+            })
+        };
+        let readers: Vec<_> = (0..8)
+            .map(|_| {
+                let table = Arc::clone(&table);
+                let inodes = inodes.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        for (i, &inode) in inodes.iter().enumerate() {
+                            let path = table.get_path(inode).unwrap();
+                            assert_eq!(path, PathBuf::from(format!("file{}", i)));
+                            assert_eq!(table.known((0, 100 + i as u64)), Some(inode));
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        writer.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(
+            table.get_path(table.known((0, 2)).unwrap()).unwrap(),
+            Path::new("dir")
+        );
+    }
+}
@@ -0,0 +1,182 @@
+//! Inode numbers kept across mounts
+//!
+//! The database is a text log with one `dev ino inode generation birth`
+//! line for each backing file given a number, so a file comes back with
+//! the same number and generation and NFS handles to it stay valid. The
+//! birth, the file's creation time, tells a file apart from a later one
+//! given the same `dev ino`. Loading the log rewrites it without
+//! superseded lines, and new numbers are appended as they are handed out.
+//! A line cut short by a crash is skipped on the next load, which then only
+//! forgets that one number.
+
+use log::error;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Number handed out for a backing file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Entry {
+    pub(crate) inode: u64,
+    /// Generation the number was handed out with
+    pub(crate) generation: u64,
+    /// Creation time of the file in nanoseconds, 0 when unknown
+    pub(crate) birth: u64,
+}
+
+/// Entries, each number belonging to the backing file it was last given to
+#[derive(Default)]
+struct Numbers {
+    /// Backing (st_dev, st_ino) to its entry
+    entries: HashMap<(u64, u64), Entry>,
+    /// Inode number to the backing file holding it
+    owners: HashMap<u64, (u64, u64)>,
+}
+
+impl Numbers {
+    fn insert(&mut self, backing: (u64, u64), entry: Entry) {
+        if let Some(previous) = self.entries.insert(backing, entry) {
+            if self.owners.get(&previous.inode) == Some(&backing) {
+                self.owners.remove(&previous.inode);
+            }
+        }
+        if let Some(owner) = self.owners.insert(entry.inode, backing) {
+            if owner != backing {
+                self.entries.remove(&owner);
+            }
+        }
+    }
+}
+
+pub(crate) struct InodeDb {
+    /// Log the new numbers are appended to
+    log: Mutex<File>,
+    numbers: Mutex<Numbers>,
+}
+
+impl InodeDb {
+    /// Load the database at `path`, creating it if it does not exist
+    pub(crate) fn open(path: &Path) -> io::Result<Self> {
+        let numbers = match fs::read_to_string(path) {
+            Ok(contents) => parse(&contents),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Numbers::default(),
+            Err(e) => return Err(e),
+        };
+
+        // Renamed into place, so a crash leaves the old log intact
+        let mut name = path.file_name().unwrap_or_default().to_owned();
+        name.push(".tmp");
+        let compacted = path.with_file_name(name);
+        let mut writer = BufWriter::new(File::create(&compacted)?);
+        for (&(dev, ino), entry) in &numbers.entries {
+            writeln!(writer, "{}", line((dev, ino), entry))?;
+        }
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&compacted, path)?;
+
+        let log = OpenOptions::new().append(true).open(path)?;
+        Ok(InodeDb {
+            log: Mutex::new(log),
+            numbers: Mutex::new(numbers),
+        })
+    }
+
+    /// Number last handed out for the backing file, unless it has been
+    /// given to another file since
+    pub(crate) fn get(&self, backing: (u64, u64)) -> Option<Entry> {
+        self.numbers.lock().unwrap().entries.get(&backing).copied()
+    }
+
+    /// Highest number handed out so far
+    pub(crate) fn max_inode(&self) -> Option<u64> {
+        self.numbers.lock().unwrap().owners.keys().copied().max()
+    }
+
+    /// Keep a newly handed out number, taking it from any other backing
+    /// file that had it. A failed write is only logged, since the number
+    /// stays valid for this mount.
+    pub(crate) fn record(&self, backing: (u64, u64), entry: Entry) {
+        self.numbers.lock().unwrap().insert(backing, entry);
+        let line = format!("{}\n", line(backing, &entry));
+        if let Err(e) = self.log.lock().unwrap().write_all(line.as_bytes()) {
+            error!(
+                "Failed to record inode {} in the inode database: {:?}",
+                entry.inode, e
+            );
+        }
+    }
+}
+
+fn line(backing: (u64, u64), entry: &Entry) -> String {
+    format!(
+        "{} {} {} {} {}",
+        backing.0, backing.1, entry.inode, entry.generation, entry.birth
+    )
+}
+
+/// Mappings in a log, later lines overriding earlier ones. Only complete
+/// lines count, as a torn one may end in a shorter, wrong number.
+fn parse(contents: &str) -> Numbers {
+    let mut numbers = Numbers::default();
+    for line in contents.split_inclusive('\n') {
+        let Some(line) = line.strip_suffix('\n') else {
+            continue;
+        };
+        let fields: Vec<u64> = line
+            .split(' ')
+            .map_while(|field| field.parse().ok())
+            .collect();
+        if let [dev, ino, inode, generation, birth] = fields[..] {
+            let entry = Entry {
+                inode,
+                generation,
+                birth,
+            };
+            numbers.insert((dev, ino), entry);
+        }
+    }
+    numbers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_numbers_survive_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("inodes");
+
+        let entry = |inode| Entry {
+            inode,
+            generation: 100 + inode,
+            birth: 200 + inode,
+        };
+        let db = InodeDb::open(&path).unwrap();
+        assert_eq!(db.get((1, 2)), None);
+        db.record((1, 2), entry(5));
+        db.record((1, 3), entry(6));
+        db.record((1, 2), entry(7));
+        // A number given to another file is no longer the old one's
+        db.record((1, 5), entry(9));
+        db.record((1, 6), entry(9));
+        assert_eq!(db.get((1, 5)), None);
+        drop(db);
+
+        // A torn last line is dropped
+        let mut log = OpenOptions::new().append(true).open(&path).unwrap();
+        log.write_all(b"1 4 8 108 20").unwrap();
+        drop(log);
+
+        let db = InodeDb::open(&path).unwrap();
+        assert_eq!(db.get((1, 2)), Some(entry(7)));
+        assert_eq!(db.get((1, 3)), Some(entry(6)));
+        assert_eq!(db.get((1, 4)), None);
+        assert_eq!(db.get((1, 5)), None);
+        assert_eq!(db.get((1, 6)), Some(entry(9)));
+        assert_eq!(db.max_inode(), Some(9));
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 3);
+    }
+}
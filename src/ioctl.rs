@@ -0,0 +1,126 @@
+//! ioctls forwarded to the backing files
+
+use crate::retry_eintr;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+/// Map a logical block to its physical block, from linux/fs.h
+const FIBMAP: libc::Ioctl = 1;
+/// Block size used by FIBMAP, from linux/fs.h
+const FIGETBSZ: libc::Ioctl = 2;
+/// Read extended inode flags into a struct fsxattr, from linux/fs.h
+const FS_IOC_FSGETXATTR: libc::Ioctl = 0x801c581f;
+/// Set extended inode flags from a struct fsxattr, from linux/fs.h
+const FS_IOC_FSSETXATTR: libc::Ioctl = 0x401c5820;
+/// Size of struct fsxattr
+const FSXATTR_SIZE: usize = 28;
+
+fn ioctl_int(file: &File, request: libc::Ioctl, arg: &mut libc::c_int) -> io::Result<()> {
+    ioctl_ptr(file, request, arg as *mut libc::c_int as *mut libc::c_void)
+}
+
+fn ioctl_ptr(file: &File, request: libc::Ioctl, arg: *mut libc::c_void) -> io::Result<()> {
+    retry_eintr(|| {
+        let ret = unsafe { libc::ioctl(file.as_raw_fd(), request, arg) };
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    })
+}
+
+/// Whether `cmd` changes the file, so read-only mounts must refuse it
+pub fn is_write(cmd: u32) -> bool {
+    matches!(
+        cmd as libc::Ioctl,
+        libc::FS_IOC_SETFLAGS | libc::FS_IOC32_SETFLAGS | FS_IOC_FSSETXATTR
+    )
+}
+
+/// Run one of the forwarded ioctls on the backing file, returning the data
+/// to copy out. Only inode flags (chattr/lsattr) are forwarded: their
+/// argument is a plain int or struct fsxattr, so nothing in it points into
+/// the caller's memory. Anything else fails with ENOTTY, as for an unknown
+/// ioctl.
+pub fn forward(file: &File, cmd: u32, in_data: &[u8]) -> io::Result<Vec<u8>> {
+    match cmd as libc::Ioctl {
+        libc::FS_IOC_GETFLAGS | libc::FS_IOC32_GETFLAGS => {
+            let mut flags: libc::c_int = 0;
+            ioctl_int(file, libc::FS_IOC_GETFLAGS, &mut flags)?;
+            Ok(flags.to_ne_bytes().to_vec())
+        }
+        libc::FS_IOC_SETFLAGS | libc::FS_IOC32_SETFLAGS => {
+            let bytes = in_data
+                .get(..4)
+                .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+            let mut flags = libc::c_int::from_ne_bytes(bytes.try_into().unwrap());
+            ioctl_int(file, libc::FS_IOC_SETFLAGS, &mut flags)?;
+            Ok(Vec::new())
+        }
+        // The kernel reads these before changing flags through SETFLAGS
+        FS_IOC_FSGETXATTR => {
+            let mut fsxattr = [0u8; FSXATTR_SIZE];
+            ioctl_ptr(file, FS_IOC_FSGETXATTR, fsxattr.as_mut_ptr().cast())?;
+            Ok(fsxattr.to_vec())
+        }
+        FS_IOC_FSSETXATTR => {
+            let mut fsxattr: [u8; FSXATTR_SIZE] = in_data
+                .get(..FSXATTR_SIZE)
+                .and_then(|bytes| bytes.try_into().ok())
+                .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+            ioctl_ptr(file, FS_IOC_FSSETXATTR, fsxattr.as_mut_ptr().cast())?;
+            Ok(Vec::new())
+        }
+        _ => Err(io::Error::from_raw_os_error(libc::ENOTTY)),
+    }
+}
+
+/// Physical block holding logical block `idx`, both counted in units of
+/// `blocksize`, which need not match the source filesystem's block size.
+/// Holes map to block 0, as with FIBMAP itself.
+pub fn bmap(file: &File, blocksize: u32, idx: u64) -> io::Result<u64> {
+    let mut fs_blocksize: libc::c_int = 0;
+    ioctl_int(file, FIGETBSZ, &mut fs_blocksize)?;
+    if fs_blocksize <= 0 || blocksize == 0 {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+    let fs_blocksize = fs_blocksize as u64;
+
+    let offset = idx * blocksize as u64;
+    let mut block = libc::c_int::try_from(offset / fs_blocksize)
+        .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+    ioctl_int(file, FIBMAP, &mut block)?;
+    if block == 0 {
+        return Ok(0);
+    }
+
+    let physical = block as u64 * fs_blocksize + offset % fs_blocksize;
+    Ok(physical / blocksize as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bmap_finds_written_block() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let path = temp_dir.path().join("file");
+        std::fs::write(&path, vec![1u8; 64 * 1024]).unwrap();
+        let file = File::open(&path).unwrap();
+        file.sync_all().unwrap();
+
+        match bmap(&file, 4096, 0) {
+            Ok(block) => assert_ne!(block, 0, "Written block mapped to a hole"),
+            // Not block-backed, or FIBMAP needs CAP_SYS_RAWIO
+            Err(e)
+                if matches!(
+                    e.raw_os_error(),
+                    Some(libc::ENOTTY | libc::EINVAL | libc::EPERM)
+                ) => {}
+            Err(e) => panic!("bmap failed: {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,3771 @@
+//! A passthrough FUSE filesystem that mirrors one directory to another
+
+use fuser::{
+    FileAttr, FileType, Filesystem, PollHandle, ReplyAttr, ReplyData, ReplyDirectory,
+    ReplyDirectoryPlus, ReplyEntry, ReplyLseek, ReplyOpen, ReplyPoll, ReplyWrite, ReplyXattr,
+    Request, TimeOrNow,
+};
+use libc::{
+    EACCES, EAGAIN, EBADF, EEXIST, EFBIG, EINTR, EINVAL, EIO, EISDIR, ENOENT, ENOMEM, ENOSYS,
+    ENOTDIR, EPERM, EROFS, ESTALE, EXDEV,
+};
+use log::{debug, error, info, trace, warn};
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{
+    DirBuilderExt, DirEntryExt, FileExt, FileTypeExt, MetadataExt, OpenOptionsExt, PermissionsExt,
+};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+mod cache;
+mod checksum;
+mod config;
+mod direct;
+mod filter;
+mod handle;
+mod inode;
+mod inodedb;
+#[cfg(target_os = "linux")]
+mod ioctl;
+#[cfg(target_os = "linux")]
+mod lock;
+mod mount;
+mod overlay;
+mod pool;
+mod stats;
+mod throttle;
+mod timeout;
+#[cfg(target_os = "linux")]
+mod watch;
+mod xattr;
+
+use cache::{AttrCache, OwnWrites};
+use checksum::Checksums;
+use direct::{AlignedBuf, O_DIRECT};
+use filter::NameFilter;
+use handle::{HandleTable, OpenHandle};
+use inode::{birth, InodeTable};
+use overlay::Overlay;
+use pool::WorkerPool;
+use stats::Counters;
+use throttle::Throttle;
+use timeout::Deadline;
+
+pub use config::PassthroughConfig;
+pub use filter::check_glob;
+pub use fuser::{BackgroundSession, MountOption};
+pub use mount::Mount;
+pub use stats::{FsStats, StatsHandle};
+
+/// Largest write or readahead request asked of the kernel
+const MAX_REQUEST_SIZE: u32 = 1024 * 1024;
+
+/// Blocking lock requests that may wait at once, each on a thread of its
+/// own, before new ones fail with ENOLCK
+const MAX_LOCK_WAITERS: usize = 64;
+
+/// Convert an I/O error into the errno reported back to the kernel
+fn io_error_to_errno(e: &io::Error) -> i32 {
+    if let Some(errno) = e.raw_os_error() {
+        return errno;
+    }
+
+    match e.kind() {
+        ErrorKind::NotFound => ENOENT,
+        ErrorKind::PermissionDenied => EACCES,
+        ErrorKind::AlreadyExists => EEXIST,
+        ErrorKind::InvalidInput => EINVAL,
+        ErrorKind::Interrupted => EINTR,
+        ErrorKind::WouldBlock => EAGAIN,
+        ErrorKind::OutOfMemory => ENOMEM,
+        ErrorKind::Unsupported => ENOSYS,
+        _ => EIO,
+    }
+}
+
+/// Reject directory entry names that are not a single path component.
+/// The kernel never sends such names, but joining one onto a path would
+/// reach outside the parent directory.
+fn check_name(name: &OsStr) -> Result<(), i32> {
+    let bytes = name.as_bytes();
+    if bytes.is_empty() || bytes == b"." || bytes == b".." || bytes.contains(&b'/') {
+        warn!("Rejecting invalid name {:?}", name);
+        return Err(EINVAL);
+    }
+    Ok(())
+}
+
+/// Validate the range of a read or write from the kernel, returning its
+/// offset. A negative offset would wrap around as u64 and a range past
+/// i64::MAX cannot exist in a file.
+fn check_io_range(offset: i64, len: u64) -> Result<u64, i32> {
+    if offset < 0 {
+        return Err(EINVAL);
+    }
+    if len > (i64::MAX - offset) as u64 {
+        return Err(EFBIG);
+    }
+    Ok(offset as u64)
+}
+
+/// Entries of the directory at `real_path`, without . and ..
+fn list_dir(real_path: &Path) -> io::Result<Vec<overlay::Entry>> {
+    let entries = fs::read_dir(real_path)?;
+    // Children share the directory's device, so a single stat covers them all
+    let dev = fs::metadata(real_path)?.dev();
+
+    let mut listing = Vec::new();
+    for entry in entries.flatten() {
+        // d_type from getdents avoids a stat per entry; the standard
+        // library only falls back to lstat when it is DT_UNKNOWN, and that
+        // fails for entries removed since the directory was read
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        listing.push(overlay::Entry {
+            backing: (dev, entry.ino()),
+            name: entry.file_name(),
+            file_type,
+        });
+    }
+    Ok(listing)
+}
+
+/// `name` folded for comparisons ignoring case: by Unicode lowercase when it
+/// is valid UTF-8, by ASCII lowercase otherwise
+fn fold_case(name: &OsStr) -> Vec<u8> {
+    match name.to_str() {
+        Some(name) => name.to_lowercase().into_bytes(),
+        None => name.as_bytes().to_ascii_lowercase(),
+    }
+}
+
+/// Convert a path into a NUL-terminated C string for libc calls
+fn path_to_cstring(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|_| io::Error::from_raw_os_error(EINVAL))
+}
+
+/// Rename with renameat2 flags such as RENAME_NOREPLACE and RENAME_EXCHANGE
+#[cfg(target_os = "linux")]
+fn rename_with_flags(old: &Path, new: &Path, flags: u32) -> io::Result<()> {
+    let old = path_to_cstring(old)?;
+    let new = path_to_cstring(new)?;
+    let ret = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            old.as_ptr(),
+            libc::AT_FDCWD,
+            new.as_ptr(),
+            flags,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Rename flags are Linux-specific
+#[cfg(not(target_os = "linux"))]
+fn rename_with_flags(_old: &Path, _new: &Path, _flags: u32) -> io::Result<()> {
+    Err(io::Error::from_raw_os_error(EINVAL))
+}
+
+/// Allocate or deallocate space, `mode` being a set of FALLOC_FL_* flags
+#[cfg(target_os = "linux")]
+fn fallocate(file: &File, offset: i64, length: i64, mode: i32) -> io::Result<()> {
+    retry_eintr(|| {
+        let ret = unsafe { libc::fallocate(file.as_raw_fd(), mode, offset, length) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    })
+}
+
+/// fallocate is Linux-specific
+#[cfg(not(target_os = "linux"))]
+fn fallocate(_file: &File, _offset: i64, _length: i64, _mode: i32) -> io::Result<()> {
+    Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+}
+
+/// Open flags for a writable handle under the writeback cache, where the
+/// kernel reads pages back even through write-only handles and works out
+/// append offsets itself
+fn writeback_flags(flags: i32) -> i32 {
+    (flags & !(libc::O_ACCMODE | libc::O_APPEND)) | libc::O_RDWR
+}
+
+/// Open `path` with extra open(2) flags. O_NOATIME is only allowed on
+/// files the daemon owns, so without it the file is opened normally.
+fn open_custom(options: &OpenOptions, path: &Path, custom_flags: i32) -> io::Result<File> {
+    let mut options = options.clone();
+    match options.custom_flags(custom_flags).open(path) {
+        Err(e) if e.raw_os_error() == Some(libc::EPERM) && custom_flags & libc::O_NOATIME != 0 => {
+            options
+                .custom_flags(custom_flags & !libc::O_NOATIME)
+                .open(path)
+        }
+        result => result,
+    }
+}
+
+/// Store `length` zero bytes at `offset` as a hole instead of data,
+/// growing the file if they end past EOF
+fn write_hole(file: &File, offset: u64, length: u64) -> io::Result<()> {
+    let size = file.metadata()?.len();
+    let end = offset + length;
+    if offset < size {
+        let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+        fallocate(file, offset as i64, (end.min(size) - offset) as i64, mode)?;
+    }
+    if end > size {
+        file.set_len(end)?;
+    }
+    Ok(())
+}
+
+/// Permission bits for a new file or directory: the kernel masks the mode
+/// with the caller's umask unless FUSE_DONT_MASK is negotiated, so applying
+/// it again is harmless either way
+fn creation_mode(mode: u32, umask: u32) -> u32 {
+    mode & 0o7777 & !umask
+}
+
+/// Restore bits the daemon's own umask stripped when creating with `mode`
+///
+/// Creation never grants more than `mode`, so there is no window where the
+/// new file is more permissive than requested.
+fn fix_mode(file: &File, mode: u32) -> io::Result<()> {
+    let current = file.metadata()?.permissions().mode() & 0o7777;
+    if current != mode {
+        file.set_permissions(fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+/// Build a SystemTime from raw st_*tim seconds and nanoseconds, keeping
+/// sub-second precision and times before the epoch
+fn timestamp(secs: i64, nsecs: i64) -> SystemTime {
+    let nsecs = Duration::from_nanos(nsecs as u64);
+    if secs >= 0 {
+        UNIX_EPOCH + Duration::from_secs(secs as u64) + nsecs
+    } else {
+        UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs()) + nsecs
+    }
+}
+
+/// Encode a device number for FileAttr.rdev, which the Linux kernel decodes
+/// as a 32-bit dev_t: 12 bits of major and 20 bits of minor, split around
+/// the low byte of the minor. Larger numbers cannot be passed through and
+/// are reported as 0 rather than truncated into another device.
+#[cfg(target_os = "linux")]
+fn encode_rdev(rdev: u64) -> u32 {
+    let (major, minor) = (libc::major(rdev), libc::minor(rdev));
+    if major >= 1 << 12 || minor >= 1 << 20 {
+        warn!("Device {}:{} does not fit in a FUSE rdev", major, minor);
+        return 0;
+    }
+    (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12)
+}
+
+/// macOS dev_t is 32 bits and passed through as it is
+#[cfg(target_os = "macos")]
+fn encode_rdev(rdev: u64) -> u32 {
+    rdev as u32
+}
+
+/// Write a newline to `fd` and close it, telling whoever waits on its other
+/// end that the mount is serving requests
+fn signal_ready(fd: RawFd) {
+    // The descriptor was handed over for this alone, so it is ours to close
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    if let Err(e) = file.write_all(b"\n") {
+        warn!("Failed to signal readiness on fd {}: {}", fd, e);
+    }
+}
+
+/// Check `mask` (R_OK/W_OK/X_OK/F_OK) with the daemon's effective ids
+fn faccessat(path: &Path, mask: i32) -> io::Result<()> {
+    let path = path_to_cstring(path)?;
+    let ret = unsafe { libc::faccessat(libc::AT_FDCWD, path.as_ptr(), mask, libc::AT_EACCESS) };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Primary and supplementary groups of the calling process, as far as they
+/// can be read from /proc
+fn caller_groups(pid: u32, gid: u32) -> Vec<u32> {
+    let mut groups = vec![gid];
+    if let Ok(status) = fs::read_to_string(format!("/proc/{}/status", pid)) {
+        if let Some(line) = status.lines().find(|line| line.starts_with("Groups:")) {
+            groups.extend(
+                line["Groups:".len()..]
+                    .split_whitespace()
+                    .filter_map(|group| group.parse::<u32>().ok()),
+            );
+        }
+    }
+    groups
+}
+
+/// Check `mask` against the mode bits the way the kernel would for a caller
+fn permits(metadata: &fs::Metadata, uid: u32, groups: &[u32], mask: i32) -> bool {
+    let wanted = (mask & (libc::R_OK | libc::W_OK | libc::X_OK)) as u32;
+    let mode = metadata.mode();
+
+    // root may read and write anything, and execute if any x bit is set
+    if uid == 0 {
+        return wanted & libc::X_OK as u32 == 0 || metadata.is_dir() || mode & 0o111 != 0;
+    }
+
+    let granted = if metadata.uid() == uid {
+        (mode >> 6) & 0o7
+    } else if groups.contains(&metadata.gid()) {
+        (mode >> 3) & 0o7
+    } else {
+        mode & 0o7
+    };
+    wanted & !granted == 0
+}
+
+/// Translate a source id to the id shown through the mount
+fn map_id(map: &[(u32, u32)], id: u32) -> u32 {
+    map.iter()
+        .find(|(source, _)| *source == id)
+        .map_or(id, |(_, mounted)| *mounted)
+}
+
+/// Translate an id seen through the mount back to the source id
+fn unmap_id(map: &[(u32, u32)], id: u32) -> u32 {
+    map.iter()
+        .find(|(_, mounted)| *mounted == id)
+        .map_or(id, |(source, _)| *source)
+}
+
+/// Convert an optional setattr time into a timespec for utimensat. `Now`
+/// becomes UTIME_NOW, so when both times are set to now in one call the
+/// kernel gives them the same timestamp.
+fn time_to_timespec(time: Option<TimeOrNow>) -> libc::timespec {
+    match time {
+        None => libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_OMIT,
+        },
+        Some(TimeOrNow::Now) => libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_NOW,
+        },
+        Some(TimeOrNow::SpecificTime(t)) => match t.duration_since(UNIX_EPOCH) {
+            Ok(d) => libc::timespec {
+                tv_sec: d.as_secs() as libc::time_t,
+                tv_nsec: d.subsec_nanos() as libc::c_long,
+            },
+            Err(e) => {
+                // Times before the epoch have a negative seconds field
+                let d = e.duration();
+                let mut sec = -(d.as_secs() as libc::time_t);
+                let mut nsec = d.subsec_nanos() as libc::c_long;
+                if nsec > 0 {
+                    sec -= 1;
+                    nsec = 1_000_000_000 - nsec;
+                }
+                libc::timespec {
+                    tv_sec: sec,
+                    tv_nsec: nsec,
+                }
+            }
+        },
+    }
+}
+
+/// The FUSE type of a source file. FIFOs, sockets and devices keep their
+/// type, so the kernel serves them itself as on any other filesystem.
+fn fuse_file_type(file_type: fs::FileType) -> FileType {
+    if file_type.is_dir() {
+        FileType::Directory
+    } else if file_type.is_symlink() {
+        FileType::Symlink
+    } else if file_type.is_fifo() {
+        FileType::NamedPipe
+    } else if file_type.is_char_device() {
+        FileType::CharDevice
+    } else if file_type.is_block_device() {
+        FileType::BlockDevice
+    } else if file_type.is_socket() {
+        FileType::Socket
+    } else {
+        FileType::RegularFile
+    }
+}
+
+/// Poll `file` for the poll(2) `events`, waiting at most `timeout_ms`,
+/// and return the events that are ready
+fn poll_file(file: &File, events: u32, timeout_ms: libc::c_int) -> io::Result<u32> {
+    let mut pollfd = libc::pollfd {
+        fd: file.as_raw_fd(),
+        events: events as libc::c_short,
+        revents: 0,
+    };
+    retry_eintr(|| {
+        if unsafe { libc::poll(&mut pollfd, 1, timeout_ms) } >= 0 {
+            Ok(pollfd.revents as u16 as u32)
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    })
+}
+
+/// Wait until `file` is ready for `events`, or its handle is gone, then
+/// tell the kernel to poll again
+fn notify_when_ready(
+    file: Weak<File>,
+    events: u32,
+    ph: PollHandle,
+    waits: Arc<Mutex<HashSet<u64>>>,
+) {
+    let kh = u64::from(ph.clone());
+    while let Some(file) = file.upgrade() {
+        // Wake up now and then to notice the handle being released
+        match poll_file(&file, events, 1000) {
+            Ok(0) => continue,
+            _ => break,
+        }
+    }
+    waits.lock().unwrap().remove(&kh);
+    if let Err(e) = ph.notify() {
+        debug!("poll notify {}: {}", kh, e);
+    }
+}
+
+/// Run `f` again for as long as it fails with EINTR. Signals reaching the
+/// daemon, such as the one asking it to unmount, would otherwise fail the
+/// request they happened to interrupt.
+pub(crate) fn retry_eintr<T>(mut f: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    loop {
+        match f() {
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            result => return result,
+        }
+    }
+}
+
+/// Fill `buffer` from `offset` until it is full or EOF is reached, using
+/// positioned reads so the shared file cursor is never touched.
+/// Like read(2), a failure after some bytes arrived reports the partial count.
+fn read_fully_at(file: &File, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match retry_eintr(|| file.read_at(&mut buffer[read..], offset + read as u64)) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(_) if read > 0 => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+/// Run blocking I/O on the source within `deadline`, if there is one
+fn timed<T, F>(deadline: Option<&Deadline>, op: F) -> io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+{
+    match deadline {
+        Some(deadline) => deadline.run(op),
+        None => op(),
+    }
+}
+
+/// Write `data` at `offset`, as a hole instead when `hole` is set and the
+/// source filesystem can punch one
+fn write_data(file: &File, data: &[u8], offset: u64, hole: bool) -> io::Result<usize> {
+    if hole && write_hole(file, offset, data.len() as u64).is_ok() {
+        return Ok(data.len());
+    }
+    write_fully_at(file, data, offset)
+}
+
+/// Write all of `data` at `offset`, retrying short and interrupted writes.
+/// Like write(2), a failure after some bytes landed reports the partial count.
+fn write_fully_at(file: &File, data: &[u8], offset: u64) -> io::Result<usize> {
+    let mut written = 0;
+    while written < data.len() {
+        match retry_eintr(|| file.write_at(&data[written..], offset + written as u64)) {
+            Ok(0) => break,
+            Ok(n) => written += n,
+            Err(_) if written > 0 => break,
+            Err(e) => return Err(e),
+        }
+    }
+
+    if written == 0 && !data.is_empty() {
+        return Err(ErrorKind::WriteZero.into());
+    }
+    Ok(written)
+}
+
+/// Log who issued a request, at trace level to keep debug logs readable
+fn log_caller(op: &str, req: &Request) {
+    trace!(
+        "{} from uid={} gid={} pid={}",
+        op,
+        req.uid(),
+        req.gid(),
+        req.pid()
+    );
+}
+
+/// Attributes of a negative lookup entry, reported with inode 0
+fn negative_attr() -> FileAttr {
+    FileAttr {
+        ino: 0,
+        size: 0,
+        blocks: 0,
+        atime: UNIX_EPOCH,
+        mtime: UNIX_EPOCH,
+        ctime: UNIX_EPOCH,
+        crtime: UNIX_EPOCH,
+        kind: FileType::RegularFile,
+        perm: 0,
+        nlink: 0,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        blksize: 0,
+        flags: 0,
+    }
+}
+
+/// Directory entry as reported by readdir: inode, type and name
+type DirEntry = (u64, FileType, OsString);
+
+/// Passthrough filesystem implementation
+pub struct PassthroughFS {
+    /// Source directory path
+    source: PathBuf,
+    /// Backing (st_dev, st_ino) of the source directory at mount time
+    source_backing: (u64, u64),
+    /// Set once the source directory has been found missing or replaced
+    source_lost: AtomicBool,
+    /// Reject every modification with EROFS
+    read_only: bool,
+    /// Offer readdirplus to the kernel
+    readdirplus: bool,
+    /// Deny callers what their own credentials would not allow
+    enforce_permissions: bool,
+    /// Punch holes for all-zero writes instead of storing the zeros
+    detect_zero_holes: bool,
+    /// Let the kernel buffer writes in its page cache
+    writeback_cache: bool,
+    /// Bypass the kernel page cache for every open file
+    direct_io: bool,
+    /// Keep reads through the mount from updating access times in the source
+    noatime: bool,
+    /// Digests of file contents, when reads are checked against them
+    checksums: Option<Arc<Checksums>>,
+    /// Canonical layer paths one of which every resolved path must stay
+    /// under, when symlinks leading out of the source are refused
+    confine_to: Option<Vec<PathBuf>>,
+    /// Read-only lower directories under the source, if any
+    overlay: Option<Overlay>,
+    /// Names kept out of the mount
+    name_filter: NameFilter,
+    /// Match names that do not exist as spelled ignoring case
+    case_insensitive: bool,
+    /// (source, mount) uid translations
+    uid_map: Vec<(u32, u32)>,
+    /// (source, mount) gid translations
+    gid_map: Vec<(u32, u32)>,
+    /// Attribute cache timeout
+    attr_ttl: Duration,
+    /// Entry cache timeout
+    entry_ttl: Duration,
+    /// How long the kernel may remember a missing name, zero to not cache misses
+    negative_ttl: Duration,
+    /// Inode to path mappings
+    inodes: Arc<InodeTable>,
+    /// Attributes served without a fresh stat, when enabled
+    attr_cache: Arc<AttrCache>,
+    /// Open file handles, shared so I/O on one never holds up another, and
+    /// with the idle handle reaper
+    open_files: Arc<HandleTable>,
+    /// How long a handle may go unused before the reaper closes its file
+    idle_handle_timeout: Option<Duration>,
+    /// How often the reaper looks for idle handles
+    idle_handle_scan_interval: Duration,
+    /// Writes made through the mount, when telling the kernel about changes
+    /// made directly in the source
+    own_writes: Option<Arc<OwnWrites>>,
+    /// Deadline for reads and writes on the source, when configured
+    deadline: Option<Arc<Deadline>>,
+    /// Rate limits on reads and writes, when configured
+    throttle: Option<Arc<Throttle>>,
+    /// Threads serving reads and writes, None to serve them in the session
+    workers: Option<WorkerPool>,
+    /// Descriptor to signal once the kernel has initialized the mount
+    ready_fd: Option<RawFd>,
+    /// Number of open file handles per inode
+    open_counts: Mutex<HashMap<u64, usize>>,
+    /// Unlinked files that are still open, renamed aside until last close
+    hidden_files: Mutex<HashMap<u64, PathBuf>>,
+    /// Kernel poll handles a thread is waiting to notify
+    poll_waits: Arc<Mutex<HashSet<u64>>>,
+    /// Descriptions holding byte-range locks, keyed by inode and lock owner
+    lock_files: Mutex<HashMap<(u64, u64), File>>,
+    /// Blocking lock requests still waiting
+    lock_waiters: Arc<AtomicUsize>,
+    /// Directory listings snapshotted at opendir, keyed by handle
+    open_dirs: Mutex<HashMap<u64, Vec<DirEntry>>>,
+    /// Next available file handle
+    next_fh: AtomicU64,
+    /// Request counters, shared with any stats handles
+    counters: Arc<Counters>,
+}
+
+impl PassthroughFS {
+    /// Create a filesystem mirroring `source`, which should be an absolute path
+    pub fn new(source: PathBuf) -> Self {
+        Self::with_config(PassthroughConfig::new(source))
+            .expect("A default configuration opens nothing that can fail")
+    }
+
+    /// Create a filesystem from a full configuration, failing if the inode
+    /// database cannot be loaded or a name pattern in `exclude` or
+    /// `include` fails [`check_glob`]
+    pub fn with_config(config: PassthroughConfig) -> io::Result<Self> {
+        let source = config.source;
+
+        // Root directory inode is 1, and is never forgotten
+        let backing = fs::metadata(&source)
+            .map(|metadata| (metadata.dev(), metadata.ino()))
+            .unwrap_or_default();
+
+        let overlay =
+            (!config.lower.is_empty()).then(|| Overlay::new(source.clone(), config.lower));
+        let confine_to = config.no_follow_escapes.then(|| {
+            let layers = match &overlay {
+                Some(overlay) => overlay.layers(),
+                None => std::slice::from_ref(&source),
+            };
+            layers
+                .iter()
+                .map(|layer| fs::canonicalize(layer).unwrap_or_else(|_| layer.clone()))
+                .collect()
+        });
+
+        let name_filter = NameFilter::new(&config.exclude, &config.include)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let inodes = match &config.inode_db {
+            Some(path) => InodeTable::persistent(backing, path).map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("inode database {}: {}", path.display(), e),
+                )
+            })?,
+            None => InodeTable::new(backing),
+        };
+
+        Ok(PassthroughFS {
+            source,
+            confine_to,
+            overlay,
+            name_filter,
+            case_insensitive: config.case_insensitive,
+            read_only: config.read_only,
+            readdirplus: config.readdirplus,
+            enforce_permissions: config.enforce_permissions,
+            detect_zero_holes: config.detect_zero_holes,
+            writeback_cache: config.writeback_cache,
+            direct_io: config.direct_io,
+            noatime: config.noatime,
+            checksums: config
+                .verify_checksums
+                .then(|| Arc::new(Checksums::default())),
+            uid_map: config.uid_map,
+            gid_map: config.gid_map,
+            attr_ttl: config.attr_ttl,
+            entry_ttl: config.entry_ttl,
+            negative_ttl: if config.case_insensitive {
+                Duration::ZERO
+            } else {
+                config.negative_ttl
+            },
+            source_backing: backing,
+            source_lost: AtomicBool::new(false),
+            inodes: Arc::new(inodes),
+            attr_cache: Arc::new(AttrCache::new(config.attr_cache_ttl)),
+            open_files: Arc::new(HandleTable::new(config.max_open_files)),
+            idle_handle_timeout: config.idle_handle_timeout,
+            idle_handle_scan_interval: config.idle_handle_scan_interval,
+            own_writes: config.watch_source.then(Default::default),
+            deadline: config
+                .op_timeout
+                .map(|timeout| Arc::new(Deadline::new(timeout))),
+            throttle: Throttle::new(config.read_bps, config.write_bps, config.iops).map(Arc::new),
+            workers: (config.io_threads > 1).then(|| WorkerPool::new(config.io_threads)),
+            ready_fd: config.ready_fd,
+            open_counts: Mutex::new(HashMap::new()),
+            hidden_files: Mutex::new(HashMap::new()),
+            poll_waits: Arc::new(Mutex::new(HashSet::new())),
+            lock_files: Mutex::new(HashMap::new()),
+            lock_waiters: Arc::new(AtomicUsize::new(0)),
+            open_dirs: Mutex::new(HashMap::new()),
+            next_fh: AtomicU64::new(1),
+            counters: Arc::new(Counters::default()),
+        })
+    }
+
+    /// Snapshot of the request counters
+    pub fn stats(&self) -> FsStats {
+        self.stats_handle().stats()
+    }
+
+    /// Handle that keeps reporting the counters after the filesystem has
+    /// been moved into a mount session
+    pub fn stats_handle(&self) -> StatsHandle {
+        StatsHandle(Arc::clone(&self.counters))
+    }
+
+    /// Start the thread closing the backing files of idle handles, if an
+    /// idle timeout is configured. It exits once the filesystem is dropped.
+    fn spawn_handle_reaper(&self) {
+        let Some(timeout) = self.idle_handle_timeout else {
+            return;
+        };
+        let interval = self.idle_handle_scan_interval;
+        let open_files = Arc::downgrade(&self.open_files);
+
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            let Some(open_files) = open_files.upgrade() else {
+                return;
+            };
+            let closed = open_files.reap(timeout);
+            if closed > 0 {
+                debug!("Closed {} idle backing files", closed);
+            }
+        });
+    }
+
+    /// Path of `inode` relative to the source, failing with EACCES if it
+    /// resolves outside the source when escapes are refused. A symlink at
+    /// the end of the path is not followed, the kernel only does that
+    /// through readlink.
+    fn path_of(&self, inode: u64) -> Result<PathBuf, i32> {
+        let path = self.inodes.get_path(inode).ok_or(ENOENT)?;
+        if !self.confined(&self.real_path(&path), false) {
+            return Err(EACCES);
+        }
+        Ok(path)
+    }
+
+    /// Like [`Self::path_of`], for a directory whose entries are about to
+    /// be listed, looked up or changed, so a symlink at the end is followed
+    fn dir_path_of(&self, inode: u64) -> Result<PathBuf, i32> {
+        let path = self.inodes.get_path(inode).ok_or(ENOENT)?;
+        if !self.confined(&self.real_path(&path), true) {
+            return Err(EACCES);
+        }
+        Ok(path)
+    }
+
+    /// Whether `real_path` stays within the source once symlinks are
+    /// resolved, as it does unless a directory in the source was swapped
+    /// for a symlink. Paths that fail to resolve are left for the operation
+    /// itself to report.
+    fn confined(&self, real_path: &Path, follow: bool) -> bool {
+        let Some(roots) = &self.confine_to else {
+            return true;
+        };
+
+        let resolved = match (fs::symlink_metadata(real_path), real_path.parent()) {
+            (Ok(metadata), Some(parent)) if !follow && metadata.file_type().is_symlink() => {
+                fs::canonicalize(parent)
+            }
+            _ => fs::canonicalize(real_path),
+        };
+        match resolved {
+            Ok(resolved) if !roots.iter().any(|root| resolved.starts_with(root)) => {
+                warn!(
+                    "Refusing {}, which resolves to {} outside the source",
+                    real_path.display(),
+                    resolved.display()
+                );
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Whether the kernel may follow a symlink at `real_path` to `target`.
+    /// Targets are resolved as seen from the source, so absolute ones and
+    /// those climbing out with `..` are refused when escapes are.
+    fn target_confined(&self, real_path: &Path, target: &Path) -> bool {
+        let Some(roots) = &self.confine_to else {
+            return true;
+        };
+
+        let target = match real_path.parent() {
+            Some(parent) => parent.join(target),
+            None => target.to_path_buf(),
+        };
+        match fs::canonicalize(&target) {
+            Ok(resolved) => roots.iter().any(|root| resolved.starts_with(root)),
+            // Dangling, so nothing to reach through it
+            Err(_) => true,
+        }
+    }
+
+    /// Get the real path on the underlying filesystem, in the topmost
+    /// layer holding it when there are lower directories
+    fn real_path(&self, relative: &Path) -> PathBuf {
+        match &self.overlay {
+            Some(overlay) => overlay.resolve(relative),
+            None => self.source.join(relative),
+        }
+    }
+
+    /// Name of the entry `name` refers to in the directory at `parent_path`.
+    /// With case-insensitive lookups a name missing as spelled resolves to
+    /// an entry matching it ignoring case, the first in byte order when
+    /// several do. The inode is recorded under the entry's real name, so
+    /// requests on it never scan the directory again.
+    fn resolve_case(&self, parent_path: &Path, name: &OsStr) -> OsString {
+        if !self.case_insensitive
+            || fs::symlink_metadata(self.real_path(&parent_path.join(name))).is_ok()
+        {
+            return name.to_owned();
+        }
+        let entries = match &self.overlay {
+            Some(overlay) => overlay.list(parent_path),
+            None => list_dir(&self.real_path(parent_path)),
+        };
+        let folded = fold_case(name);
+        entries
+            .into_iter()
+            .flatten()
+            .map(|entry| entry.name)
+            .filter(|entry| !self.name_filter.hides(entry) && fold_case(entry) == folded)
+            .min()
+            .unwrap_or_else(|| name.to_owned())
+    }
+
+    /// Copy `relative` up into the source before it is changed, if it
+    /// only exists in a lower directory
+    fn copy_up(&self, relative: &Path) -> Result<(), i32> {
+        match &self.overlay {
+            Some(overlay) => {
+                let copied = overlay.copy_up(relative);
+                self.rebind_copied(copied)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Copy the parent of a new entry at `relative` up into the source
+    fn prepare_entry(&self, relative: &Path) -> Result<(), i32> {
+        match relative.parent() {
+            Some(parent) => self.copy_up(parent),
+            None => Ok(()),
+        }
+    }
+
+    /// Settle a new entry created at `relative` into the overlay
+    fn finish_entry(&self, relative: &Path, is_dir: bool) {
+        if let Some(overlay) = &self.overlay {
+            if let Err(e) = overlay.finish_entry(relative, is_dir) {
+                warn!(
+                    "Failed to settle {} into the overlay: {:?}",
+                    relative.display(),
+                    e
+                );
+            }
+        }
+    }
+
+    /// Keep the inodes of copied-up paths, now backed by their copies
+    fn rebind_copied(&self, copied: io::Result<Vec<PathBuf>>) -> Result<(), i32> {
+        let copied = copied.map_err(|e| {
+            error!("copy up error: {:?}", e);
+            self.source_errno(&e)
+        })?;
+        for path in copied {
+            if let Ok(metadata) = fs::symlink_metadata(self.source.join(&path)) {
+                self.inodes
+                    .rebind(&path, (metadata.dev(), metadata.ino()), birth(&metadata));
+                if let Some(inode) = self.inodes.get_inode(&path) {
+                    self.attr_cache.invalidate(inode);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Hide `relative` in the lower directories once it is gone from the
+    /// source, if they have it
+    fn whiteout(&self, relative: &Path) -> Result<(), i32> {
+        match &self.overlay {
+            Some(overlay) => overlay.whiteout(relative).map_err(|e| {
+                error!("whiteout error: {:?}", e);
+                self.source_errno(&e)
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Bring both ends of a rename into the source, returning their real
+    /// paths there. Directories a lower layer has are not copied up with
+    /// their whole tree, so renaming them fails with EXDEV, which makes
+    /// `mv` fall back to copying.
+    fn prepare_rename(
+        &self,
+        old: &Path,
+        new: &Path,
+        flags: u32,
+    ) -> Result<(PathBuf, PathBuf), i32> {
+        let Some(overlay) = &self.overlay else {
+            return Ok((self.real_path(old), self.real_path(new)));
+        };
+
+        #[cfg(target_os = "linux")]
+        let (exchange, noreplace) = (
+            flags & libc::RENAME_EXCHANGE != 0,
+            flags & libc::RENAME_NOREPLACE != 0,
+        );
+        #[cfg(not(target_os = "linux"))]
+        let (exchange, noreplace) = {
+            let _ = flags;
+            (false, false)
+        };
+
+        let old_metadata =
+            fs::symlink_metadata(overlay.resolve(old)).map_err(|e| self.source_errno(&e))?;
+        let new_metadata = fs::symlink_metadata(overlay.resolve(new)).ok();
+        let new_is_dir = new_metadata.as_ref().is_some_and(|m| m.is_dir());
+        if (old_metadata.is_dir() && overlay.below(old))
+            || (exchange && new_is_dir && overlay.below(new))
+        {
+            return Err(EXDEV);
+        }
+
+        if exchange {
+            self.copy_up(old)?;
+            self.copy_up(new)?;
+        } else {
+            // The target may only exist below, where renaming in the source
+            // would not notice it
+            match &new_metadata {
+                Some(_) if noreplace => return Err(EEXIST),
+                Some(_) if old_metadata.is_dir() && !new_is_dir => return Err(ENOTDIR),
+                Some(_) if !old_metadata.is_dir() && new_is_dir => return Err(EISDIR),
+                Some(_) if new_is_dir => {
+                    overlay.remove_dir(new).map_err(|e| self.source_errno(&e))?;
+                }
+                _ => {}
+            }
+            self.copy_up(old)?;
+            self.prepare_entry(new)?;
+        }
+        Ok((self.source.join(old), self.source.join(new)))
+    }
+
+    /// Hide the old name of a renamed entry in the lower directories and
+    /// settle its new one into the overlay
+    fn finish_rename(&self, old: &Path, new: &Path) -> Result<(), i32> {
+        if self.overlay.is_none() {
+            return Ok(());
+        }
+        self.whiteout(old)?;
+        let is_dir = fs::symlink_metadata(self.source.join(new)).is_ok_and(|m| m.is_dir());
+        self.finish_entry(new, is_dir);
+        Ok(())
+    }
+
+    /// Run `job` on a worker thread, or right away without workers
+    fn offload(&self, job: impl FnOnce() + Send + 'static) {
+        match &self.workers {
+            Some(workers) => workers.execute(job),
+            None => job(),
+        }
+    }
+
+    /// Attributes of inode `ino` itself, or of its parent for `dotdot`,
+    /// taking a lookup reference. Inodes the table no longer knows, or
+    /// whose path now holds another file, are stale.
+    fn lookup_dot(&self, ino: u64, dotdot: bool) -> Result<FileAttr, i32> {
+        let path = self.inodes.get_path(ino).ok_or(ESTALE)?;
+        let path = match path.parent() {
+            Some(parent) if dotdot => parent.to_path_buf(),
+            _ => path,
+        };
+        let metadata = fs::symlink_metadata(self.real_path(&path)).map_err(|_| ESTALE)?;
+        let backing = (metadata.dev(), metadata.ino());
+
+        let inode = if dotdot {
+            self.inodes
+                .get_or_create(&path, backing, birth(&metadata), 1)
+        } else if self.inodes.known(backing) == Some(ino) && self.inodes.add_lookups(ino, 1) {
+            ino
+        } else {
+            return Err(ESTALE);
+        };
+        Ok(self.metadata_to_attr(&metadata, inode))
+    }
+
+    /// [`check_name`], also refusing the names whiteouts are kept under
+    /// and treating names hidden by the filter as missing
+    fn check_entry_name(&self, name: &OsStr) -> Result<(), i32> {
+        check_name(name)?;
+        if self.overlay.is_some() && overlay::is_reserved(name) {
+            return Err(EINVAL);
+        }
+        if self.name_filter.hides(name) {
+            return Err(ENOENT);
+        }
+        Ok(())
+    }
+
+    /// Errno for a failed operation on the source. When the source
+    /// directory itself was removed, unmounted or replaced, a missing path
+    /// says nothing about the name asked for, so EIO is reported instead
+    /// and a warning is logged once.
+    fn source_errno(&self, e: &io::Error) -> i32 {
+        let errno = io_error_to_errno(e);
+        if errno != ENOENT && errno != ENOTDIR {
+            return errno;
+        }
+
+        let intact = fs::metadata(&self.source)
+            .map(|metadata| {
+                metadata.is_dir() && (metadata.dev(), metadata.ino()) == self.source_backing
+            })
+            .unwrap_or(false);
+        if intact {
+            return errno;
+        }
+
+        if !self.source_lost.swap(true, Ordering::SeqCst) {
+            warn!(
+                "Source directory {} is gone, failing requests with EIO",
+                self.source.display()
+            );
+        }
+        EIO
+    }
+
+    /// Check `mask` against the mode bits for the caller, whose ids are
+    /// translated back to source ids first
+    fn caller_permits(&self, req: &Request, metadata: &fs::Metadata, mask: i32) -> bool {
+        let uid = unmap_id(&self.uid_map, req.uid());
+        let groups: Vec<u32> = caller_groups(req.pid(), req.gid())
+            .into_iter()
+            .map(|gid| unmap_id(&self.gid_map, gid))
+            .collect();
+        permits(metadata, uid, &groups, mask)
+    }
+
+    /// When enforcing permissions, fail with EACCES unless the caller's
+    /// credentials grant `mask` on `real_path`. Only the mode bits are
+    /// consulted; ACLs and the sticky bit are not.
+    fn check_caller(&self, req: &Request, real_path: &Path, mask: i32) -> Result<(), i32> {
+        if !self.enforce_permissions {
+            return Ok(());
+        }
+
+        let metadata = fs::metadata(real_path).map_err(|e| self.source_errno(&e))?;
+        if self.caller_permits(req, &metadata, mask) {
+            Ok(())
+        } else {
+            debug!("denied uid={} mask={} on {:?}", req.uid(), mask, real_path);
+            Err(EACCES)
+        }
+    }
+
+    /// Drop the cached attributes of a directory and of the entry at
+    /// `path` inside it, whose link count or ctime is about to change
+    fn invalidate_entry(&self, parent: u64, path: &Path) {
+        self.attr_cache.invalidate(parent);
+        if let Some(inode) = self.inodes.get_inode(path) {
+            self.attr_cache.invalidate(inode);
+        }
+    }
+
+    /// Register an open file for `ino` and return its new handle
+    fn add_handle(&self, ino: u64, flags: i32, file: File) -> u64 {
+        let fh = self.next_fh.fetch_add(1, Ordering::SeqCst);
+        self.open_files.insert(fh, ino, flags, file);
+        *self.open_counts.lock().unwrap().entry(ino).or_insert(0) += 1;
+        fh
+    }
+
+    /// Close a handle, deleting its file for real if it was unlinked while
+    /// open and this was the last handle
+    fn remove_handle(&self, ino: u64, fh: u64) {
+        if !self.open_files.remove(fh) {
+            return;
+        }
+        if let Some(checksums) = &self.checksums {
+            checksums.forget(fh);
+        }
+
+        let mut open_counts = self.open_counts.lock().unwrap();
+        let count = open_counts.entry(ino).or_insert(1);
+        *count -= 1;
+        if *count > 0 {
+            return;
+        }
+        open_counts.remove(&ino);
+        drop(open_counts);
+
+        self.store_checksum(ino);
+        if let Some(hidden) = self.hidden_files.lock().unwrap().remove(&ino) {
+            if let Err(e) = fs::remove_file(self.real_path(&hidden)) {
+                error!("release: failed to remove {:?}: {:?}", hidden, e);
+            }
+            self.inodes.remove(&hidden, false);
+        }
+    }
+
+    /// Note a change to `ino` other than a write, storing its checksum
+    /// right away when no handle is left to do it at release
+    fn checksum_changed(&self, ino: u64) {
+        let Some(checksums) = &self.checksums else {
+            return;
+        };
+        checksums.changed(ino);
+        if !self.open_counts.lock().unwrap().contains_key(&ino) {
+            self.store_checksum(ino);
+        }
+    }
+
+    /// Whether `name` is the checksum attribute, which only the
+    /// filesystem itself may change while checksums are verified
+    fn checksum_reserved(&self, name: &OsStr) -> bool {
+        self.checksums.is_some() && name == checksum::XATTR_NAME
+    }
+
+    /// Store the checksum of `ino` if it changed through the mount
+    fn store_checksum(&self, ino: u64) {
+        if let (Some(checksums), Some(path)) = (&self.checksums, self.inodes.get_path(ino)) {
+            checksums.store(ino, &self.real_path(&path));
+        }
+    }
+
+    /// Get an open file by handle, reopening it if it was evicted. The map
+    /// lock is released before the caller does any I/O.
+    fn open_file(&self, fh: u64) -> Option<Arc<File>> {
+        let handle = self.open_files.get(fh)?;
+        self.backing_file(fh, &handle)
+    }
+
+    /// Get the file to read from or write to through `fh`, failing with
+    /// EBADF like the kernel when the handle was not opened for it
+    fn io_file(&self, fh: u64, write: bool) -> Result<Arc<File>, i32> {
+        let handle = self.open_files.get(fh).ok_or(EBADF)?;
+        if !handle.allows(write) {
+            return Err(EBADF);
+        }
+        self.backing_file(fh, &handle).ok_or(EBADF)
+    }
+
+    /// Flags for the reply to open or create. Handles opened with O_DIRECT,
+    /// or every handle with `direct_io`, bypass the kernel's page cache, so
+    /// each read and write reaches the source as the caller issued it.
+    fn open_reply_flags(&self, flags: i32) -> u32 {
+        if self.direct_io || flags & O_DIRECT != 0 {
+            fuser::consts::FOPEN_DIRECT_IO
+        } else {
+            0
+        }
+    }
+
+    /// Whether a write of `data` through `fh` should become a hole. Appends
+    /// are left alone since their offset is only a guess at EOF.
+    fn zero_hole_write(&self, fh: u64, data: &[u8]) -> bool {
+        self.detect_zero_holes
+            && !data.is_empty()
+            && data.iter().all(|&byte| byte == 0)
+            && self
+                .open_files
+                .get(fh)
+                .is_some_and(|handle| handle.flags & libc::O_APPEND == 0)
+    }
+
+    fn backing_file(&self, fh: u64, handle: &OpenHandle) -> Option<Arc<File>> {
+        let reopen = || {
+            let path = self
+                .path_of(handle.ino)
+                .map_err(io::Error::from_raw_os_error)?;
+            let access = handle.flags & libc::O_ACCMODE;
+            let mut options = OpenOptions::new();
+            options
+                .read(access == libc::O_RDONLY || access == libc::O_RDWR)
+                .write(access == libc::O_WRONLY || access == libc::O_RDWR)
+                .append(handle.flags & libc::O_APPEND != 0);
+            open_custom(
+                &options,
+                &self.real_path(&path),
+                handle.flags & (libc::O_NOATIME | O_DIRECT),
+            )
+        };
+        match self.open_files.file(fh, handle, reopen) {
+            Ok(file) => Some(file),
+            Err(e) => {
+                warn!("failed to reopen evicted handle {}: {:?}", fh, e);
+                None
+            }
+        }
+    }
+
+    /// Get the lock owner's description of a file, opening it from `fh` if needed
+    #[cfg(target_os = "linux")]
+    fn lock_file(&self, ino: u64, fh: u64, lock_owner: u64) -> io::Result<File> {
+        let mut lock_files = self.lock_files.lock().unwrap();
+        if let Some(file) = lock_files.get(&(ino, lock_owner)) {
+            return file.try_clone();
+        }
+
+        let reopened = match self.open_file(fh) {
+            Some(file) => lock::reopen(&file)?,
+            None => return Err(io::Error::from_raw_os_error(EBADF)),
+        };
+        let file = reopened.try_clone()?;
+        lock_files.insert((ino, lock_owner), reopened);
+        Ok(file)
+    }
+
+    /// Convert std::fs::Metadata to FileAttr
+    fn metadata_to_attr(&self, metadata: &fs::Metadata, inode: u64) -> FileAttr {
+        let kind = fuse_file_type(metadata.file_type());
+
+        let atime = timestamp(metadata.atime(), metadata.atime_nsec());
+        let mtime = timestamp(metadata.mtime(), metadata.mtime_nsec());
+        let ctime = timestamp(metadata.ctime(), metadata.ctime_nsec());
+        // statx STATX_BTIME on Linux, st_birthtime on macOS; some
+        // filesystems do not record it at all
+        let crtime = metadata.created().unwrap_or(UNIX_EPOCH);
+
+        FileAttr {
+            ino: inode,
+            size: metadata.size(),
+            blocks: metadata.blocks(),
+            atime,
+            mtime,
+            ctime,
+            crtime,
+            kind,
+            perm: (metadata.mode() & 0o7777) as u16,
+            nlink: metadata.nlink() as u32,
+            uid: map_id(&self.uid_map, metadata.uid()),
+            gid: map_id(&self.gid_map, metadata.gid()),
+            rdev: encode_rdev(metadata.rdev()),
+            blksize: metadata.blksize() as u32,
+            flags: 0,
+        }
+    }
+}
+
+impl Filesystem for PassthroughFS {
+    /// Negotiate request sizes and capabilities with the kernel
+    ///
+    /// Enabled capabilities:
+    /// - FUSE_POSIX_LOCKS: byte-range locks go to the source filesystem
+    /// - FUSE_AUTO_INVAL_DATA: cached pages are dropped when the source's
+    ///   mtime changes, so edits made outside the mount become visible
+    /// - FUSE_ATOMIC_O_TRUNC: O_TRUNC is handled by open rather than a
+    ///   separate setattr
+    /// - FUSE_EXPORT_SUPPORT: lookups of "." and ".." resolve file handles,
+    ///   so the mount can be exported over NFS
+    /// - FUSE_DO_READDIRPLUS, FUSE_READDIRPLUS_AUTO: listings carry
+    ///   attributes unless disabled in the configuration
+    /// - FUSE_WRITEBACK_CACHE, when configured: the kernel buffers writes
+    ///   and sends them in large chunks, at the latest on flush or fsync,
+    ///   and keeps track of file sizes itself
+    ///
+    /// FUSE_POSIX_ACL stays off: ACLs are only available as raw xattrs.
+    /// FUSE_FLOCK_LOCKS stays off because fuser has no flock callback and
+    /// drops FUSE_LK_FLOCK, so flock(2) requests would arrive as setlk and
+    /// conflict with fcntl locks. Left to the kernel, flock still excludes
+    /// other users of the mount, just not the source.
+    fn init(
+        &mut self,
+        _req: &Request,
+        config: &mut fuser::KernelConfig,
+    ) -> Result<(), libc::c_int> {
+        let max_write = config
+            .set_max_write(MAX_REQUEST_SIZE)
+            .map(|_| MAX_REQUEST_SIZE)
+            .unwrap_or_else(|nearest| {
+                let _ = config.set_max_write(nearest);
+                nearest
+            });
+        let max_readahead = config
+            .set_max_readahead(MAX_REQUEST_SIZE)
+            .map(|_| MAX_REQUEST_SIZE)
+            .unwrap_or_else(|nearest| {
+                let _ = config.set_max_readahead(nearest);
+                nearest
+            });
+
+        let mut capabilities = fuser::consts::FUSE_AUTO_INVAL_DATA
+            | fuser::consts::FUSE_ATOMIC_O_TRUNC
+            | fuser::consts::FUSE_EXPORT_SUPPORT;
+        #[cfg(target_os = "linux")]
+        {
+            capabilities |= fuser::consts::FUSE_POSIX_LOCKS;
+        }
+        // With AUTO the kernel only asks for attributes when the entries
+        // are likely to be looked up afterwards
+        if self.readdirplus {
+            capabilities |=
+                fuser::consts::FUSE_DO_READDIRPLUS | fuser::consts::FUSE_READDIRPLUS_AUTO;
+        }
+        if self.writeback_cache {
+            capabilities |= fuser::consts::FUSE_WRITEBACK_CACHE;
+        }
+        // Take whatever subset the kernel offers
+        if let Err(unsupported) = config.add_capabilities(capabilities) {
+            info!("Kernel does not support capabilities {:#x}", unsupported);
+            let _ = config.add_capabilities(capabilities & !unsupported);
+        }
+
+        info!(
+            "Negotiated max_write={}, max_readahead={}",
+            max_write, max_readahead
+        );
+        if let Some(fd) = self.ready_fd.take() {
+            signal_ready(fd);
+        }
+        Ok(())
+    }
+
+    /// Delete files that were unlinked while open and never closed
+    fn destroy(&mut self) {
+        for (_, hidden) in self.hidden_files.lock().unwrap().drain() {
+            let _ = fs::remove_file(self.real_path(&hidden));
+        }
+    }
+
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        debug!("lookup: parent={}, name={:?}", parent, name);
+        log_caller("lookup", req);
+        stats::add(&self.counters.lookup, 1);
+
+        // File handles the kernel no longer has cached, as NFS hands them
+        // back, are resolved by looking up "." in the inode and ".." for
+        // its parent
+        if name == "." || name == ".." {
+            match self.lookup_dot(parent, name == "..") {
+                Ok(attr) => reply.entry(&self.entry_ttl, &attr, self.inodes.generation(attr.ino)),
+                Err(errno) => reply.error(errno),
+            }
+            return;
+        }
+
+        if let Err(errno) = self.check_entry_name(name) {
+            reply.error(errno);
+            return;
+        }
+
+        let parent_path = match self.dir_path_of(parent) {
+            Ok(p) => p,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        let relative_path = parent_path.join(self.resolve_case(&parent_path, name));
+
+        // A known name with fresh cached attributes needs no stat
+        let cached = self
+            .inodes
+            .get_inode(&relative_path)
+            .and_then(|inode| self.attr_cache.get(inode));
+        if let Some(attr) = cached {
+            if self.inodes.add_lookups(attr.ino, 1) {
+                reply.entry(&self.entry_ttl, &attr, self.inodes.generation(attr.ino));
+                return;
+            }
+        }
+
+        let real_path = self.real_path(&relative_path);
+        match fs::symlink_metadata(&real_path) {
+            Ok(metadata) => {
+                let inode = self.inodes.get_or_create(
+                    &relative_path,
+                    (metadata.dev(), metadata.ino()),
+                    birth(&metadata),
+                    1,
+                );
+                let attr = self.metadata_to_attr(&metadata, inode);
+                self.attr_cache.insert(&attr);
+                reply.entry(&self.entry_ttl, &attr, self.inodes.generation(attr.ino));
+            }
+            Err(e) => match self.source_errno(&e) {
+                ENOENT if !self.negative_ttl.is_zero() => {
+                    // An entry with inode 0 makes the kernel cache the miss as
+                    // a negative dentry. The kernel drops it by itself when the
+                    // name is created or renamed into through the mount; only
+                    // changes made directly to the source wait out the TTL.
+                    reply.entry(&self.negative_ttl, &negative_attr(), 0);
+                }
+                errno => reply.error(errno),
+            },
+        }
+    }
+
+    fn forget(&mut self, req: &Request, ino: u64, nlookup: u64) {
+        debug!("forget: ino={}, nlookup={}", ino, nlookup);
+        log_caller("forget", req);
+        if self.inodes.forget(ino, nlookup) {
+            self.attr_cache.invalidate(ino);
+        }
+    }
+
+    /// Forget many inodes at once, as the kernel does when it drops its
+    /// dentry cache, taking the inode table lock only once
+    fn batch_forget(&mut self, req: &Request, nodes: &[fuser::fuse_forget_one]) {
+        debug!("batch_forget: {} inodes", nodes.len());
+        log_caller("batch_forget", req);
+        let evicted = self
+            .inodes
+            .forget_many(nodes.iter().map(|node| (node.nodeid, node.nlookup)));
+        for ino in evicted {
+            self.attr_cache.invalidate(ino);
+        }
+    }
+
+    fn getattr(&mut self, req: &Request, ino: u64, fh: Option<u64>, reply: ReplyAttr) {
+        debug!("getattr: ino={}, fh={:?}", ino, fh);
+        log_caller("getattr", req);
+        stats::add(&self.counters.getattr, 1);
+
+        if let Some(attr) = self.attr_cache.get(ino) {
+            reply.attr(&self.attr_ttl, &attr);
+            return;
+        }
+
+        // An open handle stays valid even if its path was unlinked or renamed
+        if let Some(fh) = fh {
+            if let Some(file) = self.open_file(fh) {
+                match file.metadata() {
+                    Ok(metadata) => {
+                        let attr = self.metadata_to_attr(&metadata, ino);
+                        self.attr_cache.insert(&attr);
+                        reply.attr(&self.attr_ttl, &attr);
+                    }
+                    Err(e) => {
+                        error!("getattr error: {:?}", e);
+                        reply.error(self.source_errno(&e));
+                    }
+                }
+                return;
+            }
+        }
+
+        let path = match self.path_of(ino) {
+            Ok(p) => p,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        let real_path = self.real_path(&path);
+
+        match fs::symlink_metadata(&real_path) {
+            Ok(metadata) => {
+                let attr = self.metadata_to_attr(&metadata, ino);
+                self.attr_cache.insert(&attr);
+                reply.attr(&self.attr_ttl, &attr);
+            }
+            Err(e) => {
+                reply.error(self.source_errno(&e));
+            }
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        debug!("setattr: ino={}, fh={:?}", ino, fh);
+        log_caller("setattr", req);
+        stats::add(&self.counters.setattr, 1);
+
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+
+        let path = match self.path_of(ino) {
+            Ok(p) => p,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        let changes = mode.is_some()
+            || uid.is_some()
+            || gid.is_some()
+            || size.is_some()
+            || atime.is_some()
+            || mtime.is_some();
+        if changes {
+            if let Err(errno) = self.copy_up(&path) {
+                reply.error(errno);
+                return;
+            }
+        }
+        let real_path = self.real_path(&path);
+
+        if size.is_some() {
+            if let Err(errno) = self.check_caller(req, &real_path, libc::W_OK) {
+                reply.error(errno);
+                return;
+            }
+        }
+        self.attr_cache.invalidate(ino);
+
+        // Handle file truncation, through the open handle when there is one
+        if let Some(new_size) = size {
+            let result = match fh.and_then(|fh| self.open_file(fh)) {
+                Some(file) => file.set_len(new_size),
+                None => OpenOptions::new()
+                    .write(true)
+                    .open(&real_path)
+                    .and_then(|file| file.set_len(new_size)),
+            };
+            if let Err(e) = result {
+                error!("setattr truncate error: {:?}", e);
+                reply.error(self.source_errno(&e));
+                return;
+            }
+            self.checksum_changed(ino);
+        }
+
+        // Handle uid/gid change, on a symlink itself rather than its target
+        if uid.is_some() || gid.is_some() {
+            let uid = uid.map_or(u32::MAX, |uid| unmap_id(&self.uid_map, uid));
+            let gid = gid.map_or(u32::MAX, |gid| unmap_id(&self.gid_map, gid));
+            let result = path_to_cstring(&real_path).and_then(|path_cstr| {
+                if unsafe { libc::lchown(path_cstr.as_ptr(), uid, gid) } == -1 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(())
+                }
+            });
+            if let Err(e) = result {
+                error!("setattr chown error: {:?}", e);
+                reply.error(self.source_errno(&e));
+                return;
+            }
+        }
+
+        // Handle permission change after the ownership change, since chown
+        // clears the setuid and setgid bits
+        if let Some(new_mode) = mode {
+            if let Err(e) = fs::set_permissions(&real_path, fs::Permissions::from_mode(new_mode)) {
+                error!("setattr chmod error: {:?}", e);
+                reply.error(self.source_errno(&e));
+                return;
+            }
+        }
+
+        // Handle atime/mtime change
+        if atime.is_some() || mtime.is_some() {
+            let times = [time_to_timespec(atime), time_to_timespec(mtime)];
+            let result = path_to_cstring(&real_path).and_then(|path_cstr| {
+                let ret = unsafe {
+                    libc::utimensat(
+                        libc::AT_FDCWD,
+                        path_cstr.as_ptr(),
+                        times.as_ptr(),
+                        libc::AT_SYMLINK_NOFOLLOW,
+                    )
+                };
+                if ret == 0 {
+                    Ok(())
+                } else {
+                    Err(io::Error::last_os_error())
+                }
+            });
+            if let Err(e) = result {
+                error!("setattr utimensat error: {:?}", e);
+                reply.error(self.source_errno(&e));
+                return;
+            }
+        }
+
+        // Return updated attributes
+        match fs::symlink_metadata(&real_path) {
+            Ok(metadata) => {
+                let attr = self.metadata_to_attr(&metadata, ino);
+                self.attr_cache.insert(&attr);
+                reply.attr(&self.attr_ttl, &attr);
+            }
+            Err(e) => {
+                reply.error(self.source_errno(&e));
+            }
+        }
+    }
+
+    /// Read through a userspace buffer. fuser only replies from byte
+    /// slices and keeps the /dev/fuse descriptor private, so neither
+    /// splicing from the source file nor kernel passthrough (FUSE 7.40) is
+    /// available; the large `max_readahead` negotiated in `init` keeps the
+    /// per-request copy overhead down instead.
+    fn read(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        debug!(
+            "read: ino={}, fh={}, offset={}, size={}",
+            ino, fh, offset, size
+        );
+        log_caller("read", req);
+        stats::add(&self.counters.read, 1);
+
+        // The kernel never asks for more than it negotiated, so a larger
+        // size is only served in part rather than allocated
+        let size = size.min(MAX_REQUEST_SIZE);
+        let offset = match check_io_range(offset, size as u64) {
+            Ok(offset) => offset,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        let file = match self.io_file(fh, false) {
+            Ok(file) => file,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        // An open descriptor no longer depends on the source path, so
+        // errors are passed on as they are
+        let deadline = self.deadline.clone();
+        let counters = Arc::clone(&self.counters);
+        let checksums = self.checksums.clone();
+        let throttle = self.throttle.clone();
+        self.offload(move || {
+            let result = timed(deadline.as_deref(), move || {
+                if let Some(checksums) = &checksums {
+                    checksums.verify(ino, fh, &file)?;
+                }
+                let mut buffer = AlignedBuf::zeroed(size as usize);
+                let bytes_read = read_fully_at(&file, &mut buffer, offset)?;
+                Ok((buffer, bytes_read))
+            });
+            // Charged for what came back, as reads near the end of a file
+            // return less than asked for
+            if let Some(throttle) = &throttle {
+                let bytes_read = result.as_ref().map_or(0, |(_, bytes_read)| *bytes_read);
+                throttle.read(bytes_read as u64);
+            }
+            match result {
+                Ok((buffer, bytes_read)) => {
+                    stats::add(&counters.read_bytes, bytes_read as u64);
+                    reply.data(&buffer[..bytes_read]);
+                }
+                Err(e) => {
+                    error!("read error: {:?}", e);
+                    reply.error(io_error_to_errno(&e));
+                }
+            }
+        });
+    }
+
+    fn write(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        debug!(
+            "write: ino={}, fh={}, offset={}, size={}",
+            ino,
+            fh,
+            offset,
+            data.len()
+        );
+        log_caller("write", req);
+        stats::add(&self.counters.write, 1);
+
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+
+        let offset = match check_io_range(offset, data.len() as u64) {
+            Ok(offset) => offset,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        let file = match self.io_file(fh, true) {
+            Ok(file) => file,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        let hole = self.zero_hole_write(fh, data);
+        let deadline = self.deadline.clone();
+        let counters = Arc::clone(&self.counters);
+        let attr_cache = Arc::clone(&self.attr_cache);
+        let checksums = self.checksums.clone();
+        let throttle = self.throttle.clone();
+        let own_writes = self.own_writes.clone();
+        let data = AlignedBuf::copy_from(data);
+        self.offload(move || {
+            if let Some(throttle) = &throttle {
+                throttle.write(data.len() as u64);
+            }
+            let result = timed(deadline.as_deref(), move || {
+                let bytes_written = match &own_writes {
+                    Some(own_writes) => own_writes.write(ino, || {
+                        let result = write_data(&file, &data, offset, hole);
+                        let wrote = matches!(result, Ok(n) if n > 0);
+                        (result, wrote)
+                    }),
+                    None => write_data(&file, &data, offset, hole),
+                }?;
+                if let Some(checksums) = &checksums {
+                    checksums.wrote(ino, offset, &data[..bytes_written]);
+                }
+                Ok(bytes_written)
+            });
+            attr_cache.invalidate(ino);
+            match result {
+                Ok(bytes_written) => {
+                    stats::add(&counters.write_bytes, bytes_written as u64);
+                    reply.written(bytes_written as u32);
+                }
+                Err(e) => {
+                    error!("write error: {:?}", e);
+                    reply.error(io_error_to_errno(&e));
+                }
+            }
+        });
+    }
+
+    fn opendir(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        debug!("opendir: ino={}, flags={}", ino, flags);
+        log_caller("opendir", req);
+
+        let path = match self.dir_path_of(ino) {
+            Ok(p) => p,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        let real_path = self.real_path(&path);
+
+        if let Err(errno) = self.check_caller(req, &real_path, libc::R_OK) {
+            reply.error(errno);
+            return;
+        }
+
+        let entries = match &self.overlay {
+            Some(overlay) => overlay.list(&path),
+            None => list_dir(&real_path),
+        };
+        let entries = match entries {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("opendir error: {:?}", e);
+                reply.error(self.source_errno(&e));
+                return;
+            }
+        };
+
+        // The root is its own parent
+        let parent = path
+            .parent()
+            .and_then(|parent| self.inodes.get_inode(parent))
+            .unwrap_or(ino);
+        let mut all_entries: Vec<DirEntry> = vec![
+            (ino, FileType::Directory, OsString::from(".")),
+            (parent, FileType::Directory, OsString::from("..")),
+        ];
+
+        for entry in entries {
+            if self.name_filter.hides(&entry.name) {
+                continue;
+            }
+            // Entries the kernel has not looked up are reported by their
+            // backing inode number rather than allocating a mapping that
+            // would never be forgotten
+            let child_inode = self.inodes.known(entry.backing).unwrap_or(entry.backing.1);
+
+            all_entries.push((child_inode, fuse_file_type(entry.file_type), entry.name));
+        }
+
+        // The listing is fixed for the life of the handle so that paginated
+        // reads neither skip nor repeat entries when the directory changes
+        let fh = self.next_fh.fetch_add(1, Ordering::SeqCst);
+        self.open_dirs.lock().unwrap().insert(fh, all_entries);
+        reply.opened(fh, 0);
+    }
+
+    fn readdir(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        debug!("readdir: ino={}, fh={}, offset={}", ino, fh, offset);
+        log_caller("readdir", req);
+        stats::add(&self.counters.readdir, 1);
+
+        let open_dirs = self.open_dirs.lock().unwrap();
+        let all_entries = match open_dirs.get(&fh) {
+            Some(entries) => entries,
+            None => {
+                reply.error(EBADF);
+                return;
+            }
+        };
+
+        for (i, (inode, file_type, name)) in all_entries.iter().enumerate().skip(offset as usize) {
+            if reply.add(*inode, (i + 1) as i64, *file_type, name) {
+                break;
+            }
+        }
+
+        reply.ok();
+    }
+
+    fn readdirplus(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectoryPlus,
+    ) {
+        debug!("readdirplus: ino={}, fh={}, offset={}", ino, fh, offset);
+        log_caller("readdirplus", req);
+        stats::add(&self.counters.readdir, 1);
+
+        let open_dirs = self.open_dirs.lock().unwrap();
+        let all_entries = match open_dirs.get(&fh) {
+            Some(entries) => entries,
+            None => {
+                reply.error(EBADF);
+                return;
+            }
+        };
+
+        let path = match self.dir_path_of(ino) {
+            Ok(p) => p,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        let dir_attr = match fs::symlink_metadata(self.real_path(&path)) {
+            Ok(metadata) => self.metadata_to_attr(&metadata, ino),
+            Err(e) => {
+                reply.error(self.source_errno(&e));
+                return;
+            }
+        };
+
+        for (i, (dot_inode, _, name)) in all_entries.iter().enumerate().skip(offset as usize) {
+            let offset = (i + 1) as i64;
+
+            // The kernel does not take a lookup reference on . and .., nor
+            // look at their attributes
+            if name == "." || name == ".." {
+                // fuser fills in the listed inode number from the attributes
+                let attr = FileAttr {
+                    ino: *dot_inode,
+                    ..dir_attr
+                };
+                if reply.add(
+                    *dot_inode,
+                    offset,
+                    name,
+                    &self.entry_ttl,
+                    &attr,
+                    self.inodes.generation(attr.ino),
+                ) {
+                    break;
+                }
+                continue;
+            }
+
+            // Entries removed since opendir are left out
+            let relative_path = path.join(name);
+            let metadata = match fs::symlink_metadata(self.real_path(&relative_path)) {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+
+            // Every entry that fits in the reply counts as one lookup
+            let inode = self.inodes.get_or_create(
+                &relative_path,
+                (metadata.dev(), metadata.ino()),
+                birth(&metadata),
+                0,
+            );
+            let attr = self.metadata_to_attr(&metadata, inode);
+            if reply.add(
+                inode,
+                offset,
+                name,
+                &self.entry_ttl,
+                &attr,
+                self.inodes.generation(attr.ino),
+            ) {
+                // The entry did not fit, so an inode created for it would
+                // never be looked up, nor forgotten by the kernel
+                self.inodes.forget(inode, 0);
+                break;
+            }
+            self.inodes.add_lookups(inode, 1);
+            self.attr_cache.insert(&attr);
+        }
+
+        reply.ok();
+    }
+
+    fn releasedir(
+        &mut self,
+        req: &Request,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        debug!("releasedir: fh={}", fh);
+        log_caller("releasedir", req);
+        self.open_dirs.lock().unwrap().remove(&fh);
+        reply.ok();
+    }
+
+    fn open(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        debug!("open: ino={}, flags={}", ino, flags);
+        log_caller("open", req);
+        stats::add(&self.counters.open, 1);
+
+        let path = match self.path_of(ino) {
+            Ok(p) => p,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        let read = (flags & libc::O_ACCMODE) == libc::O_RDONLY
+            || (flags & libc::O_ACCMODE) == libc::O_RDWR;
+        let write = (flags & libc::O_ACCMODE) == libc::O_WRONLY
+            || (flags & libc::O_ACCMODE) == libc::O_RDWR;
+
+        // Checked on the file as it is, so callers refused a lower file
+        // leave no copy of it behind
+        let mask = if read { libc::R_OK } else { 0 } | if write { libc::W_OK } else { 0 };
+        if let Err(errno) = self.check_caller(req, &self.real_path(&path), mask) {
+            reply.error(errno);
+            return;
+        }
+
+        // Lower directories are never written, so writers get a copy
+        if write && !self.read_only {
+            if let Err(errno) = self.copy_up(&path) {
+                reply.error(errno);
+                return;
+            }
+        }
+        let real_path = self.real_path(&path);
+
+        // Reopens of the handle keep the flag too
+        let flags = if self.noatime {
+            flags | libc::O_NOATIME
+        } else {
+            flags
+        };
+        let widened = self.writeback_cache && write;
+        let flags = if widened {
+            writeback_flags(flags)
+        } else {
+            flags
+        };
+
+        // O_TRUNC is passed as a raw flag since std rejects it alongside
+        // O_APPEND, and a read-only open never truncates
+        let truncate = if write { flags & libc::O_TRUNC } else { 0 };
+        let custom_flags = truncate | (flags & (libc::O_NOFOLLOW | libc::O_NOATIME | O_DIRECT));
+        let open = |read| {
+            let mut options = OpenOptions::new();
+            options
+                .read(read)
+                .write(write)
+                .append((flags & libc::O_APPEND) != 0);
+            open_custom(&options, &real_path, custom_flags)
+        };
+
+        // A file the daemon may write but not read still opens for writing
+        // when the writeback cache widened the open. Only the kernel's reads
+        // for partial page writes fail then, with EBADF.
+        let (flags, result) = match open(read || widened) {
+            Err(e) if widened && !read && e.raw_os_error() == Some(EACCES) => {
+                ((flags & !libc::O_ACCMODE) | libc::O_WRONLY, open(false))
+            }
+            result => (flags, result),
+        };
+
+        match result {
+            Ok(file) => {
+                let fh = self.add_handle(ino, flags, file);
+                if truncate != 0 {
+                    self.checksum_changed(ino);
+                }
+                reply.opened(fh, self.open_reply_flags(flags));
+            }
+            Err(e) => {
+                error!("open error: {:?}", e);
+                reply.error(self.source_errno(&e));
+            }
+        }
+    }
+
+    fn release(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        debug!("release: fh={}", fh);
+        log_caller("release", req);
+        self.remove_handle(ino, fh);
+        reply.ok();
+    }
+
+    fn create(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: fuser::ReplyCreate,
+    ) {
+        debug!("create: parent={}, name={:?}, mode={}", parent, name, mode);
+        log_caller("create", req);
+        stats::add(&self.counters.create, 1);
+
+        if let Err(errno) = self.check_entry_name(name) {
+            reply.error(errno);
+            return;
+        }
+
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+
+        let parent_path = match self.dir_path_of(parent) {
+            Ok(p) => p,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        let relative_path = parent_path.join(self.resolve_case(&parent_path, name));
+
+        if let Err(errno) =
+            self.check_caller(req, &self.real_path(&parent_path), libc::W_OK | libc::X_OK)
+        {
+            reply.error(errno);
+            return;
+        }
+
+        // Opening an existing file only found below needs a copy of it
+        let exists_below = self.overlay.is_some()
+            && flags & libc::O_EXCL == 0
+            && fs::symlink_metadata(self.real_path(&relative_path)).is_ok();
+        let prepared = if exists_below {
+            self.copy_up(&relative_path)
+        } else {
+            self.prepare_entry(&relative_path)
+        };
+        if let Err(errno) = prepared {
+            reply.error(errno);
+            return;
+        }
+        let real_path = self.real_path(&relative_path);
+
+        let read = (flags & libc::O_ACCMODE) == libc::O_RDONLY
+            || (flags & libc::O_ACCMODE) == libc::O_RDWR;
+        let write = (flags & libc::O_ACCMODE) == libc::O_WRONLY
+            || (flags & libc::O_ACCMODE) == libc::O_RDWR;
+        let (flags, read) = if self.writeback_cache && write {
+            (writeback_flags(flags), true)
+        } else {
+            (flags, read)
+        };
+
+        let mode = creation_mode(mode, umask);
+        let mut options = OpenOptions::new();
+        options
+            .read(read)
+            .write(write)
+            .truncate((flags & libc::O_TRUNC) != 0)
+            .custom_flags(flags & O_DIRECT)
+            .mode(mode);
+
+        // Only a file this call created gets its mode set; O_CREAT without
+        // O_EXCL on a file that appeared meanwhile just opens it
+        self.attr_cache.invalidate(parent);
+        let result = match options.clone().create_new(true).open(&real_path) {
+            Ok(file) => fix_mode(&file, mode).map(|_| file),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists && flags & libc::O_EXCL == 0 => {
+                options.open(&real_path)
+            }
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(file) => match file.metadata() {
+                Ok(metadata) => {
+                    self.finish_entry(&relative_path, false);
+                    let inode = self.inodes.get_or_create(
+                        &relative_path,
+                        (metadata.dev(), metadata.ino()),
+                        birth(&metadata),
+                        1,
+                    );
+                    let fh = self.add_handle(inode, flags, file);
+                    self.checksum_changed(inode);
+
+                    let attr = self.metadata_to_attr(&metadata, inode);
+                    reply.created(
+                        &self.entry_ttl,
+                        &attr,
+                        self.inodes.generation(attr.ino),
+                        fh,
+                        self.open_reply_flags(flags),
+                    );
+                }
+                Err(e) => {
+                    reply.error(self.source_errno(&e));
+                }
+            },
+            Err(e) => {
+                error!("create error: {:?}", e);
+                reply.error(self.source_errno(&e));
+            }
+        }
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        debug!("mkdir: parent={}, name={:?}, mode={}", parent, name, mode);
+        log_caller("mkdir", req);
+        stats::add(&self.counters.mkdir, 1);
+
+        if let Err(errno) = self.check_entry_name(name) {
+            reply.error(errno);
+            return;
+        }
+
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+
+        let parent_path = match self.dir_path_of(parent) {
+            Ok(p) => p,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        let relative_path = parent_path.join(self.resolve_case(&parent_path, name));
+
+        if let Err(errno) =
+            self.check_caller(req, &self.real_path(&parent_path), libc::W_OK | libc::X_OK)
+        {
+            reply.error(errno);
+            return;
+        }
+
+        if let Err(errno) = self.prepare_entry(&relative_path) {
+            reply.error(errno);
+            return;
+        }
+        let real_path = self.real_path(&relative_path);
+
+        let mode = creation_mode(mode, umask);
+        self.attr_cache.invalidate(parent);
+        let result = fs::DirBuilder::new()
+            .mode(mode)
+            .create(&real_path)
+            .and_then(|_| File::open(&real_path))
+            .and_then(|dir| fix_mode(&dir, mode));
+
+        match result {
+            Ok(_) => match fs::symlink_metadata(&real_path) {
+                Ok(metadata) => {
+                    self.finish_entry(&relative_path, true);
+                    let inode = self.inodes.get_or_create(
+                        &relative_path,
+                        (metadata.dev(), metadata.ino()),
+                        birth(&metadata),
+                        1,
+                    );
+                    let attr = self.metadata_to_attr(&metadata, inode);
+                    reply.entry(&self.entry_ttl, &attr, self.inodes.generation(attr.ino));
+                }
+                Err(e) => {
+                    reply.error(self.source_errno(&e));
+                }
+            },
+            Err(e) => {
+                error!("mkdir error: {:?}", e);
+                reply.error(self.source_errno(&e));
+            }
+        }
+    }
+
+    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
+        debug!("unlink: parent={}, name={:?}", parent, name);
+        log_caller("unlink", req);
+        stats::add(&self.counters.unlink, 1);
+
+        if let Err(errno) = self.check_entry_name(name) {
+            reply.error(errno);
+            return;
+        }
+
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+
+        let parent_path = match self.dir_path_of(parent) {
+            Ok(p) => p,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        let relative_path = parent_path.join(self.resolve_case(&parent_path, name));
+        let real_path = self.real_path(&relative_path);
+
+        if let Err(errno) =
+            self.check_caller(req, &self.real_path(&parent_path), libc::W_OK | libc::X_OK)
+        {
+            reply.error(errno);
+            return;
+        }
+
+        // A name only found below is hidden, leaving the lower file alone
+        if self
+            .overlay
+            .as_ref()
+            .is_some_and(|overlay| !overlay.in_upper(&relative_path))
+        {
+            if let Err(e) = fs::symlink_metadata(&real_path) {
+                reply.error(self.source_errno(&e));
+                return;
+            }
+            self.invalidate_entry(parent, &relative_path);
+            match self.whiteout(&relative_path) {
+                Ok(()) => {
+                    self.inodes.remove(&relative_path, false);
+                    reply.ok();
+                }
+                Err(errno) => reply.error(errno),
+            }
+            return;
+        }
+
+        // Other hardlinks keep the inode alive after this name is gone
+        let has_other_links = fs::symlink_metadata(&real_path)
+            .map(|metadata| metadata.nlink() > 1)
+            .unwrap_or(false);
+
+        self.invalidate_entry(parent, &relative_path);
+
+        // The last name of a file that is still open is only renamed aside,
+        // so operations on the inode keep working; release deletes it
+        let open_inode = self.inodes.get_inode(&relative_path).filter(|inode| {
+            !has_other_links && self.open_counts.lock().unwrap().contains_key(inode)
+        });
+        if let Some(inode) = open_inode {
+            let hidden = parent_path.join(format!(".fuse_hidden{:016x}", inode));
+            match fs::rename(&real_path, self.real_path(&hidden)) {
+                Ok(_) => {
+                    self.inodes.rename_subtree(&relative_path, &hidden);
+                    self.hidden_files.lock().unwrap().insert(inode, hidden);
+                    match self.whiteout(&relative_path) {
+                        Ok(()) => reply.ok(),
+                        Err(errno) => reply.error(errno),
+                    }
+                }
+                Err(e) => {
+                    error!("unlink error: {:?}", e);
+                    reply.error(self.source_errno(&e));
+                }
+            }
+            return;
+        }
+
+        match fs::remove_file(&real_path) {
+            Ok(_) => {
+                // Clean up inode mapping
+                self.inodes.remove(&relative_path, has_other_links);
+                match self.whiteout(&relative_path) {
+                    Ok(()) => reply.ok(),
+                    Err(errno) => reply.error(errno),
+                }
+            }
+            Err(e) => {
+                error!("unlink error: {:?}", e);
+                reply.error(self.source_errno(&e));
+            }
+        }
+    }
+
+    fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
+        debug!("rmdir: parent={}, name={:?}", parent, name);
+        log_caller("rmdir", req);
+        stats::add(&self.counters.rmdir, 1);
+
+        if let Err(errno) = self.check_entry_name(name) {
+            reply.error(errno);
+            return;
+        }
+
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+
+        let parent_path = match self.dir_path_of(parent) {
+            Ok(p) => p,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        let relative_path = parent_path.join(self.resolve_case(&parent_path, name));
+        let real_path = self.real_path(&relative_path);
+
+        if let Err(errno) =
+            self.check_caller(req, &self.real_path(&parent_path), libc::W_OK | libc::X_OK)
+        {
+            reply.error(errno);
+            return;
+        }
+
+        self.invalidate_entry(parent, &relative_path);
+        let removed = match &self.overlay {
+            Some(overlay) => overlay.remove_dir(&relative_path),
+            None => fs::remove_dir(&real_path),
+        };
+        match removed {
+            Ok(_) => {
+                // Clean up inode mapping
+                self.inodes.remove(&relative_path, false);
+                reply.ok();
+            }
+            Err(e) => {
+                error!("rmdir error: {:?}", e);
+                reply.error(self.source_errno(&e));
+            }
+        }
+    }
+
+    fn rename(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        debug!(
+            "rename: parent={}, name={:?}, newparent={}, newname={:?}, flags={}",
+            parent, name, newparent, newname, flags
+        );
+        log_caller("rename", req);
+        stats::add(&self.counters.rename, 1);
+
+        if let Err(errno) = self
+            .check_entry_name(name)
+            .and(self.check_entry_name(newname))
+        {
+            reply.error(errno);
+            return;
+        }
+
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+
+        let parent_path = match self.dir_path_of(parent) {
+            Ok(p) => p,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        let newparent_path = match self.dir_path_of(newparent) {
+            Ok(p) => p,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        let old_relative = parent_path.join(self.resolve_case(&parent_path, name));
+        let new_relative = newparent_path.join(self.resolve_case(&newparent_path, newname));
+        let old_real = self.real_path(&old_relative);
+
+        for dir in [&parent_path, &newparent_path] {
+            if let Err(errno) =
+                self.check_caller(req, &self.real_path(dir), libc::W_OK | libc::X_OK)
+            {
+                reply.error(errno);
+                return;
+            }
+        }
+
+        // Renaming a name onto itself succeeds without changing anything
+        if old_relative == new_relative {
+            match fs::symlink_metadata(&old_real) {
+                Ok(_) => reply.ok(),
+                Err(e) => reply.error(self.source_errno(&e)),
+            }
+            return;
+        }
+
+        let (old_real, new_real) = match self.prepare_rename(&old_relative, &new_relative, flags) {
+            Ok(paths) => paths,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        // Neither does renaming onto another link to the same file, which
+        // keeps both names, so the mappings must stay as they are
+        let same_file = match (
+            fs::symlink_metadata(&old_real),
+            fs::symlink_metadata(&new_real),
+        ) {
+            (Ok(old), Ok(new)) => (old.dev(), old.ino()) == (new.dev(), new.ino()),
+            _ => false,
+        };
+
+        self.invalidate_entry(parent, &old_relative);
+        self.invalidate_entry(newparent, &new_relative);
+        let result = if flags == 0 {
+            fs::rename(&old_real, &new_real)
+        } else {
+            rename_with_flags(&old_real, &new_real, flags)
+        };
+
+        match result {
+            Ok(_) if same_file => reply.ok(),
+            Ok(_) => {
+                #[cfg(target_os = "linux")]
+                if flags & libc::RENAME_EXCHANGE != 0 {
+                    self.inodes.exchange_subtrees(&old_relative, &new_relative);
+                    reply.ok();
+                    return;
+                }
+
+                self.inodes.rename_subtree(&old_relative, &new_relative);
+                match self.finish_rename(&old_relative, &new_relative) {
+                    Ok(()) => reply.ok(),
+                    Err(errno) => reply.error(errno),
+                }
+            }
+            Err(e) => {
+                error!("rename error: {:?}", e);
+                reply.error(self.source_errno(&e));
+            }
+        }
+    }
+
+    fn statfs(&mut self, req: &Request, _ino: u64, reply: fuser::ReplyStatfs) {
+        debug!("statfs");
+        log_caller("statfs", req);
+
+        // Every inode lives on the source filesystem, so report its stats
+        let path_cstr = match path_to_cstring(&self.source) {
+            Ok(p) => p,
+            Err(e) => {
+                reply.error(self.source_errno(&e));
+                return;
+            }
+        };
+
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(path_cstr.as_ptr(), &mut stat) } != 0 {
+            let e = io::Error::last_os_error();
+            error!("statfs error: {:?}", e);
+            reply.error(self.source_errno(&e));
+            return;
+        }
+
+        reply.statfs(
+            stat.f_blocks as u64,
+            stat.f_bfree as u64,
+            stat.f_bavail as u64,
+            stat.f_files as u64,
+            stat.f_ffree as u64,
+            stat.f_bsize as u32,
+            stat.f_namemax as u32,
+            stat.f_frsize as u32,
+        );
+    }
+
+    /// macOS only: Exchange two files atomically
+    #[cfg(target_os = "macos")]
+    fn exchange(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        _options: u64,
+        reply: fuser::ReplyEmpty,
+    ) {
+        debug!(
+            "exchange: parent={}, name={:?}, newparent={}, newname={:?}",
+            parent, name, newparent, newname
+        );
+        log_caller("exchange", req);
+
+        if let Err(errno) = self
+            .check_entry_name(name)
+            .and(self.check_entry_name(newname))
+        {
+            reply.error(errno);
+            return;
+        }
+
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+
+        // For non-atomic exchange, just do a rename
+        let parent_path = match self.dir_path_of(parent) {
+            Ok(p) => p,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        let newparent_path = match self.dir_path_of(newparent) {
+            Ok(p) => p,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        let old_relative = parent_path.join(self.resolve_case(&parent_path, name));
+        let new_relative = newparent_path.join(self.resolve_case(&newparent_path, newname));
+
+        for dir in [&parent_path, &newparent_path] {
+            if let Err(errno) =
+                self.check_caller(req, &self.real_path(dir), libc::W_OK | libc::X_OK)
+            {
+                reply.error(errno);
+                return;
+            }
+        }
+
+        let (old_real, new_real) = match self.prepare_rename(&old_relative, &new_relative, 0) {
+            Ok(paths) => paths,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        self.invalidate_entry(parent, &old_relative);
+        self.invalidate_entry(newparent, &new_relative);
+        match fs::rename(&old_real, &new_real) {
+            Ok(_) => {
+                self.inodes.rename_subtree(&old_relative, &new_relative);
+                match self.finish_rename(&old_relative, &new_relative) {
+                    Ok(()) => reply.ok(),
+                    Err(errno) => reply.error(errno),
+                }
+            }
+            Err(e) => {
+                error!("exchange error: {:?}", e);
+                reply.error(self.source_errno(&e));
+            }
+        }
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        position: u32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        debug!(
+            "setxattr: ino={}, name={:?}, flags={}, position={}",
+            ino, name, flags, position
+        );
+        log_caller("setxattr", req);
+
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+
+        if self.checksum_reserved(name) {
+            reply.error(EPERM);
+            return;
+        }
+
+        let path = match self.path_of(ino) {
+            Ok(p) => p,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        if let Err(errno) = self.copy_up(&path) {
+            reply.error(errno);
+            return;
+        }
+        let real_path = self.real_path(&path);
+
+        self.attr_cache.invalidate(ino);
+        match xattr::set(&real_path, name, value, flags, position) {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(self.source_errno(&e)),
+        }
+    }
+
+    fn getxattr(&mut self, req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        debug!("getxattr: ino={}, name={:?}, size={}", ino, name, size);
+        log_caller("getxattr", req);
+
+        let path = match self.path_of(ino) {
+            Ok(p) => p,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        let real_path = self.real_path(&path);
+
+        // A zero size asks for the length of the value; the syscall reports
+        // ERANGE by itself when a non-zero buffer is too small
+        let mut buffer = vec![0u8; size as usize];
+        match xattr::get(&real_path, name, &mut buffer) {
+            Ok(len) if size == 0 => reply.size(len as u32),
+            Ok(len) => reply.data(&buffer[..len]),
+            Err(e) => reply.error(self.source_errno(&e)),
+        }
+    }
+
+    fn listxattr(&mut self, req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        debug!("listxattr: ino={}, size={}", ino, size);
+        log_caller("listxattr", req);
+
+        let path = match self.path_of(ino) {
+            Ok(p) => p,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        let real_path = self.real_path(&path);
+
+        let mut buffer = vec![0u8; size as usize];
+        match xattr::list(&real_path, &mut buffer) {
+            Ok(len) if size == 0 => reply.size(len as u32),
+            Ok(len) => reply.data(&buffer[..len]),
+            Err(e) => reply.error(self.source_errno(&e)),
+        }
+    }
+
+    fn removexattr(&mut self, req: &Request, ino: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
+        debug!("removexattr: ino={}, name={:?}", ino, name);
+        log_caller("removexattr", req);
+
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+
+        if self.checksum_reserved(name) {
+            reply.error(EPERM);
+            return;
+        }
+
+        let path = match self.path_of(ino) {
+            Ok(p) => p,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        if let Err(errno) = self.copy_up(&path) {
+            reply.error(errno);
+            return;
+        }
+        let real_path = self.real_path(&path);
+
+        self.attr_cache.invalidate(ino);
+        match xattr::remove(&real_path, name) {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(self.source_errno(&e)),
+        }
+    }
+
+    fn access(&mut self, req: &Request, ino: u64, mask: i32, reply: fuser::ReplyEmpty) {
+        debug!("access: ino={}, mask={}, uid={}", ino, mask, req.uid());
+        log_caller("access", req);
+
+        let path = match self.path_of(ino) {
+            Ok(p) => p,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        let real_path = self.real_path(&path);
+
+        if self.read_only && mask & libc::W_OK != 0 {
+            reply.error(EROFS);
+            return;
+        }
+
+        // faccessat can only check as the daemon, so other callers are
+        // checked against the mode bits with their own credentials
+        let result = if req.uid() == unsafe { libc::geteuid() } {
+            faccessat(&real_path, mask)
+        } else {
+            fs::metadata(&real_path).and_then(|metadata| {
+                if self.caller_permits(req, &metadata, mask) {
+                    Ok(())
+                } else {
+                    Err(io::Error::from_raw_os_error(EACCES))
+                }
+            })
+        };
+
+        match result {
+            Ok(()) => reply.ok(),
+            Err(e) => {
+                debug!("access denied: {:?}", e);
+                reply.error(self.source_errno(&e));
+            }
+        }
+    }
+
+    fn readlink(&mut self, req: &Request, ino: u64, reply: ReplyData) {
+        debug!("readlink: ino={}", ino);
+        log_caller("readlink", req);
+
+        let path = match self.path_of(ino) {
+            Ok(p) => p,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        let real_path = self.real_path(&path);
+
+        match fs::read_link(&real_path) {
+            Ok(target) if !self.target_confined(&real_path, &target) => {
+                warn!(
+                    "Refusing to follow {} to {} outside the source",
+                    real_path.display(),
+                    target.display()
+                );
+                reply.error(EACCES);
+            }
+            Ok(target) => {
+                reply.data(target.as_os_str().as_bytes());
+            }
+            Err(e) => {
+                reply.error(self.source_errno(&e));
+            }
+        }
+    }
+
+    fn symlink(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        link_name: &OsStr,
+        target: &Path,
+        reply: ReplyEntry,
+    ) {
+        debug!(
+            "symlink: parent={}, name={:?}, target={:?}",
+            parent, link_name, target
+        );
+        log_caller("symlink", req);
+
+        if let Err(errno) = self.check_entry_name(link_name) {
+            reply.error(errno);
+            return;
+        }
+
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+
+        let parent_path = match self.dir_path_of(parent) {
+            Ok(p) => p,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        let relative_path = parent_path.join(self.resolve_case(&parent_path, link_name));
+
+        if let Err(errno) =
+            self.check_caller(req, &self.real_path(&parent_path), libc::W_OK | libc::X_OK)
+        {
+            reply.error(errno);
+            return;
+        }
+
+        if let Err(errno) = self.prepare_entry(&relative_path) {
+            reply.error(errno);
+            return;
+        }
+        let real_path = self.real_path(&relative_path);
+
+        self.attr_cache.invalidate(parent);
+        match std::os::unix::fs::symlink(target, &real_path) {
+            Ok(_) => match fs::symlink_metadata(&real_path) {
+                Ok(metadata) => {
+                    self.finish_entry(&relative_path, false);
+                    let inode = self.inodes.get_or_create(
+                        &relative_path,
+                        (metadata.dev(), metadata.ino()),
+                        birth(&metadata),
+                        1,
+                    );
+                    let attr = self.metadata_to_attr(&metadata, inode);
+                    reply.entry(&self.entry_ttl, &attr, self.inodes.generation(attr.ino));
+                }
+                Err(e) => {
+                    reply.error(self.source_errno(&e));
+                }
+            },
+            Err(e) => {
+                error!("symlink error: {:?}", e);
+                reply.error(self.source_errno(&e));
+            }
+        }
+    }
+
+    fn link(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        debug!(
+            "link: ino={}, newparent={}, newname={:?}",
+            ino, newparent, newname
+        );
+        log_caller("link", req);
+
+        if let Err(errno) = self.check_entry_name(newname) {
+            reply.error(errno);
+            return;
+        }
+
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+
+        let path = match self.path_of(ino) {
+            Ok(p) => p,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        let newparent_path = match self.dir_path_of(newparent) {
+            Ok(p) => p,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        let new_relative = newparent_path.join(self.resolve_case(&newparent_path, newname));
+        if let Err(errno) = self.copy_up(&path).and(self.prepare_entry(&new_relative)) {
+            reply.error(errno);
+            return;
+        }
+        let old_real = self.real_path(&path);
+        let new_real = self.real_path(&new_relative);
+
+        if let Err(errno) = self.check_caller(
+            req,
+            &self.real_path(&newparent_path),
+            libc::W_OK | libc::X_OK,
+        ) {
+            reply.error(errno);
+            return;
+        }
+
+        self.attr_cache.invalidate(ino);
+        self.attr_cache.invalidate(newparent);
+        match fs::hard_link(&old_real, &new_real) {
+            Ok(_) => {
+                // Both names share the backing file, and therefore one inode
+                match fs::symlink_metadata(&new_real) {
+                    Ok(metadata) => {
+                        self.finish_entry(&new_relative, false);
+                        let inode = self.inodes.get_or_create(
+                            &new_relative,
+                            (metadata.dev(), metadata.ino()),
+                            birth(&metadata),
+                            1,
+                        );
+                        let attr = self.metadata_to_attr(&metadata, inode);
+                        reply.entry(&self.entry_ttl, &attr, self.inodes.generation(attr.ino));
+                    }
+                    Err(e) => {
+                        reply.error(self.source_errno(&e));
+                    }
+                }
+            }
+            Err(e) => {
+                error!("link error: {:?}", e);
+                reply.error(self.source_errno(&e));
+            }
+        }
+    }
+
+    fn flush(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        reply: fuser::ReplyEmpty,
+    ) {
+        debug!("flush: fh={}", fh);
+        log_caller("flush", req);
+
+        // Closing any descriptor drops the owner's locks on the file, as POSIX requires
+        self.lock_files.lock().unwrap().remove(&(ino, lock_owner));
+
+        let file = match self.open_file(fh) {
+            Some(file) => file,
+            None => {
+                reply.error(EBADF);
+                return;
+            }
+        };
+
+        // Like close(2), closing a duplicate reports deferred write errors
+        // from the source filesystem without forcing the data to disk
+        let ret = unsafe {
+            let fd = libc::dup(file.as_raw_fd());
+            if fd < 0 {
+                fd
+            } else {
+                libc::close(fd)
+            }
+        };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            error!("flush error: {:?}", e);
+            reply.error(self.source_errno(&e));
+        } else {
+            reply.ok();
+        }
+    }
+
+    fn fsync(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        datasync: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        debug!("fsync: fh={}, datasync={}", fh, datasync);
+        log_caller("fsync", req);
+
+        let file = match self.open_file(fh) {
+            Some(file) => file,
+            None => {
+                reply.error(EBADF);
+                return;
+            }
+        };
+
+        // fdatasync only needs the data and the metadata required to read it
+        let result = retry_eintr(|| {
+            if datasync {
+                file.sync_data()
+            } else {
+                file.sync_all()
+            }
+        });
+        // Filesystems that allocate on writeback only settle st_blocks now
+        self.attr_cache.invalidate(ino);
+        match result {
+            Ok(()) => reply.ok(),
+            Err(e) => {
+                error!("fsync error: {:?}", e);
+                reply.error(self.source_errno(&e));
+            }
+        }
+    }
+
+    /// Sync a directory, so renames and creations inside it survive a crash
+    fn fsyncdir(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        datasync: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        debug!("fsyncdir: ino={}, fh={}, datasync={}", ino, fh, datasync);
+        log_caller("fsyncdir", req);
+
+        let path = match self.dir_path_of(ino) {
+            Ok(p) => p,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        // opendir only snapshots the listing, so open a descriptor to sync
+        let result = File::open(self.real_path(&path)).and_then(|dir| {
+            retry_eintr(|| {
+                if datasync {
+                    dir.sync_data()
+                } else {
+                    dir.sync_all()
+                }
+            })
+        });
+        match result {
+            Ok(()) => reply.ok(),
+            Err(e) => {
+                error!("fsyncdir error: {:?}", e);
+                reply.error(self.source_errno(&e));
+            }
+        }
+    }
+
+    fn fallocate(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        debug!(
+            "fallocate: fh={}, offset={}, length={}, mode={}",
+            fh, offset, length, mode
+        );
+        log_caller("fallocate", req);
+
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+
+        let file = match self.open_file(fh) {
+            Some(file) => file,
+            None => {
+                reply.error(EBADF);
+                return;
+            }
+        };
+
+        self.attr_cache.invalidate(ino);
+        match fallocate(&file, offset, length, mode) {
+            Ok(()) => {
+                self.checksum_changed(ino);
+                reply.ok();
+            }
+            Err(e) => {
+                error!("fallocate error: {:?}", e);
+                reply.error(self.source_errno(&e));
+            }
+        }
+    }
+
+    /// Map a logical block of a file to a physical block. The kernel only
+    /// asks for this on block-device backed (fuseblk) mounts.
+    #[cfg(target_os = "linux")]
+    fn bmap(&mut self, req: &Request, ino: u64, blocksize: u32, idx: u64, reply: fuser::ReplyBmap) {
+        debug!("bmap: ino={}, blocksize={}, idx={}", ino, blocksize, idx);
+        log_caller("bmap", req);
+
+        let path = match self.path_of(ino) {
+            Ok(p) => p,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        match File::open(self.real_path(&path)).and_then(|file| ioctl::bmap(&file, blocksize, idx))
+        {
+            Ok(block) => reply.bmap(block),
+            Err(e) => {
+                debug!("bmap error: {:?}", e);
+                // Filesystems without block mapping reject FIBMAP with ENOTTY
+                let errno = match e.raw_os_error() {
+                    Some(libc::ENOTTY) => ENOSYS,
+                    _ => self.source_errno(&e),
+                };
+                reply.error(errno);
+            }
+        }
+    }
+
+    /// Forward inode flag ioctls (chattr/lsattr) to the backing file
+    #[cfg(target_os = "linux")]
+    fn ioctl(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+        out_size: u32,
+        reply: fuser::ReplyIoctl,
+    ) {
+        debug!(
+            "ioctl: ino={}, fh={}, flags={}, cmd={:#x}, in={}, out={}",
+            ino,
+            fh,
+            flags,
+            cmd,
+            in_data.len(),
+            out_size
+        );
+        log_caller("ioctl", req);
+
+        if self.read_only && ioctl::is_write(cmd) {
+            reply.error(EROFS);
+            return;
+        }
+
+        // A handle on a file still only found below would change the lower
+        // copy, so the file is copied up and opened by path instead
+        let below = ioctl::is_write(cmd)
+            && self.overlay.as_ref().is_some_and(|overlay| {
+                self.path_of(ino).is_ok_and(|path| !overlay.in_upper(&path))
+            });
+        if below {
+            if let Err(errno) = self.path_of(ino).and_then(|path| self.copy_up(&path)) {
+                reply.error(errno);
+                return;
+            }
+        }
+
+        // Directory handles only hold a listing, so those open the path
+        let file = match self.open_file(fh).filter(|_| !below) {
+            Some(file) => Ok(file),
+            None => match self.path_of(ino) {
+                Ok(path) => File::open(self.real_path(&path)).map(Arc::new),
+                Err(errno) => {
+                    reply.error(errno);
+                    return;
+                }
+            },
+        };
+
+        match file.and_then(|file| ioctl::forward(&file, cmd, in_data)) {
+            Ok(data) if data.len() > out_size as usize => reply.error(EINVAL),
+            Ok(data) => {
+                self.attr_cache.invalidate(ino);
+                reply.ioctl(0, &data);
+            }
+            Err(e) => {
+                debug!("ioctl error: {:?}", e);
+                reply.error(self.source_errno(&e));
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn getlk(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        reply: fuser::ReplyLock,
+    ) {
+        debug!(
+            "getlk: fh={}, owner={}, start={}, end={}, typ={}",
+            fh, lock_owner, start, end, typ
+        );
+        log_caller("getlk", req);
+
+        // Test from the owner's description when it exists so its own locks
+        // do not count as conflicts; the handle itself never holds locks
+        let owned = self
+            .lock_files
+            .lock()
+            .unwrap()
+            .get(&(ino, lock_owner))
+            .map(|file| file.try_clone());
+        let file = match owned {
+            Some(file) => file,
+            None => match self.open_file(fh) {
+                Some(file) => file.try_clone(),
+                None => {
+                    reply.error(EBADF);
+                    return;
+                }
+            },
+        };
+
+        let request = lock::Lock {
+            start,
+            end,
+            typ,
+            pid,
+        };
+        match file.and_then(|file| lock::get(&file, request)) {
+            Ok(lock) => reply.locked(lock.start, lock.end, lock.typ, lock.pid),
+            Err(e) => {
+                error!("getlk error: {:?}", e);
+                reply.error(self.source_errno(&e));
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn setlk(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        sleep: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        debug!(
+            "setlk: fh={}, owner={}, start={}, end={}, typ={}, sleep={}",
+            fh, lock_owner, start, end, typ, sleep
+        );
+        log_caller("setlk", req);
+
+        // An owner without a description holds no locks to release
+        if typ == libc::F_UNLCK
+            && !self
+                .lock_files
+                .lock()
+                .unwrap()
+                .contains_key(&(ino, lock_owner))
+        {
+            reply.ok();
+            return;
+        }
+
+        let file = match self.lock_file(ino, fh, lock_owner) {
+            Ok(file) => file,
+            Err(e) => {
+                error!("setlk error: {:?}", e);
+                reply.error(self.source_errno(&e));
+                return;
+            }
+        };
+
+        let lock = lock::Lock {
+            start,
+            end,
+            typ,
+            pid,
+        };
+
+        if sleep {
+            // Waiting here would stall the session loop, and with it the
+            // unlock request that would let this lock through. fuser does not
+            // pass on interrupts, so a waiter whose caller was interrupted
+            // keeps waiting, and may still take the lock, until it is granted.
+            if self.lock_waiters.fetch_add(1, Ordering::SeqCst) >= MAX_LOCK_WAITERS {
+                self.lock_waiters.fetch_sub(1, Ordering::SeqCst);
+                reply.error(libc::ENOLCK);
+                return;
+            }
+            let waiters = Arc::clone(&self.lock_waiters);
+            let spawned = std::thread::Builder::new()
+                .name("fuse-setlkw".to_string())
+                .spawn(move || {
+                    match lock::set(&file, lock, true) {
+                        Ok(()) => reply.ok(),
+                        Err(e) => reply.error(io_error_to_errno(&e)),
+                    }
+                    waiters.fetch_sub(1, Ordering::SeqCst);
+                });
+            if let Err(e) = spawned {
+                // The reply goes with the thread and answers EIO when dropped
+                error!("setlk: cannot wait for the lock: {}", e);
+                self.lock_waiters.fetch_sub(1, Ordering::SeqCst);
+            }
+            return;
+        }
+
+        match lock::set(&file, lock, false) {
+            Ok(()) => reply.ok(),
+            Err(e) => {
+                debug!("setlk error: {:?}", e);
+                reply.error(self.source_errno(&e));
+            }
+        }
+    }
+
+    /// Readiness of the backing descriptor, so sources whose files signal
+    /// readiness, such as sysfs attributes, wake pollers through the mount.
+    /// FIFOs and devices never get here: the kernel serves those itself.
+    fn poll(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        ph: PollHandle,
+        events: u32,
+        flags: u32,
+        reply: ReplyPoll,
+    ) {
+        debug!(
+            "poll: ino={}, fh={}, events={:#x}, flags={:#x}",
+            ino, fh, events, flags
+        );
+        log_caller("poll", req);
+
+        let Some(file) = self.open_file(fh) else {
+            reply.error(EBADF);
+            return;
+        };
+        let revents = match poll_file(&file, events, 0) {
+            Ok(revents) => revents,
+            Err(e) => {
+                reply.error(self.source_errno(&e));
+                return;
+            }
+        };
+
+        // One waiting thread per kernel handle is enough, whatever the
+        // number of pollers
+        let notify = revents == 0 && flags & fuser::consts::FUSE_POLL_SCHEDULE_NOTIFY != 0;
+        if notify
+            && self
+                .poll_waits
+                .lock()
+                .unwrap()
+                .insert(u64::from(ph.clone()))
+        {
+            let file = Arc::downgrade(&file);
+            let waits = Arc::clone(&self.poll_waits);
+            std::thread::spawn(move || notify_when_ready(file, events, ph, waits));
+        }
+        reply.poll(revents);
+    }
+
+    fn lseek(
+        &mut self,
+        req: &Request,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: ReplyLseek,
+    ) {
+        debug!("lseek: fh={}, offset={}, whence={}", fh, offset, whence);
+        log_caller("lseek", req);
+
+        let file = match self.open_file(fh) {
+            Some(file) => file,
+            None => {
+                reply.error(EBADF);
+                return;
+            }
+        };
+
+        // Reads and writes are positioned, so moving the shared offset is
+        // harmless; SEEK_DATA/SEEK_HOLE report ENXIO past the last data
+        let ret = unsafe { libc::lseek(file.as_raw_fd(), offset, whence) };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            debug!("lseek error: {:?}", e);
+            reply.error(self.source_errno(&e));
+        } else {
+            reply.offset(ret);
+        }
+    }
+}
+
+/// Mount `source` at `mountpoint` in a background thread
+///
+/// The filesystem stays mounted until the returned [`Mount`] is unmounted
+/// or dropped.
+pub fn mount(source: &Path, mountpoint: &Path, options: &[MountOption]) -> io::Result<Mount> {
+    mount_fs(
+        PassthroughFS::new(source.to_path_buf()),
+        mountpoint,
+        options,
+    )
+}
+
+/// Mount with the options and cache timeouts described by `config`
+pub fn mount_with_config(config: PassthroughConfig, mountpoint: &Path) -> io::Result<Mount> {
+    let options = config.mount_options();
+    mount_fs(PassthroughFS::with_config(config)?, mountpoint, &options)
+}
+
+/// Mount an already constructed filesystem, for instance one whose
+/// [`PassthroughFS::stats_handle`] was taken first
+pub fn mount_fs(
+    fs: PassthroughFS,
+    mountpoint: &Path,
+    options: &[MountOption],
+) -> io::Result<Mount> {
+    fs.spawn_handle_reaper();
+    let watch = fs.own_writes.clone().map(|own_writes| {
+        (
+            fs.source.clone(),
+            Arc::clone(&fs.inodes),
+            Arc::clone(&fs.attr_cache),
+            own_writes,
+        )
+    });
+
+    let session = fuser::spawn_mount2(fs, mountpoint, options)?;
+    if let Some((source, inodes, attr_cache, own_writes)) = watch {
+        watch_source(&source, &inodes, &attr_cache, own_writes, &session);
+    }
+    Ok(Mount::new(session, mountpoint))
+}
+
+/// Start passing changes made directly in the source on to the kernel
+#[cfg(target_os = "linux")]
+fn watch_source(
+    source: &Path,
+    inodes: &Arc<InodeTable>,
+    attr_cache: &Arc<AttrCache>,
+    own_writes: Arc<OwnWrites>,
+    session: &BackgroundSession,
+) {
+    if let Err(e) = watch::spawn(source, inodes, attr_cache, own_writes, session.notifier()) {
+        warn!("Not watching {}: {}", source.display(), e);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn watch_source(
+    source: &Path,
+    _inodes: &Arc<InodeTable>,
+    _attr_cache: &Arc<AttrCache>,
+    _own_writes: Arc<OwnWrites>,
+    _session: &BackgroundSession,
+) {
+    warn!("Not watching {}: only supported on Linux", source.display());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocked_read_does_not_hold_other_handles() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let fs = PassthroughFS::new(temp_dir.path().to_path_buf());
+
+        // A read from an empty FIFO blocks until something is written, like
+        // a slow network source would
+        let fifo = temp_dir.path().join("fifo");
+        let fifo_cstr = path_to_cstring(&fifo).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo_cstr.as_ptr(), 0o600) }, 0);
+        let pipe = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&fifo)
+            .unwrap();
+        std::fs::write(temp_dir.path().join("file"), "content").unwrap();
+        let file = File::open(temp_dir.path().join("file")).unwrap();
+
+        let pipe_fh = fs.add_handle(0, libc::O_RDWR, pipe.try_clone().unwrap());
+        let file_fh = fs.add_handle(0, libc::O_RDONLY, file);
+
+        std::thread::scope(|scope| {
+            let blocked = scope.spawn(|| {
+                use std::io::Read;
+                // FIFOs cannot pread, so read through the handle's cursor
+                let mut buffer = [0u8; 4];
+                let pipe = fs.open_file(pipe_fh).unwrap();
+                (&*pipe).read_exact(&mut buffer).map(|_| buffer.len())
+            });
+            std::thread::sleep(Duration::from_millis(100));
+
+            // Another handle can be read, opened and closed meanwhile
+            let mut buffer = [0u8; 7];
+            let read = read_fully_at(&fs.open_file(file_fh).unwrap(), &mut buffer, 0).unwrap();
+            assert_eq!(&buffer[..read], b"content");
+            fs.remove_handle(0, file_fh);
+            assert!(!blocked.is_finished());
+
+            use std::io::Write;
+            (&pipe).write_all(b"done").unwrap();
+            assert_eq!(blocked.join().unwrap().unwrap(), 4);
+        });
+    }
+
+    #[test]
+    fn test_retry_eintr_retries_only_interrupts() {
+        let mut calls = 0;
+        let result = retry_eintr(|| {
+            calls += 1;
+            if calls < 4 {
+                Err(io::Error::from(ErrorKind::Interrupted))
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(result.unwrap(), 4);
+
+        let mut calls = 0;
+        let result: io::Result<()> = retry_eintr(|| {
+            calls += 1;
+            Err(io::Error::from_raw_os_error(libc::EAGAIN))
+        });
+        assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EAGAIN));
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_signals_do_not_fail_blocked_calls() {
+        extern "C" fn ignore(_: libc::c_int) {}
+
+        // Without SA_RESTART every signal fails the blocked call with EINTR
+        let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+        action.sa_sigaction = ignore as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        assert_eq!(
+            unsafe { libc::sigaction(libc::SIGUSR1, &action, std::ptr::null_mut()) },
+            0
+        );
+
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let fifo = temp_dir.path().join("fifo");
+        let fifo_cstr = path_to_cstring(&fifo).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo_cstr.as_ptr(), 0o600) }, 0);
+        let pipe = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&fifo)
+            .unwrap();
+
+        std::thread::scope(|scope| {
+            let (sender, receiver) = std::sync::mpsc::channel();
+            let pipe = &pipe;
+            let blocked = scope.spawn(move || {
+                sender.send(unsafe { libc::pthread_self() }).unwrap();
+                poll_file(pipe, libc::POLLIN as u32, -1)
+            });
+            let thread = receiver.recv().unwrap();
+            for _ in 0..10 {
+                std::thread::sleep(Duration::from_millis(20));
+                assert_eq!(unsafe { libc::pthread_kill(thread, libc::SIGUSR1) }, 0);
+            }
+            assert!(!blocked.is_finished());
+
+            (&*pipe).write_all(b"done").unwrap();
+            assert_eq!(blocked.join().unwrap().unwrap(), libc::POLLIN as u32);
+        });
+    }
+
+    #[test]
+    fn test_io_checks_handle_access_mode() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let path = temp_dir.path().join("file");
+        std::fs::write(&path, "content").unwrap();
+        let fs = PassthroughFS::new(temp_dir.path().to_path_buf());
+
+        let read_only = fs.add_handle(0, libc::O_RDONLY, File::open(&path).unwrap());
+        assert!(fs.io_file(read_only, false).is_ok());
+        assert_eq!(fs.io_file(read_only, true).unwrap_err(), EBADF);
+
+        let write_only = OpenOptions::new().write(true).open(&path).unwrap();
+        let write_only = fs.add_handle(0, libc::O_WRONLY, write_only);
+        assert_eq!(fs.io_file(write_only, false).unwrap_err(), EBADF);
+        assert!(fs.io_file(write_only, true).is_ok());
+
+        assert_eq!(fs.io_file(999, false).unwrap_err(), EBADF);
+    }
+
+    #[test]
+    fn test_check_io_range_rejects_bad_offsets() {
+        assert_eq!(check_io_range(0, u32::MAX as u64), Ok(0));
+        assert_eq!(check_io_range(4096, 10), Ok(4096));
+        assert_eq!(check_io_range(-1, 10), Err(EINVAL));
+        assert_eq!(check_io_range(i64::MIN, 0), Err(EINVAL));
+        assert_eq!(check_io_range(i64::MAX, 0), Ok(i64::MAX as u64));
+        assert_eq!(check_io_range(i64::MAX - 1, 2), Err(EFBIG));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_encode_rdev_splits_minor() {
+        assert_eq!(encode_rdev(libc::makedev(8, 1)), 0x801);
+        assert_eq!(encode_rdev(libc::makedev(0xabc, 0x12345)), 0x123abc45);
+        assert_eq!(encode_rdev(libc::makedev(1 << 12, 0)), 0);
+        assert_eq!(encode_rdev(libc::makedev(0, 1 << 20)), 0);
+    }
+
+    #[test]
+    fn test_check_name_rejects_traversal() {
+        for name in ["../etc", "a/../../etc", "a/b", "..", ".", ""] {
+            assert_eq!(check_name(OsStr::new(name)), Err(EINVAL), "{:?}", name);
+        }
+        for name in ["file.txt", "..hidden", "a..b", "..."] {
+            assert_eq!(check_name(OsStr::new(name)), Ok(()), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn test_crtime_from_birth_time() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let real_path = temp_dir.path().join("born.txt");
+        std::fs::write(&real_path, "content").expect("Failed to write file");
+        let metadata = std::fs::symlink_metadata(&real_path).expect("Failed to stat file");
+
+        let Ok(created) = metadata.created() else {
+            eprintln!("Skipping: filesystem does not record birth time");
+            return;
+        };
+
+        let fs = PassthroughFS::new(temp_dir.path().to_path_buf());
+        let attr = fs.metadata_to_attr(&metadata, 2);
+        assert_eq!(attr.crtime, created);
+        let age = SystemTime::now()
+            .duration_since(attr.crtime)
+            .expect("crtime is in the future");
+        assert!(age < Duration::from_secs(60), "crtime is {:?} old", age);
+    }
+}
@@ -0,0 +1,106 @@
+//! POSIX byte-range locks backed by open file description (OFD) locks
+//!
+//! Each lock owner gets its own description of the backing file, so owners
+//! conflict with each other exactly as separate processes would.
+
+use crate::retry_eintr;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+/// End offset the kernel uses for a lock that extends to end of file
+const OFFSET_MAX: u64 = i64::MAX as u64;
+
+/// A lock range and type as exchanged with the kernel
+#[derive(Debug, Clone, Copy)]
+pub struct Lock {
+    pub start: u64,
+    /// Inclusive end offset, OFFSET_MAX for "to end of file"
+    pub end: u64,
+    /// F_RDLCK, F_WRLCK or F_UNLCK
+    pub typ: i32,
+    pub pid: u32,
+}
+
+impl Lock {
+    fn to_flock(self) -> libc::flock {
+        let mut fl: libc::flock = unsafe { std::mem::zeroed() };
+        fl.l_type = self.typ as libc::c_short;
+        fl.l_whence = libc::SEEK_SET as libc::c_short;
+        fl.l_start = self.start as libc::off_t;
+        fl.l_len = if self.end >= OFFSET_MAX {
+            0
+        } else {
+            (self.end - self.start + 1) as libc::off_t
+        };
+        // OFD locks require l_pid to be zero
+        fl.l_pid = 0;
+        fl
+    }
+
+    fn from_flock(fl: &libc::flock) -> Self {
+        let start = fl.l_start as u64;
+        let end = if fl.l_len == 0 {
+            OFFSET_MAX
+        } else {
+            start + fl.l_len as u64 - 1
+        };
+        Lock {
+            start,
+            end,
+            typ: fl.l_type as i32,
+            // Conflicting OFD locks report a pid of -1
+            pid: fl.l_pid.max(0) as u32,
+        }
+    }
+}
+
+/// Open a new description of the same file as `file`
+///
+/// Read-write is preferred so one owner's description can hold both read and
+/// write locks, falling back to the access mode of the original handle.
+pub fn reopen(file: &File) -> io::Result<File> {
+    let proc_path = format!("/proc/self/fd/{}", file.as_raw_fd());
+
+    if let Ok(reopened) = OpenOptions::new().read(true).write(true).open(&proc_path) {
+        return Ok(reopened);
+    }
+
+    let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let access = flags & libc::O_ACCMODE;
+    OpenOptions::new()
+        .read(access != libc::O_WRONLY)
+        .write(access != libc::O_RDONLY)
+        .open(&proc_path)
+}
+
+/// Test for a lock conflicting with `lock`, returning it or an F_UNLCK lock
+pub fn get(file: &File, lock: Lock) -> io::Result<Lock> {
+    let mut fl = lock.to_flock();
+    let ret = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_OFD_GETLK, &mut fl) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Lock::from_flock(&fl))
+}
+
+/// Acquire or release `lock`, waiting for conflicting locks if `sleep` is set
+pub fn set(file: &File, lock: Lock, sleep: bool) -> io::Result<()> {
+    let fl = lock.to_flock();
+    let cmd = if sleep {
+        libc::F_OFD_SETLKW
+    } else {
+        libc::F_OFD_SETLK
+    };
+    retry_eintr(|| {
+        let ret = unsafe { libc::fcntl(file.as_raw_fd(), cmd, &fl) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    })
+}
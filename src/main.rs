@@ -1,21 +1,15 @@
 use clap::Parser;
-use fuser::{
-    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
-    ReplyOpen, ReplyWrite, Request, TimeOrNow,
-};
-use libc::{ENOENT, ENOSYS};
-use log::{debug, error, info};
-use std::collections::HashMap;
-use std::ffi::OsStr;
-use std::fs::{self, File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use fuse_passthrough::{PassthroughConfig, PassthroughFS};
+use log::info;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-
-const TTL: Duration = Duration::from_secs(1);
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -28,805 +22,286 @@ struct Args {
     #[arg(short, long)]
     mountpoint: String,
 
-    /// Allow other users to access the mounted filesystem
-    #[arg(long, default_value = "false")]
-    allow_other: bool,
-}
-
-/// Passthrough filesystem implementation
-struct PassthroughFS {
-    /// Source directory path
-    source: PathBuf,
-    /// Inode to path mapping
-    inode_to_path: Mutex<HashMap<u64, PathBuf>>,
-    /// Path to inode mapping
-    path_to_inode: Mutex<HashMap<PathBuf, u64>>,
-    /// Next available inode number
-    next_inode: AtomicU64,
-    /// Open file handles
-    open_files: Mutex<HashMap<u64, File>>,
-    /// Next available file handle
-    next_fh: AtomicU64,
-}
-
-impl PassthroughFS {
-    fn new(source: PathBuf) -> Self {
-        let mut inode_to_path = HashMap::new();
-        let mut path_to_inode = HashMap::new();
-
-        // Root directory inode is 1
-        inode_to_path.insert(1, PathBuf::from(""));
-        path_to_inode.insert(PathBuf::from(""), 1);
-
-        PassthroughFS {
-            source,
-            inode_to_path: Mutex::new(inode_to_path),
-            path_to_inode: Mutex::new(path_to_inode),
-            next_inode: AtomicU64::new(2),
-            open_files: Mutex::new(HashMap::new()),
-            next_fh: AtomicU64::new(1),
-        }
-    }
-
-    /// Get the real path on the underlying filesystem
-    fn real_path(&self, relative: &Path) -> PathBuf {
-        self.source.join(relative)
-    }
-
-    /// Get relative path by inode
-    fn get_path(&self, inode: u64) -> Option<PathBuf> {
-        self.inode_to_path.lock().unwrap().get(&inode).cloned()
-    }
+    /// Read-only directory to show under the source, which takes the
+    /// changes (repeatable, topmost first)
+    #[arg(long, value_name = "PATH")]
+    lower: Vec<PathBuf>,
 
-    /// Allocate or get inode for a path
-    fn get_or_create_inode(&self, path: &Path) -> u64 {
-        let mut path_to_inode = self.path_to_inode.lock().unwrap();
-        if let Some(&inode) = path_to_inode.get(path) {
-            return inode;
-        }
-
-        let inode = self.next_inode.fetch_add(1, Ordering::SeqCst);
-        path_to_inode.insert(path.to_path_buf(), inode);
-        self.inode_to_path
-            .lock()
-            .unwrap()
-            .insert(inode, path.to_path_buf());
-        inode
-    }
-
-    /// Convert std::fs::Metadata to FileAttr
-    fn metadata_to_attr(&self, metadata: &fs::Metadata, inode: u64) -> FileAttr {
-        let kind = if metadata.is_dir() {
-            FileType::Directory
-        } else if metadata.is_symlink() {
-            FileType::Symlink
-        } else {
-            FileType::RegularFile
-        };
-
-        let atime = metadata.accessed().unwrap_or(UNIX_EPOCH);
-        let mtime = metadata.modified().unwrap_or(UNIX_EPOCH);
-        let ctime = SystemTime::UNIX_EPOCH + Duration::from_secs(metadata.ctime() as u64);
-
-        FileAttr {
-            ino: inode,
-            size: metadata.size(),
-            blocks: metadata.blocks(),
-            atime,
-            mtime,
-            ctime,
-            crtime: UNIX_EPOCH,
-            kind,
-            perm: (metadata.mode() & 0o7777) as u16,
-            nlink: metadata.nlink() as u32,
-            uid: metadata.uid(),
-            gid: metadata.gid(),
-            rdev: metadata.rdev() as u32,
-            blksize: metadata.blksize() as u32,
-            flags: 0,
-        }
-    }
-}
-
-impl Filesystem for PassthroughFS {
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        debug!("lookup: parent={}, name={:?}", parent, name);
-
-        let parent_path = match self.get_path(parent) {
-            Some(p) => p,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
-        };
+    /// Hide entries whose names match GLOB (repeatable)
+    #[arg(long, value_name = "GLOB", value_parser = parse_glob)]
+    exclude: Vec<String>,
 
-        let relative_path = parent_path.join(name);
-        let real_path = self.real_path(&relative_path);
+    /// Show entries whose names match GLOB even if an --exclude matches
+    /// (repeatable)
+    #[arg(long, value_name = "GLOB", value_parser = parse_glob)]
+    include: Vec<String>,
 
-        match fs::metadata(&real_path) {
-            Ok(metadata) => {
-                let inode = self.get_or_create_inode(&relative_path);
-                let attr = self.metadata_to_attr(&metadata, inode);
-                reply.entry(&TTL, &attr, 0);
-            }
-            Err(_) => {
-                reply.error(ENOENT);
-            }
-        }
-    }
-
-    fn getattr(&mut self, _req: &Request, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
-        debug!("getattr: ino={}", ino);
-
-        let path = match self.get_path(ino) {
-            Some(p) => p,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
-        };
-
-        let real_path = self.real_path(&path);
-
-        match fs::metadata(&real_path) {
-            Ok(metadata) => {
-                let attr = self.metadata_to_attr(&metadata, ino);
-                reply.attr(&TTL, &attr);
-            }
-            Err(_) => {
-                reply.error(ENOENT);
-            }
-        }
-    }
-
-    fn setattr(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        mode: Option<u32>,
-        uid: Option<u32>,
-        gid: Option<u32>,
-        size: Option<u64>,
-        _atime: Option<TimeOrNow>,
-        _mtime: Option<TimeOrNow>,
-        _ctime: Option<SystemTime>,
-        _fh: Option<u64>,
-        _crtime: Option<SystemTime>,
-        _chgtime: Option<SystemTime>,
-        _bkuptime: Option<SystemTime>,
-        _flags: Option<u32>,
-        reply: ReplyAttr,
-    ) {
-        debug!("setattr: ino={}", ino);
-
-        let path = match self.get_path(ino) {
-            Some(p) => p,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
-        };
-
-        let real_path = self.real_path(&path);
-
-        // Handle file truncation
-        if let Some(new_size) = size {
-            if let Ok(file) = OpenOptions::new().write(true).open(&real_path) {
-                let _ = file.set_len(new_size);
-            }
-        }
-
-        // Handle permission change
-        if let Some(new_mode) = mode {
-            let _ = fs::set_permissions(&real_path, fs::Permissions::from_mode(new_mode));
-        }
-
-        // Handle uid/gid change
-        if uid.is_some() || gid.is_some() {
-            let uid = uid.unwrap_or(u32::MAX);
-            let gid = gid.unwrap_or(u32::MAX);
-            unsafe {
-                let path_cstr = std::ffi::CString::new(real_path.to_str().unwrap()).unwrap();
-                libc::chown(path_cstr.as_ptr(), uid, gid);
-            }
-        }
-
-        // Return updated attributes
-        match fs::metadata(&real_path) {
-            Ok(metadata) => {
-                let attr = self.metadata_to_attr(&metadata, ino);
-                reply.attr(&TTL, &attr);
-            }
-            Err(_) => {
-                reply.error(ENOENT);
-            }
-        }
-    }
-
-    fn read(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        fh: u64,
-        offset: i64,
-        size: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        reply: ReplyData,
-    ) {
-        debug!(
-            "read: ino={}, fh={}, offset={}, size={}",
-            ino, fh, offset, size
-        );
-
-        let mut open_files = self.open_files.lock().unwrap();
-        if let Some(file) = open_files.get_mut(&fh) {
-            let mut buffer = vec![0u8; size as usize];
-            if file.seek(SeekFrom::Start(offset as u64)).is_ok() {
-                match file.read(&mut buffer) {
-                    Ok(bytes_read) => {
-                        reply.data(&buffer[..bytes_read]);
-                        return;
-                    }
-                    Err(e) => {
-                        error!("read error: {:?}", e);
-                    }
-                }
-            }
-        }
-        reply.error(ENOENT);
-    }
+    /// Hide entries whose names start with a dot, like --exclude '.*'
+    #[arg(long, default_value = "false")]
+    hide_dotfiles: bool,
 
-    fn write(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        fh: u64,
-        offset: i64,
-        data: &[u8],
-        _write_flags: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        reply: ReplyWrite,
-    ) {
-        debug!(
-            "write: ino={}, fh={}, offset={}, size={}",
-            ino,
-            fh,
-            offset,
-            data.len()
-        );
+    /// Name shown as the mount's source (default: the source directory's name)
+    #[arg(long)]
+    fsname: Option<String>,
 
-        let mut open_files = self.open_files.lock().unwrap();
-        if let Some(file) = open_files.get_mut(&fh) {
-            if file.seek(SeekFrom::Start(offset as u64)).is_ok() {
-                match file.write(data) {
-                    Ok(bytes_written) => {
-                        reply.written(bytes_written as u32);
-                        return;
-                    }
-                    Err(e) => {
-                        error!("write error: {:?}", e);
-                    }
-                }
-            }
-        }
-        reply.error(ENOENT);
-    }
+    /// Filesystem subtype, shown as fuse.<SUBTYPE>
+    #[arg(long)]
+    subtype: Option<String>,
 
-    fn readdir(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        mut reply: ReplyDirectory,
-    ) {
-        debug!("readdir: ino={}, offset={}", ino, offset);
-
-        let path = match self.get_path(ino) {
-            Some(p) => p,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
-        };
+    /// Allow other users to access the mounted filesystem
+    #[arg(long, default_value = "false")]
+    allow_other: bool,
 
-        let real_path = self.real_path(&path);
+    /// Allow only root besides the mounting user
+    #[arg(long, default_value = "false", conflicts_with = "allow_other")]
+    allow_root: bool,
 
-        let entries = match fs::read_dir(&real_path) {
-            Ok(entries) => entries,
-            Err(_) => {
-                reply.error(ENOENT);
-                return;
-            }
-        };
+    /// Let the kernel check permissions against the file mode and owner
+    #[arg(long, default_value = "false")]
+    default_permissions: bool,
 
-        let mut all_entries: Vec<_> = vec![
-            (ino, FileType::Directory, ".".to_string()),
-            (ino, FileType::Directory, "..".to_string()),
-        ];
-
-        for entry in entries.flatten() {
-            let name = entry.file_name().to_string_lossy().to_string();
-            let relative_path = path.join(&name);
-            let child_inode = self.get_or_create_inode(&relative_path);
-
-            let file_type = if let Ok(metadata) = entry.metadata() {
-                if metadata.is_dir() {
-                    FileType::Directory
-                } else if metadata.is_symlink() {
-                    FileType::Symlink
-                } else {
-                    FileType::RegularFile
-                }
-            } else {
-                FileType::RegularFile
-            };
-
-            all_entries.push((child_inode, file_type, name));
-        }
+    /// Check every request against the caller's uid and gid
+    #[arg(long, default_value = "false")]
+    enforce_permissions: bool,
 
-        for (i, (inode, file_type, name)) in all_entries.iter().enumerate().skip(offset as usize) {
-            if reply.add(*inode, (i + 1) as i64, *file_type, name) {
-                break;
-            }
-        }
+    /// Mount read-only, rejecting every modification
+    #[arg(long, default_value = "false")]
+    read_only: bool,
 
-        reply.ok();
-    }
+    /// Seconds the kernel may cache file attributes (0 disables)
+    #[arg(long, default_value = "1")]
+    attr_ttl: u64,
 
-    fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
-        debug!("open: ino={}, flags={}", ino, flags);
+    /// Seconds the kernel may cache name lookups (0 disables)
+    #[arg(long, default_value = "1")]
+    entry_ttl: u64,
 
-        let path = match self.get_path(ino) {
-            Some(p) => p,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
-        };
+    /// Invalidate kernel caches as soon as files change in the source
+    #[arg(long, default_value = "false")]
+    watch_source: bool,
 
-        let real_path = self.real_path(&path);
-
-        let read = (flags & libc::O_ACCMODE) == libc::O_RDONLY
-            || (flags & libc::O_ACCMODE) == libc::O_RDWR;
-        let write = (flags & libc::O_ACCMODE) == libc::O_WRONLY
-            || (flags & libc::O_ACCMODE) == libc::O_RDWR;
-
-        match OpenOptions::new()
-            .read(read)
-            .write(write)
-            .append((flags & libc::O_APPEND) != 0)
-            .open(&real_path)
-        {
-            Ok(file) => {
-                let fh = self.next_fh.fetch_add(1, Ordering::SeqCst);
-                self.open_files.lock().unwrap().insert(fh, file);
-                reply.opened(fh, 0);
-            }
-            Err(e) => {
-                error!("open error: {:?}", e);
-                reply.error(ENOENT);
-            }
-        }
-    }
+    /// Seconds the kernel may cache lookups of missing names (0 disables)
+    #[arg(long, default_value = "0")]
+    negative_ttl: u64,
 
-    fn release(
-        &mut self,
-        _req: &Request,
-        _ino: u64,
-        fh: u64,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        _flush: bool,
-        reply: fuser::ReplyEmpty,
-    ) {
-        debug!("release: fh={}", fh);
-        self.open_files.lock().unwrap().remove(&fh);
-        reply.ok();
-    }
+    /// Do not return attributes with directory listings
+    #[arg(long, default_value = "false")]
+    no_readdirplus: bool,
 
-    fn create(
-        &mut self,
-        _req: &Request,
-        parent: u64,
-        name: &OsStr,
-        mode: u32,
-        _umask: u32,
-        flags: i32,
-        reply: fuser::ReplyCreate,
-    ) {
-        debug!("create: parent={}, name={:?}, mode={}", parent, name, mode);
-
-        let parent_path = match self.get_path(parent) {
-            Some(p) => p,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
-        };
+    /// Refuse to follow symlinks that lead outside the source
+    #[arg(long, default_value = "false")]
+    no_follow_escapes: bool,
 
-        let relative_path = parent_path.join(name);
-        let real_path = self.real_path(&relative_path);
-
-        let read = (flags & libc::O_ACCMODE) == libc::O_RDONLY
-            || (flags & libc::O_ACCMODE) == libc::O_RDWR;
-        let write = (flags & libc::O_ACCMODE) == libc::O_WRONLY
-            || (flags & libc::O_ACCMODE) == libc::O_RDWR;
-
-        match OpenOptions::new()
-            .read(read)
-            .write(write)
-            .create(true)
-            .truncate((flags & libc::O_TRUNC) != 0)
-            .open(&real_path)
-        {
-            Ok(file) => {
-                // Set permissions
-                let _ = fs::set_permissions(&real_path, fs::Permissions::from_mode(mode));
-
-                let inode = self.get_or_create_inode(&relative_path);
-                let fh = self.next_fh.fetch_add(1, Ordering::SeqCst);
-                self.open_files.lock().unwrap().insert(fh, file);
-
-                match fs::metadata(&real_path) {
-                    Ok(metadata) => {
-                        let attr = self.metadata_to_attr(&metadata, inode);
-                        reply.created(&TTL, &attr, 0, fh, 0);
-                    }
-                    Err(_) => {
-                        reply.error(ENOENT);
-                    }
-                }
-            }
-            Err(e) => {
-                error!("create error: {:?}", e);
-                reply.error(ENOENT);
-            }
-        }
-    }
+    /// Show files owned by uid SRC in the source as owned by DST (repeatable)
+    #[arg(long = "map-uid", value_name = "SRC:DST", value_parser = parse_id_pair)]
+    map_uid: Vec<(u32, u32)>,
 
-    fn mkdir(
-        &mut self,
-        _req: &Request,
-        parent: u64,
-        name: &OsStr,
-        mode: u32,
-        _umask: u32,
-        reply: ReplyEntry,
-    ) {
-        debug!("mkdir: parent={}, name={:?}, mode={}", parent, name, mode);
-
-        let parent_path = match self.get_path(parent) {
-            Some(p) => p,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
-        };
+    /// Show files owned by gid SRC in the source as owned by DST (repeatable)
+    #[arg(long = "map-gid", value_name = "SRC:DST", value_parser = parse_id_pair)]
+    map_gid: Vec<(u32, u32)>,
 
-        let relative_path = parent_path.join(name);
-        let real_path = self.real_path(&relative_path);
-
-        match fs::create_dir(&real_path) {
-            Ok(_) => {
-                let _ = fs::set_permissions(&real_path, fs::Permissions::from_mode(mode));
-                let inode = self.get_or_create_inode(&relative_path);
-                match fs::metadata(&real_path) {
-                    Ok(metadata) => {
-                        let attr = self.metadata_to_attr(&metadata, inode);
-                        reply.entry(&TTL, &attr, 0);
-                    }
-                    Err(_) => {
-                        reply.error(ENOENT);
-                    }
-                }
-            }
-            Err(e) => {
-                error!("mkdir error: {:?}", e);
-                reply.error(libc::EEXIST);
-            }
-        }
-    }
+    /// Punch holes instead of writing blocks that are all zeros
+    #[arg(long, default_value = "false")]
+    detect_zero_holes: bool,
 
-    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
-        debug!("unlink: parent={}, name={:?}", parent, name);
+    /// Let the kernel buffer writes and flush them in large chunks
+    #[arg(long, default_value = "false")]
+    writeback_cache: bool,
 
-        let parent_path = match self.get_path(parent) {
-            Some(p) => p,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
-        };
+    /// Bypass the kernel page cache, so reads always reach the source
+    #[arg(long, default_value = "false")]
+    direct_io: bool,
 
-        let relative_path = parent_path.join(name);
-        let real_path = self.real_path(&relative_path);
+    /// Do not update access times in the source when reading through the mount
+    #[arg(long, default_value = "false")]
+    noatime: bool,
 
-        match fs::remove_file(&real_path) {
-            Ok(_) => {
-                // Clean up inode mapping
-                if let Some(inode) = self.path_to_inode.lock().unwrap().remove(&relative_path) {
-                    self.inode_to_path.lock().unwrap().remove(&inode);
-                }
-                reply.ok();
-            }
-            Err(e) => {
-                error!("unlink error: {:?}", e);
-                reply.error(ENOENT);
-            }
-        }
-    }
+    /// Store a checksum of files written through the mount and fail reads of
+    /// files that no longer match it
+    #[arg(long, default_value = "false")]
+    verify_checksums: bool,
 
-    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
-        debug!("rmdir: parent={}, name={:?}", parent, name);
+    /// Find names that do not exist as spelled by matching them ignoring case
+    #[arg(long, default_value = "false")]
+    case_insensitive: bool,
+
+    /// Keep inode numbers and generations across mounts in this file
+    #[arg(long, value_name = "PATH")]
+    inode_db: Option<PathBuf>,
+
+    /// Keep at most this many backing files open, reopening evicted ones on use
+    #[arg(long, value_name = "N")]
+    max_open_files: Option<usize>,
+
+    /// Close backing files of handles unused for this many seconds,
+    /// reopening them on next use
+    #[arg(long, value_name = "SECONDS")]
+    idle_handle_timeout: Option<u64>,
+
+    /// Seconds between scans for idle handles
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value = "60",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    idle_handle_scan_interval: u64,
+
+    /// Fail reads and writes the source takes longer than this many
+    /// milliseconds to serve with ETIMEDOUT
+    #[arg(
+        long,
+        value_name = "MS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    op_timeout: Option<u64>,
+
+    /// Threads serving reads and writes; other requests are always served
+    /// one at a time (default: 1). With 1, a request delayed by
+    /// --read-bps, --write-bps or --iops holds up every other request
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    io_threads: Option<u64>,
+
+    /// Delay reads beyond this many bytes per second
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u64).range(1..))]
+    read_bps: Option<u64>,
+
+    /// Delay writes beyond this many bytes per second
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u64).range(1..))]
+    write_bps: Option<u64>,
+
+    /// Delay reads and writes beyond this many per second
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    iops: Option<u64>,
+
+    /// Write a newline to this inherited descriptor, and close it, once the
+    /// mount is serving requests
+    #[arg(long, value_name = "FD")]
+    ready_fd: Option<i32>,
+
+    /// Log the request counters every this many seconds
+    #[arg(long, value_name = "SECONDS")]
+    stats_log_interval: Option<u64>,
+
+    /// Only check that the source and mountpoint could be mounted, then exit
+    #[arg(long, default_value = "false")]
+    check: bool,
+}
 
-        let parent_path = match self.get_path(parent) {
-            Some(p) => p,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
-        };
+/// Parse a name pattern for --exclude or --include
+fn parse_glob(value: &str) -> Result<String, String> {
+    fuse_passthrough::check_glob(value).map(|()| value.to_string())
+}
 
-        let relative_path = parent_path.join(name);
-        let real_path = self.real_path(&relative_path);
+/// Parse a `SRC:DST` id pair
+fn parse_id_pair(value: &str) -> Result<(u32, u32), String> {
+    let (source, mounted) = value
+        .split_once(':')
+        .ok_or_else(|| format!("expected SRC:DST, got '{}'", value))?;
+    let parse = |id: &str| {
+        id.parse::<u32>()
+            .map_err(|e| format!("invalid id '{}': {}", id, e))
+    };
+    Ok((parse(source)?, parse(mounted)?))
+}
 
-        match fs::remove_dir(&real_path) {
-            Ok(_) => {
-                // Clean up inode mapping
-                if let Some(inode) = self.path_to_inode.lock().unwrap().remove(&relative_path) {
-                    self.inode_to_path.lock().unwrap().remove(&inode);
-                }
-                reply.ok();
-            }
-            Err(e) => {
-                error!("rmdir error: {:?}", e);
-                reply.error(ENOENT);
-            }
-        }
+/// Whether the calling process may access `path` with `mode`
+fn access(path: &Path, mode: libc::c_int) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::access(path.as_ptr(), mode) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
     }
+}
 
-    fn rename(
-        &mut self,
-        _req: &Request,
-        parent: u64,
-        name: &OsStr,
-        newparent: u64,
-        newname: &OsStr,
-        _flags: u32,
-        reply: fuser::ReplyEmpty,
-    ) {
-        debug!(
-            "rename: parent={}, name={:?}, newparent={}, newname={:?}",
-            parent, name, newparent, newname
-        );
-
-        let parent_path = match self.get_path(parent) {
-            Some(p) => p,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
-        };
-
-        let newparent_path = match self.get_path(newparent) {
-            Some(p) => p,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
-        };
-
-        let old_relative = parent_path.join(name);
-        let new_relative = newparent_path.join(newname);
-        let old_real = self.real_path(&old_relative);
-        let new_real = self.real_path(&new_relative);
-
-        match fs::rename(&old_real, &new_real) {
-            Ok(_) => {
-                // Update inode mapping - use a single lock scope to avoid deadlock
-                {
-                    let mut path_to_inode = self.path_to_inode.lock().unwrap();
-                    if let Some(inode) = path_to_inode.remove(&old_relative) {
-                        path_to_inode.insert(new_relative.clone(), inode);
-                        self.inode_to_path
-                            .lock()
-                            .unwrap()
-                            .insert(inode, new_relative);
-                    }
-                }
-                reply.ok();
-            }
-            Err(e) => {
-                error!("rename error: {:?}", e);
-                reply.error(ENOENT);
-            }
+/// Check that `path` is a directory the daemon can list
+fn check_dir(path: &Path) -> Result<(), String> {
+    match fs::metadata(path) {
+        Ok(metadata) if metadata.is_dir() => {
+            access(path, libc::R_OK | libc::X_OK).map_err(|e| format!("cannot be read: {}", e))
         }
+        Ok(_) => Err("is not a directory".to_string()),
+        Err(e) => Err(e.to_string()),
     }
+}
 
-    fn statfs(&mut self, _req: &Request, _ino: u64, reply: fuser::ReplyStatfs) {
-        reply.statfs(0, 0, 0, 0, 0, 512, 255, 0);
+/// Check that nothing is mounted on the directory `path` yet
+fn check_not_mounted(path: &Path) -> Result<(), String> {
+    let path = path.canonicalize().map_err(|e| e.to_string())?;
+    let metadata = fs::metadata(&path).map_err(|e| e.to_string())?;
+    let parent = fs::metadata(path.join("..")).map_err(|e| e.to_string())?;
+    // A mount root sits on another device than its parent, or is its own
+    // parent for /
+    if metadata.dev() != parent.dev() || metadata.ino() == parent.ino() {
+        return Err("is already a mountpoint".to_string());
     }
+    Ok(())
+}
 
-    /// macOS only: Exchange two files atomically
-    #[cfg(target_os = "macos")]
-    fn exchange(
-        &mut self,
-        _req: &Request,
-        parent: u64,
-        name: &OsStr,
-        newparent: u64,
-        newname: &OsStr,
-        _options: u64,
-        reply: fuser::ReplyEmpty,
-    ) {
-        debug!(
-            "exchange: parent={}, name={:?}, newparent={}, newname={:?}",
-            parent, name, newparent, newname
-        );
-
-        // For non-atomic exchange, just do a rename
-        let parent_path = match self.get_path(parent) {
-            Some(p) => p,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
-        };
-
-        let newparent_path = match self.get_path(newparent) {
-            Some(p) => p,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
+/// Check that the inode database at `path` can be read and written, or
+/// created when it does not exist yet
+fn check_inode_db(path: &Path) -> Result<(), String> {
+    let result = if path.exists() {
+        access(path, libc::R_OK | libc::W_OK)
+    } else {
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
         };
+        access(parent, libc::W_OK | libc::X_OK)
+    };
+    result.map_err(|e| e.to_string())
+}
 
-        let old_relative = parent_path.join(name);
-        let new_relative = newparent_path.join(newname);
-        let old_real = self.real_path(&old_relative);
-        let new_real = self.real_path(&new_relative);
-
-        match fs::rename(&old_real, &new_real) {
-            Ok(_) => {
-                // Update inode mapping - use a single lock scope to avoid deadlock
-                {
-                    let mut path_to_inode = self.path_to_inode.lock().unwrap();
-                    if let Some(inode) = path_to_inode.remove(&old_relative) {
-                        path_to_inode.insert(new_relative.clone(), inode);
-                        self.inode_to_path
-                            .lock()
-                            .unwrap()
-                            .insert(inode, new_relative);
-                    }
-                }
-                reply.ok();
-            }
-            Err(e) => {
-                error!("exchange error: {:?}", e);
-                reply.error(ENOENT);
-            }
-        }
+/// Run every check done before mounting, without mounting, and print a
+/// line per check. Returns whether all of them passed.
+fn preflight(args: &Args) -> bool {
+    let source = Path::new(&args.source);
+    let mountpoint = Path::new(&args.mountpoint);
+    let mut checks = vec![(format!("source {}", args.source), check_dir(source))];
+    for lower in &args.lower {
+        checks.push((format!("lower {}", lower.display()), check_dir(lower)));
     }
-
-    fn access(&mut self, _req: &Request, ino: u64, mask: i32, reply: fuser::ReplyEmpty) {
-        debug!("access: ino={}, mask={}", ino, mask);
-
-        let path = match self.get_path(ino) {
-            Some(p) => p,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
-        };
-
-        let real_path = self.real_path(&path);
-
-        if real_path.exists() {
-            reply.ok();
-        } else {
-            reply.error(ENOENT);
-        }
+    checks.push((
+        format!("mountpoint {}", args.mountpoint),
+        check_dir(mountpoint).and_then(|_| check_not_mounted(mountpoint)),
+    ));
+    if let Some(path) = &args.inode_db {
+        checks.push((
+            format!("inode database {}", path.display()),
+            check_inode_db(path),
+        ));
     }
-
-    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
-        debug!("readlink: ino={}", ino);
-
-        let path = match self.get_path(ino) {
-            Some(p) => p,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
-        };
-
-        let real_path = self.real_path(&path);
-
-        match fs::read_link(&real_path) {
-            Ok(target) => {
-                reply.data(target.to_string_lossy().as_bytes());
-            }
-            Err(_) => {
-                reply.error(ENOENT);
-            }
-        }
+    // fusermount only mounts on directories the user may write to
+    if unsafe { libc::geteuid() } != 0 {
+        checks.push((
+            format!("mountpoint {} is writable", args.mountpoint),
+            access(mountpoint, libc::W_OK).map_err(|e| e.to_string()),
+        ));
     }
-
-    fn symlink(
-        &mut self,
-        _req: &Request,
-        parent: u64,
-        link_name: &OsStr,
-        target: &Path,
-        reply: ReplyEntry,
-    ) {
-        debug!(
-            "symlink: parent={}, name={:?}, target={:?}",
-            parent, link_name, target
-        );
-
-        let parent_path = match self.get_path(parent) {
-            Some(p) => p,
-            None => {
-                reply.error(ENOENT);
-                return;
+    #[cfg(target_os = "linux")]
+    checks.push((
+        "/dev/fuse".to_string(),
+        fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/fuse")
+            .map(drop)
+            .map_err(|e| e.to_string()),
+    ));
+
+    let mut passed = true;
+    for (check, result) in checks {
+        match result {
+            Ok(()) => println!("ok: {}", check),
+            Err(problem) => {
+                println!("error: {}: {}", check, problem);
+                passed = false;
             }
-        };
-
-        let relative_path = parent_path.join(link_name);
-        let real_path = self.real_path(&relative_path);
-
-        match std::os::unix::fs::symlink(target, &real_path) {
-            Ok(_) => {
-                let inode = self.get_or_create_inode(&relative_path);
-                match fs::symlink_metadata(&real_path) {
-                    Ok(metadata) => {
-                        let attr = self.metadata_to_attr(&metadata, inode);
-                        reply.entry(&TTL, &attr, 0);
-                    }
-                    Err(_) => {
-                        reply.error(ENOENT);
-                    }
-                }
-            }
-            Err(e) => {
-                error!("symlink error: {:?}", e);
-                reply.error(ENOSYS);
-            }
-        }
-    }
-
-    fn flush(
-        &mut self,
-        _req: &Request,
-        _ino: u64,
-        fh: u64,
-        _lock_owner: u64,
-        reply: fuser::ReplyEmpty,
-    ) {
-        debug!("flush: fh={}", fh);
-        if let Some(file) = self.open_files.lock().unwrap().get_mut(&fh) {
-            let _ = file.sync_all();
         }
-        reply.ok();
-    }
-
-    fn fsync(
-        &mut self,
-        _req: &Request,
-        _ino: u64,
-        fh: u64,
-        _datasync: bool,
-        reply: fuser::ReplyEmpty,
-    ) {
-        debug!("fsync: fh={}", fh);
-        if let Some(file) = self.open_files.lock().unwrap().get_mut(&fh) {
-            let _ = file.sync_all();
-        }
-        reply.ok();
     }
+    passed
 }
 
 fn main() {
@@ -834,6 +309,10 @@ fn main() {
 
     let args = Args::parse();
 
+    if args.check {
+        std::process::exit(if preflight(&args) { 0 } else { 1 });
+    }
+
     let source = PathBuf::from(&args.source);
     let mountpoint = PathBuf::from(&args.mountpoint);
 
@@ -855,6 +334,22 @@ fn main() {
         std::process::exit(1);
     }
 
+    // Verify the readiness descriptor was inherited, and keep it from
+    // helpers such as fusermount, which would hold it open past our exit
+    if let Some(fd) = args.ready_fd {
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+            eprintln!("Error: --ready-fd {} is not an open descriptor", fd);
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(path) = &args.inode_db {
+        if let Err(problem) = check_inode_db(path) {
+            eprintln!("Error: inode database '{}': {}", path.display(), problem);
+            std::process::exit(1);
+        }
+    }
+
     let source = source
         .canonicalize()
         .expect("Failed to get absolute path for source directory");
@@ -864,55 +359,101 @@ fn main() {
 
     info!("Mounting {} to {}", source.display(), mountpoint.display());
 
-    let fs = PassthroughFS::new(source);
-
-    let mut options = vec![
-        MountOption::RW,
-        MountOption::FSName("passthrough".to_string()),
-        MountOption::AutoUnmount,
-    ];
-
-    if args.allow_other {
-        options.push(MountOption::AllowOther);
+    let mut config = PassthroughConfig::new(source);
+    config.fsname = args.fsname;
+    config.subtype = args.subtype;
+    config.allow_other = args.allow_other;
+    config.allow_root = args.allow_root;
+    config.read_only = args.read_only;
+    config.default_permissions = args.default_permissions;
+    config.enforce_permissions = args.enforce_permissions;
+    config.attr_ttl = Duration::from_secs(args.attr_ttl);
+    config.entry_ttl = Duration::from_secs(args.entry_ttl);
+    config.negative_ttl = Duration::from_secs(args.negative_ttl);
+    config.watch_source = args.watch_source;
+    config.readdirplus = !args.no_readdirplus;
+    config.max_open_files = args.max_open_files;
+    config.idle_handle_timeout = args.idle_handle_timeout.map(Duration::from_secs);
+    config.idle_handle_scan_interval = Duration::from_secs(args.idle_handle_scan_interval);
+    config.op_timeout = args.op_timeout.map(Duration::from_millis);
+    config.ready_fd = args.ready_fd;
+    if let Some(io_threads) = args.io_threads {
+        config.io_threads = io_threads as usize;
+    }
+    config.read_bps = args.read_bps;
+    config.write_bps = args.write_bps;
+    config.iops = args.iops;
+    config.detect_zero_holes = args.detect_zero_holes;
+    config.writeback_cache = args.writeback_cache;
+    config.direct_io = args.direct_io;
+    config.noatime = args.noatime;
+    config.verify_checksums = args.verify_checksums;
+    config.case_insensitive = args.case_insensitive;
+    config.inode_db = args.inode_db;
+    config.no_follow_escapes = args.no_follow_escapes;
+    config.lower = args.lower;
+    config.exclude = args.exclude;
+    if args.hide_dotfiles {
+        config.exclude.push(".*".to_string());
     }
+    config.include = args.include;
+    config.uid_map = args.map_uid;
+    config.gid_map = args.map_gid;
 
     println!("Mounting filesystem...");
     println!("Source: {}", args.source);
     println!("Mountpoint: {}", args.mountpoint);
     println!("Press Ctrl+C to unmount and exit");
 
-    // Use background session for mounting, allowing controlled unmount
-    let session = match fuser::spawn_mount2(fs, &mountpoint, &options) {
-        Ok(session) => session,
+    let options = config.mount_options();
+    let fs = match PassthroughFS::with_config(config) {
+        Ok(fs) => fs,
         Err(e) => {
-            eprintln!("Mount failed: {}", e);
+            eprintln!("Error: {}", e);
             std::process::exit(1);
         }
     };
+    let stats = fs.stats_handle();
 
-    println!("Filesystem mounted");
-
-    // Set up Ctrl+C signal handler
+    // Set up a handler for SIGINT, SIGTERM and SIGHUP so service managers
+    // stopping the process get the same clean unmount as Ctrl+C. It is in
+    // place before mounting, since --ready-fd may tell them to go ahead
+    // before mount_fs has even returned.
     let running = Arc::new(AtomicBool::new(true));
     let r = running.clone();
-    let mp = mountpoint.clone();
 
     ctrlc::set_handler(move || {
-        println!("\nReceived Ctrl+C, unmounting...");
+        println!("\nReceived termination signal, unmounting...");
         r.store(false, Ordering::SeqCst);
     })
-    .expect("Failed to set Ctrl+C handler");
+    .expect("Failed to set signal handler");
+
+    // Use background session for mounting, allowing controlled unmount
+    let mount = match fuse_passthrough::mount_fs(fs, &mountpoint, &options) {
+        Ok(mount) => mount,
+        Err(e) => {
+            eprintln!("Mount failed: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    println!("Filesystem mounted");
 
     // Wait for exit signal
+    let stats_interval = args.stats_log_interval.map(Duration::from_secs);
+    let mut last_stats_log = Instant::now();
     while running.load(Ordering::SeqCst) {
         std::thread::sleep(Duration::from_millis(100));
+        if let Some(interval) = stats_interval {
+            if last_stats_log.elapsed() >= interval {
+                info!("Stats: {:?}", stats.stats());
+                last_stats_log = Instant::now();
+            }
+        }
     }
 
-    // Unmount filesystem
-    drop(session);
-
-    // Ensure unmount completes
-    let _ = std::process::Command::new("umount").arg(&mp).output();
+    // Unmount and wait for the session loop to finish
+    mount.unmount();
 
     println!("Filesystem unmounted, exiting");
 }
@@ -0,0 +1,55 @@
+//! Handle on a running background mount
+
+use fuser::BackgroundSession;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// A filesystem mounted in a background thread.
+///
+/// Dropping it unmounts the filesystem and waits for the session thread, so
+/// it can be held as a guard for the lifetime of the mount. Call
+/// [`Mount::unmount`] to do the same at a chosen point.
+pub struct Mount {
+    session: Option<BackgroundSession>,
+    mountpoint: PathBuf,
+}
+
+impl Mount {
+    pub(crate) fn new(session: BackgroundSession, mountpoint: &Path) -> Self {
+        Mount {
+            session: Some(session),
+            mountpoint: mountpoint.to_path_buf(),
+        }
+    }
+
+    /// Where the filesystem is mounted
+    pub fn mountpoint(&self) -> &Path {
+        &self.mountpoint
+    }
+
+    /// Unmount the filesystem and wait until the session thread has exited
+    pub fn unmount(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(session) = self.session.take() {
+            session.join();
+        }
+    }
+}
+
+impl Drop for Mount {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl fmt::Debug for Mount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mount")
+            .field("mountpoint", &self.mountpoint)
+            .field("mounted", &self.session.is_some())
+            .finish()
+    }
+}
@@ -0,0 +1,329 @@
+//! Writable upper directory over read-only lower directories
+//!
+//! Paths resolve to the topmost layer that has them, as with overlayfs.
+//! Lower layers are never written: the first change to a file only found
+//! below copies it up into the upper directory, and removing a name that
+//! still exists below leaves a whiteout in the upper directory. Whiteouts
+//! and opaque directory markers are plain files in the aufs format, so no
+//! privileges are needed to create them.
+
+use std::collections::HashSet;
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::{self, FileType};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, DirEntryExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// Prefix of the names whiteouts and markers are stored under, which
+/// are never shown through the mount
+const WHITEOUT_PREFIX: &[u8] = b".wh.";
+/// Marker hiding everything below a directory in lower layers
+const OPAQUE_MARKER: &str = ".wh..wh..opq";
+
+/// A directory entry as listed for opendir
+pub(crate) struct Entry {
+    pub(crate) name: OsString,
+    pub(crate) file_type: FileType,
+    /// (st_dev, st_ino) of the entry in the layer it comes from
+    pub(crate) backing: (u64, u64),
+}
+
+/// Where a layer stands on a path
+enum Lookup {
+    Found(PathBuf),
+    /// Not in this layer, so look further down
+    Absent,
+    /// Whited out or under an opaque directory, hiding lower layers
+    Hidden,
+}
+
+/// Whether `name` is reserved for whiteouts and markers
+pub(crate) fn is_reserved(name: &OsStr) -> bool {
+    name.as_bytes().starts_with(WHITEOUT_PREFIX)
+}
+
+fn whiteout_name(name: &OsStr) -> OsString {
+    let mut whiteout = OsString::from(OsStr::from_bytes(WHITEOUT_PREFIX));
+    whiteout.push(name);
+    whiteout
+}
+
+pub(crate) struct Overlay {
+    /// Upper directory first, then the lower ones from the top down
+    layers: Vec<PathBuf>,
+}
+
+impl Overlay {
+    pub(crate) fn new(upper: PathBuf, lowers: Vec<PathBuf>) -> Self {
+        let mut layers = vec![upper];
+        layers.extend(lowers);
+        Overlay { layers }
+    }
+
+    fn upper(&self) -> &Path {
+        &self.layers[0]
+    }
+
+    /// Every layer, upper first
+    pub(crate) fn layers(&self) -> &[PathBuf] {
+        &self.layers
+    }
+
+    /// Real path of `relative` in the topmost layer that has it, or in the
+    /// upper directory if none does, which is where it would be created
+    pub(crate) fn resolve(&self, relative: &Path) -> PathBuf {
+        self.find(relative, &self.layers)
+            .unwrap_or_else(|| self.upper().join(relative))
+    }
+
+    /// Whether `relative` exists in the upper directory itself
+    pub(crate) fn in_upper(&self, relative: &Path) -> bool {
+        fs::symlink_metadata(self.upper().join(relative)).is_ok()
+    }
+
+    /// Whether a lower layer would show `relative` if the upper directory
+    /// did not have it
+    pub(crate) fn below(&self, relative: &Path) -> bool {
+        let opaque = relative
+            .parent()
+            .is_some_and(|parent| self.upper().join(parent).join(OPAQUE_MARKER).exists());
+        !opaque && self.find(relative, &self.layers[1..]).is_some()
+    }
+
+    fn find(&self, relative: &Path, layers: &[PathBuf]) -> Option<PathBuf> {
+        for layer in layers {
+            match Self::lookup(layer, relative) {
+                Lookup::Found(path) => return Some(path),
+                Lookup::Absent => {}
+                Lookup::Hidden => return None,
+            }
+        }
+        None
+    }
+
+    fn lookup(layer: &Path, relative: &Path) -> Lookup {
+        let mut dir = layer.to_path_buf();
+        let mut opaque = false;
+        let mut components = relative.iter().peekable();
+        while let Some(name) = components.next() {
+            let next = dir.join(name);
+            match fs::symlink_metadata(&next) {
+                Ok(metadata) if metadata.is_dir() => {
+                    opaque = next.join(OPAQUE_MARKER).exists();
+                }
+                // A file here hides any directory of that name below
+                Ok(_) if components.peek().is_some() => return Lookup::Hidden,
+                Ok(_) => {}
+                Err(_) if opaque || dir.join(whiteout_name(name)).exists() => {
+                    return Lookup::Hidden;
+                }
+                Err(_) => return Lookup::Absent,
+            }
+            dir = next;
+        }
+        Lookup::Found(dir)
+    }
+
+    /// Copy `relative` and any missing parent directories into the upper
+    /// directory, returning what was copied, parents first
+    pub(crate) fn copy_up(&self, relative: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut missing = Vec::new();
+        let mut current = Some(relative);
+        while let Some(path) = current.filter(|path| !path.as_os_str().is_empty()) {
+            if self.in_upper(path) {
+                break;
+            }
+            missing.push(path.to_path_buf());
+            current = path.parent();
+        }
+
+        missing.reverse();
+        for path in &missing {
+            self.copy_one(path)?;
+        }
+        Ok(missing)
+    }
+
+    /// Copy a single entry whose parent is already in the upper directory.
+    /// Files go through a hidden temporary name, so a failed copy never
+    /// shadows the original.
+    fn copy_one(&self, relative: &Path) -> io::Result<()> {
+        let lower = self
+            .find(relative, &self.layers[1..])
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+        let metadata = fs::symlink_metadata(&lower)?;
+        let target = self.upper().join(relative);
+        let name = relative.file_name().unwrap_or_default();
+        let temp = target.with_file_name(whiteout_name(&whiteout_name(name)));
+
+        // The mode is set after the owner, since chown clears setuid bits
+        let permissions = fs::Permissions::from_mode(metadata.mode() & 0o7777);
+        let file_type = metadata.file_type();
+        if file_type.is_dir() {
+            fs::create_dir(&target)?;
+            copy_owner_and_times(&target, &metadata);
+            return fs::set_permissions(&target, permissions);
+        }
+
+        let copied = if file_type.is_symlink() {
+            symlink(fs::read_link(&lower)?, &temp).map(|_| copy_owner_and_times(&temp, &metadata))
+        } else if file_type.is_file() {
+            fs::copy(&lower, &temp).and_then(|_| {
+                copy_owner_and_times(&temp, &metadata);
+                fs::set_permissions(&temp, permissions)
+            })
+        } else {
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+        };
+        let result = copied.and_then(|_| fs::rename(&temp, &target));
+        if result.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+        result
+    }
+
+    /// Finish creating `relative` in the upper directory: hide what a
+    /// lower layer has below a new directory and drop any whiteout left by
+    /// an earlier removal, which the new entry already shadows
+    pub(crate) fn finish_entry(&self, relative: &Path, is_dir: bool) -> io::Result<()> {
+        if is_dir && self.below(relative) {
+            self.make_opaque(relative)?;
+        }
+        match self.whiteout_path(relative).map(fs::remove_file) {
+            Some(Err(e)) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn whiteout_path(&self, relative: &Path) -> Option<PathBuf> {
+        let name = relative.file_name()?;
+        Some(
+            self.upper()
+                .join(relative)
+                .with_file_name(whiteout_name(name)),
+        )
+    }
+
+    /// Hide `relative` in the lower layers, if any of them has it
+    pub(crate) fn whiteout(&self, relative: &Path) -> io::Result<()> {
+        if !self.below(relative) {
+            return Ok(());
+        }
+        if let Some(parent) = relative.parent() {
+            self.copy_up(parent)?;
+        }
+        match self.whiteout_path(relative) {
+            Some(whiteout) => fs::File::create(whiteout).map(drop),
+            None => Ok(()),
+        }
+    }
+
+    /// Hide the lower layers' contents of the upper directory `relative`
+    fn make_opaque(&self, relative: &Path) -> io::Result<()> {
+        fs::File::create(self.upper().join(relative).join(OPAQUE_MARKER)).map(drop)
+    }
+
+    /// Merged listing of the directory `relative`, upper entries first
+    pub(crate) fn list(&self, relative: &Path) -> io::Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        // Names listed or whited out by the layers above
+        let mut seen = HashSet::new();
+        let mut found = false;
+
+        for layer in &self.layers {
+            let dir = match Self::lookup(layer, relative) {
+                Lookup::Found(dir) => dir,
+                Lookup::Absent => continue,
+                Lookup::Hidden => break,
+            };
+            let metadata = fs::symlink_metadata(&dir)?;
+            if !metadata.is_dir() {
+                if found {
+                    break;
+                }
+                return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+            }
+            found = true;
+
+            let mut opaque = false;
+            let mut names = Vec::new();
+            for entry in fs::read_dir(&dir)?.flatten() {
+                let name = entry.file_name();
+                if name == OPAQUE_MARKER {
+                    opaque = true;
+                } else if let Some(hidden) = name.as_bytes().strip_prefix(WHITEOUT_PREFIX) {
+                    // Also covers temporary copy-up names, which hide nothing
+                    names.push(OsStr::from_bytes(hidden).to_os_string());
+                } else {
+                    names.push(name.clone());
+                    if seen.contains(&name) {
+                        continue;
+                    }
+                    let Ok(file_type) = entry.file_type() else {
+                        continue;
+                    };
+                    entries.push(Entry {
+                        name,
+                        file_type,
+                        backing: (metadata.dev(), entry.ino()),
+                    });
+                }
+            }
+            if opaque {
+                break;
+            }
+            seen.extend(names);
+        }
+
+        if !found {
+            return Err(io::Error::from_raw_os_error(libc::ENOENT));
+        }
+        Ok(entries)
+    }
+
+    /// Remove the directory `relative`, which must look empty through the
+    /// overlay, leaving a whiteout if a lower layer has it too
+    pub(crate) fn remove_dir(&self, relative: &Path) -> io::Result<()> {
+        if !self.list(relative)?.is_empty() {
+            return Err(io::Error::from_raw_os_error(libc::ENOTEMPTY));
+        }
+
+        if self.in_upper(relative) {
+            let upper = self.upper().join(relative);
+            // Only whiteouts and markers are left in it
+            for entry in fs::read_dir(&upper)?.flatten() {
+                fs::remove_file(entry.path())?;
+            }
+            fs::remove_dir(&upper)?;
+        }
+        self.whiteout(relative)
+    }
+}
+
+/// Give a copied-up entry the original's owner and timestamps, as far as
+/// the daemon is allowed to
+fn copy_owner_and_times(path: &Path, metadata: &fs::Metadata) {
+    let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+        return;
+    };
+    let times = [
+        libc::timespec {
+            tv_sec: metadata.atime() as libc::time_t,
+            tv_nsec: metadata.atime_nsec() as libc::c_long,
+        },
+        libc::timespec {
+            tv_sec: metadata.mtime() as libc::time_t,
+            tv_nsec: metadata.mtime_nsec() as libc::c_long,
+        },
+    ];
+    unsafe {
+        libc::lchown(path.as_ptr(), metadata.uid(), metadata.gid());
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        );
+    }
+}
@@ -0,0 +1,53 @@
+//! Worker threads serving reads and writes off the session thread
+//!
+//! fuser reads requests from the kernel on a single thread. The blocking
+//! I/O of reads and writes is handed to a fixed set of workers, which send
+//! the reply themselves, so requests on different files overlap instead of
+//! queueing behind each other. Every other request, lookups and getattr
+//! included, stays on the session thread: those need the filesystem
+//! itself, which fuser only lends out for one request at a time.
+
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+pub(crate) struct WorkerPool {
+    sender: Mutex<Sender<Job>>,
+}
+
+impl WorkerPool {
+    /// Start `threads` workers, which exit once the pool is dropped and
+    /// the jobs already queued are done
+    pub(crate) fn new(threads: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for i in 0..threads {
+            let receiver = Arc::clone(&receiver);
+            std::thread::Builder::new()
+                .name(format!("fuse-worker-{}", i))
+                .spawn(move || work(&receiver))
+                .expect("Failed to spawn worker thread");
+        }
+        WorkerPool {
+            sender: Mutex::new(sender),
+        }
+    }
+
+    /// Queue `job` for the next free worker
+    pub(crate) fn execute(&self, job: impl FnOnce() + Send + 'static) {
+        // The workers only stop once the sender is dropped with the pool
+        let _ = self.sender.lock().unwrap().send(Box::new(job));
+    }
+}
+
+fn work(receiver: &Mutex<Receiver<Job>>) {
+    loop {
+        // The lock is released before the job runs
+        let job = receiver.lock().unwrap().recv();
+        match job {
+            Ok(job) => job(),
+            Err(_) => return,
+        }
+    }
+}
@@ -0,0 +1,83 @@
+//! Per-operation usage counters
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Snapshot of how often each operation has been served
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FsStats {
+    pub lookup: u64,
+    pub getattr: u64,
+    pub setattr: u64,
+    pub readdir: u64,
+    pub open: u64,
+    pub create: u64,
+    pub read: u64,
+    /// Bytes returned by successful reads
+    pub read_bytes: u64,
+    pub write: u64,
+    /// Bytes accepted by successful writes
+    pub write_bytes: u64,
+    pub mkdir: u64,
+    pub unlink: u64,
+    pub rmdir: u64,
+    pub rename: u64,
+}
+
+/// Live counters, updated without locking on every request
+#[derive(Default)]
+pub(crate) struct Counters {
+    pub(crate) lookup: AtomicU64,
+    pub(crate) getattr: AtomicU64,
+    pub(crate) setattr: AtomicU64,
+    pub(crate) readdir: AtomicU64,
+    pub(crate) open: AtomicU64,
+    pub(crate) create: AtomicU64,
+    pub(crate) read: AtomicU64,
+    pub(crate) read_bytes: AtomicU64,
+    pub(crate) write: AtomicU64,
+    pub(crate) write_bytes: AtomicU64,
+    pub(crate) mkdir: AtomicU64,
+    pub(crate) unlink: AtomicU64,
+    pub(crate) rmdir: AtomicU64,
+    pub(crate) rename: AtomicU64,
+}
+
+/// Bump a counter by `n`
+pub(crate) fn add(counter: &AtomicU64, n: u64) {
+    counter.fetch_add(n, Ordering::Relaxed);
+}
+
+impl Counters {
+    fn snapshot(&self) -> FsStats {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        FsStats {
+            lookup: get(&self.lookup),
+            getattr: get(&self.getattr),
+            setattr: get(&self.setattr),
+            readdir: get(&self.readdir),
+            open: get(&self.open),
+            create: get(&self.create),
+            read: get(&self.read),
+            read_bytes: get(&self.read_bytes),
+            write: get(&self.write),
+            write_bytes: get(&self.write_bytes),
+            mkdir: get(&self.mkdir),
+            unlink: get(&self.unlink),
+            rmdir: get(&self.rmdir),
+            rename: get(&self.rename),
+        }
+    }
+}
+
+/// Handle for reading the counters of a filesystem after it has been
+/// handed to a mount session
+#[derive(Clone)]
+pub struct StatsHandle(pub(crate) Arc<Counters>);
+
+impl StatsHandle {
+    /// Current values of every counter
+    pub fn stats(&self) -> FsStats {
+        self.0.snapshot()
+    }
+}
@@ -0,0 +1,112 @@
+//! Rate limits on reads and writes
+//!
+//! Each limit is a token bucket holding up to one second of its rate.
+//! Writes take their cost out of the bucket as they arrive, reads once
+//! they are done and their length is known, and either may take it below
+//! zero, then sleep until the refill has paid off the debt, so a request
+//! larger than the bucket is delayed rather than refused. Bucket locks are
+//! only held for the arithmetic, never while sleeping.
+//!
+//! The sleep happens in the thread serving the request. With a single
+//! thread that is the session thread, so a delayed request holds up every
+//! other one, throttled or not.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct Bucket {
+    /// Tokens added per second, and the most the bucket holds
+    rate: f64,
+    /// Tokens available, negative while in debt, as of the instant
+    state: Mutex<(f64, Instant)>,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        let rate = rate as f64;
+        Bucket {
+            rate,
+            state: Mutex::new((rate, Instant::now())),
+        }
+    }
+
+    /// How long to wait before spending `cost` tokens
+    fn take(&self, cost: u64) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last) = &mut *state;
+        let now = Instant::now();
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.rate).min(self.rate);
+        *last = now;
+        *tokens -= cost as f64;
+        if *tokens < 0.0 {
+            Duration::from_secs_f64(-*tokens / self.rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+pub(crate) struct Throttle {
+    read_bytes: Option<Bucket>,
+    write_bytes: Option<Bucket>,
+    ops: Option<Bucket>,
+}
+
+impl Throttle {
+    /// Limits in bytes and operations per second, None when there are none
+    pub(crate) fn new(
+        read_bps: Option<u64>,
+        write_bps: Option<u64>,
+        iops: Option<u64>,
+    ) -> Option<Self> {
+        if read_bps.is_none() && write_bps.is_none() && iops.is_none() {
+            return None;
+        }
+        Some(Throttle {
+            read_bytes: read_bps.map(Bucket::new),
+            write_bytes: write_bps.map(Bucket::new),
+            ops: iops.map(Bucket::new),
+        })
+    }
+
+    /// Wait until a read that returned `bytes` fits the limits, before it
+    /// is replied to
+    pub(crate) fn read(&self, bytes: u64) {
+        self.wait(&self.read_bytes, bytes);
+    }
+
+    /// Wait until a write of `bytes` fits the limits
+    pub(crate) fn write(&self, bytes: u64) {
+        self.wait(&self.write_bytes, bytes);
+    }
+
+    fn wait(&self, bytes: &Option<Bucket>, cost: u64) {
+        let delay = [(bytes, cost), (&self.ops, 1)]
+            .into_iter()
+            .filter_map(|(bucket, cost)| bucket.as_ref().map(|bucket| bucket.take(cost)))
+            .max()
+            .unwrap_or_default();
+        if !delay.is_zero() {
+            std::thread::sleep(delay);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_delays_requests_past_the_rate() {
+        let bucket = Bucket::new(1000);
+        // A full bucket covers a second's worth at once
+        assert_eq!(bucket.take(1000), Duration::ZERO);
+        // Then each request waits for its share of the refill, and later
+        // ones queue behind the debt
+        let first = bucket.take(500);
+        let second = bucket.take(500);
+        assert!(first > Duration::ZERO && first <= Duration::from_millis(500));
+        assert!(second >= first + Duration::from_millis(400));
+        assert!(second <= Duration::from_millis(1000));
+    }
+}
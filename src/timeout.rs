@@ -0,0 +1,169 @@
+//! Deadlines for blocking I/O on the source
+//!
+//! The session serves one request at a time, so a read that never returns
+//! from a hung network filesystem would stall the whole mount. Timed
+//! operations run on a thread of their own instead; when the deadline
+//! passes the request fails with ETIMEDOUT and the thread is left to finish
+//! in the background. Threads wait for the next operation once theirs is
+//! done, so there are only ever as many as operations that ran at once.
+
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::time::Duration;
+
+/// Timed-out operations that may still be running before new ones fail
+/// right away rather than piling up more stuck threads
+const MAX_STUCK: usize = 64;
+
+/// Where a timed operation has got to, shared with its thread
+enum State<T> {
+    Running,
+    Done(io::Result<T>),
+    /// The request gave up waiting, so the thread cleans up after itself
+    Abandoned,
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// An operation for an idle thread, with the way to hand it the next one
+struct Task {
+    job: Job,
+    sender: Sender<Task>,
+}
+
+/// Threads done with their operation, waiting for another
+type Idle = Mutex<Vec<Sender<Task>>>;
+
+pub(crate) struct Deadline {
+    timeout: Duration,
+    /// Abandoned operations still running
+    stuck: Arc<AtomicUsize>,
+    /// Dropping it with the deadline lets the idle threads exit
+    idle: Arc<Idle>,
+}
+
+impl Deadline {
+    pub(crate) fn new(timeout: Duration) -> Self {
+        Deadline {
+            timeout,
+            stuck: Arc::new(AtomicUsize::new(0)),
+            idle: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Run `op`, failing with ETIMEDOUT if it takes longer than the timeout.
+    /// A timed-out write may still land once the source recovers. Fails
+    /// with EAGAIN if no thread can be started for it.
+    pub(crate) fn run<T, F>(&self, op: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> io::Result<T> + Send + 'static,
+    {
+        if self.stuck.load(Ordering::SeqCst) >= MAX_STUCK {
+            return Err(io::Error::from_raw_os_error(libc::ETIMEDOUT));
+        }
+
+        let shared = Arc::new((Mutex::new(State::Running), Condvar::new()));
+        let worker = (Arc::clone(&shared), Arc::clone(&self.stuck));
+        self.start(Box::new(move || {
+            let ((state, done), stuck) = (&*worker.0, worker.1);
+            let result = op();
+            let mut state = state.lock().unwrap();
+            if matches!(*state, State::Abandoned) {
+                stuck.fetch_sub(1, Ordering::SeqCst);
+            } else {
+                *state = State::Done(result);
+                done.notify_one();
+            }
+        }))?;
+
+        let (state, done) = &*shared;
+        let mut state = done
+            .wait_timeout_while(state.lock().unwrap(), self.timeout, |state| {
+                matches!(state, State::Running)
+            })
+            .unwrap()
+            .0;
+        match std::mem::replace(&mut *state, State::Abandoned) {
+            State::Done(result) => result,
+            _ => {
+                self.stuck.fetch_add(1, Ordering::SeqCst);
+                Err(io::Error::from_raw_os_error(libc::ETIMEDOUT))
+            }
+        }
+    }
+
+    /// Hand `job` to an idle thread, or a new one if none is waiting,
+    /// failing with EAGAIN if the thread cannot be created
+    fn start(&self, job: Job) -> io::Result<()> {
+        let idle = self.idle.lock().unwrap().pop();
+        // A thread only goes missing if a job panicked on it
+        let job = match idle {
+            Some(sender) => match sender.send(Task {
+                job,
+                sender: sender.clone(),
+            }) {
+                Ok(()) => return Ok(()),
+                Err(mpsc::SendError(task)) => task.job,
+            },
+            None => job,
+        };
+
+        let (sender, receiver) = mpsc::channel();
+        let idle = Arc::downgrade(&self.idle);
+        std::thread::Builder::new()
+            .name("fuse-timed".to_string())
+            .spawn(move || serve(job, sender, &receiver, &idle))
+            .map(|_| ())
+            .map_err(|_| io::Error::from_raw_os_error(libc::EAGAIN))
+    }
+}
+
+/// Run `job`, then whatever is handed over next, until the deadline is gone
+fn serve(job: Job, sender: Sender<Task>, receiver: &Receiver<Task>, idle: &Weak<Idle>) {
+    let (mut job, mut sender) = (job, sender);
+    loop {
+        job();
+        match idle.upgrade() {
+            Some(idle) => idle.lock().unwrap().push(sender),
+            None => return,
+        }
+        match receiver.recv() {
+            Ok(task) => (job, sender) = (task.job, task.sender),
+            Err(_) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_threads_are_reused() {
+        let deadline = Deadline::new(Duration::from_secs(5));
+        let first = deadline.run(|| Ok(std::thread::current().id())).unwrap();
+        // The thread is only idle once it is back in the list
+        while deadline.idle.lock().unwrap().is_empty() {
+            std::thread::yield_now();
+        }
+        let second = deadline.run(|| Ok(std::thread::current().id())).unwrap();
+        assert_eq!(first, second);
+        assert_ne!(first, std::thread::current().id());
+    }
+
+    #[test]
+    fn test_timed_out_operations_fail() {
+        let deadline = Deadline::new(Duration::from_millis(50));
+        let err = deadline
+            .run(|| {
+                std::thread::sleep(Duration::from_millis(500));
+                Ok(())
+            })
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ETIMEDOUT));
+        assert_eq!(deadline.run(|| Ok(1)).unwrap(), 1);
+    }
+}
@@ -0,0 +1,209 @@
+//! Kernel cache invalidation for changes made directly in the source
+//!
+//! An inotify watch on every source directory reports changes, which are
+//! passed on to the kernel as FUSE invalidation notices so it drops cached
+//! attributes, pages and dentries without waiting for their TTLs.
+
+use crate::cache::{AttrCache, OwnWrites};
+use crate::inode::InodeTable;
+use fuser::Notifier;
+use log::{debug, warn};
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+
+/// Events that change what the kernel may have cached
+const WATCH_MASK: u32 = libc::IN_MODIFY
+    | libc::IN_ATTRIB
+    | libc::IN_CREATE
+    | libc::IN_DELETE
+    | libc::IN_MOVED_FROM
+    | libc::IN_MOVED_TO
+    | libc::IN_ONLYDIR
+    | libc::IN_DONT_FOLLOW;
+/// Events that add or remove a name in the watched directory
+const ENTRY_MASK: u32 = libc::IN_CREATE | libc::IN_DELETE | libc::IN_MOVED_FROM | libc::IN_MOVED_TO;
+/// How often the watcher checks whether the filesystem is still mounted
+const POLL_TIMEOUT_MS: libc::c_int = 500;
+/// Size of the fixed part of struct inotify_event
+const EVENT_HEADER_SIZE: usize = std::mem::size_of::<libc::inotify_event>();
+
+struct Watcher {
+    fd: OwnedFd,
+    source: PathBuf,
+    /// Watch descriptor to the directory it watches, relative to the source
+    dirs: HashMap<i32, PathBuf>,
+    inodes: Weak<InodeTable>,
+    attr_cache: Weak<AttrCache>,
+    own_writes: Arc<OwnWrites>,
+    notifier: Notifier,
+    /// Whether running out of watches has been reported already
+    exhausted: bool,
+}
+
+/// Start watching `source` in a background thread, which exits once the
+/// inode table is dropped together with the filesystem
+pub(crate) fn spawn(
+    source: &Path,
+    inodes: &Arc<InodeTable>,
+    attr_cache: &Arc<AttrCache>,
+    own_writes: Arc<OwnWrites>,
+    notifier: Notifier,
+) -> io::Result<()> {
+    let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut watcher = Watcher {
+        fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        source: source.to_path_buf(),
+        dirs: HashMap::new(),
+        inodes: Arc::downgrade(inodes),
+        attr_cache: Arc::downgrade(attr_cache),
+        own_writes,
+        notifier,
+        exhausted: false,
+    };
+    watcher.add_tree(Path::new(""));
+    debug!("Watching {} source directories", watcher.dirs.len());
+
+    std::thread::spawn(move || watcher.run());
+    Ok(())
+}
+
+impl Watcher {
+    /// Watch `relative` and every directory below it
+    fn add_tree(&mut self, relative: &Path) {
+        let mut pending = vec![relative.to_path_buf()];
+        while let Some(dir) = pending.pop() {
+            let real_path = self.source.join(&dir);
+            if !self.add_watch(&real_path, &dir) {
+                continue;
+            }
+            let Ok(entries) = fs::read_dir(&real_path) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                if entry.file_type().is_ok_and(|kind| kind.is_dir()) {
+                    pending.push(dir.join(entry.file_name()));
+                }
+            }
+        }
+    }
+
+    fn add_watch(&mut self, real_path: &Path, relative: &Path) -> bool {
+        let Ok(path) = std::ffi::CString::new(real_path.as_os_str().as_bytes()) else {
+            return false;
+        };
+        let wd = unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), path.as_ptr(), WATCH_MASK) };
+        if wd < 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::ENOSPC) && !self.exhausted {
+                warn!("Out of inotify watches, some source changes will go unnoticed");
+                self.exhausted = true;
+            }
+            return false;
+        }
+        // Watching a directory again, after a move, returns the same wd
+        self.dirs.insert(wd, relative.to_path_buf());
+        true
+    }
+
+    fn run(mut self) {
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            let mut pollfd = libc::pollfd {
+                fd: self.fd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let ready = unsafe { libc::poll(&mut pollfd, 1, POLL_TIMEOUT_MS) };
+            let (Some(inodes), Some(attr_cache)) =
+                (self.inodes.upgrade(), self.attr_cache.upgrade())
+            else {
+                return;
+            };
+            if ready <= 0 {
+                continue;
+            }
+
+            let read = unsafe {
+                libc::read(
+                    self.fd.as_raw_fd(),
+                    buffer.as_mut_ptr().cast(),
+                    buffer.len(),
+                )
+            };
+            if read <= 0 {
+                continue;
+            }
+            self.handle_events(&buffer[..read as usize], &inodes, &attr_cache);
+        }
+    }
+
+    /// Invalidate whatever a batch of events touched, once per inode and name
+    fn handle_events(&mut self, mut events: &[u8], inodes: &InodeTable, attr_cache: &AttrCache) {
+        // Inode to whether its pages changed too
+        let mut changed: HashMap<u64, bool> = HashMap::new();
+        let mut entries: HashSet<(u64, OsString)> = HashSet::new();
+
+        while events.len() >= EVENT_HEADER_SIZE {
+            let field =
+                |offset: usize| u32::from_ne_bytes(events[offset..offset + 4].try_into().unwrap());
+            let (wd, mask, len) = (field(0) as i32, field(4), field(12) as usize);
+            let Some(name) = events.get(EVENT_HEADER_SIZE..EVENT_HEADER_SIZE + len) else {
+                break;
+            };
+            let name = OsStr::from_bytes(name.split(|&b| b == 0).next().unwrap_or_default());
+            events = &events[EVENT_HEADER_SIZE + len..];
+
+            if mask & libc::IN_Q_OVERFLOW != 0 {
+                warn!("Source change events overflowed, some went unnoticed");
+                continue;
+            }
+            if mask & libc::IN_IGNORED != 0 {
+                self.dirs.remove(&wd);
+                continue;
+            }
+            let Some(dir) = self.dirs.get(&wd).cloned() else {
+                continue;
+            };
+            let path = dir.join(name);
+            if mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 && mask & libc::IN_ISDIR != 0 {
+                self.add_tree(&path);
+            }
+
+            if let Some(inode) = inodes.get_inode(&path) {
+                *changed.entry(inode).or_default() |= mask & libc::IN_MODIFY != 0;
+            }
+            if mask & ENTRY_MASK != 0 && !name.is_empty() {
+                if let Some(parent) = inodes.get_inode(&dir) {
+                    changed.entry(parent).or_default();
+                    entries.insert((parent, name.to_os_string()));
+                }
+            }
+        }
+
+        for (inode, data) in changed {
+            attr_cache.invalidate(inode);
+            // Pages written through the mount are already current
+            let data = data && !self.own_writes.take(inode);
+            // A negative offset keeps cached pages, zero drops them all
+            let offset = if data { 0 } else { -1 };
+            if let Err(e) = self.notifier.inval_inode(inode, offset, 0) {
+                debug!("inval_inode {}: {}", inode, e);
+            }
+        }
+        for (parent, name) in entries {
+            if let Err(e) = self.notifier.inval_entry(parent, &name) {
+                debug!("inval_entry {} {:?}: {}", parent, name, e);
+            }
+        }
+    }
+}
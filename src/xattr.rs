@@ -0,0 +1,153 @@
+//! Extended attribute syscalls that operate on the link itself rather than its target
+
+use crate::path_to_cstring;
+use std::ffi::{CString, OsStr};
+use std::fs::File;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+fn name_to_cstring(name: &OsStr) -> io::Result<CString> {
+    CString::new(name.as_bytes()).map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))
+}
+
+/// Convert a libc return value into a byte count
+fn check(ret: libc::ssize_t) -> io::Result<usize> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+/// Read an attribute value into `buf`; an empty `buf` returns the required size
+pub fn get(path: &Path, name: &OsStr, buf: &mut [u8]) -> io::Result<usize> {
+    let path = path_to_cstring(path)?;
+    let name = name_to_cstring(name)?;
+
+    #[cfg(target_os = "linux")]
+    let ret = unsafe {
+        libc::lgetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+        )
+    };
+    #[cfg(target_os = "macos")]
+    let ret = unsafe {
+        libc::getxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+            0,
+            libc::XATTR_NOFOLLOW,
+        )
+    };
+
+    check(ret)
+}
+
+/// Like [`get`], through an open descriptor
+pub fn fget(file: &File, name: &OsStr, buf: &mut [u8]) -> io::Result<usize> {
+    let name = name_to_cstring(name)?;
+
+    #[cfg(target_os = "linux")]
+    let ret = unsafe {
+        libc::fgetxattr(
+            file.as_raw_fd(),
+            name.as_ptr(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+        )
+    };
+    #[cfg(target_os = "macos")]
+    let ret = unsafe {
+        libc::fgetxattr(
+            file.as_raw_fd(),
+            name.as_ptr(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+            0,
+            0,
+        )
+    };
+
+    check(ret)
+}
+
+/// Set an attribute value, `flags` being XATTR_CREATE/XATTR_REPLACE.
+/// `position` is the offset into a macOS resource fork and always 0
+/// elsewhere.
+pub fn set(path: &Path, name: &OsStr, value: &[u8], flags: i32, position: u32) -> io::Result<()> {
+    let path = path_to_cstring(path)?;
+    let name = name_to_cstring(name)?;
+
+    #[cfg(target_os = "linux")]
+    if position != 0 {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+    #[cfg(target_os = "linux")]
+    let ret = unsafe {
+        libc::lsetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            flags,
+        )
+    };
+    #[cfg(target_os = "macos")]
+    let ret = unsafe {
+        libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            position,
+            flags | libc::XATTR_NOFOLLOW,
+        )
+    };
+
+    check(ret as libc::ssize_t).map(|_| ())
+}
+
+/// List attribute names into `buf`; an empty `buf` returns the required size
+pub fn list(path: &Path, buf: &mut [u8]) -> io::Result<usize> {
+    let path = path_to_cstring(path)?;
+
+    #[cfg(target_os = "linux")]
+    let ret = unsafe {
+        libc::llistxattr(
+            path.as_ptr(),
+            buf.as_mut_ptr() as *mut libc::c_char,
+            buf.len(),
+        )
+    };
+    #[cfg(target_os = "macos")]
+    let ret = unsafe {
+        libc::listxattr(
+            path.as_ptr(),
+            buf.as_mut_ptr() as *mut libc::c_char,
+            buf.len(),
+            libc::XATTR_NOFOLLOW,
+        )
+    };
+
+    check(ret)
+}
+
+/// Remove an attribute
+pub fn remove(path: &Path, name: &OsStr) -> io::Result<()> {
+    let path = path_to_cstring(path)?;
+    let name = name_to_cstring(name)?;
+
+    #[cfg(target_os = "linux")]
+    let ret = unsafe { libc::lremovexattr(path.as_ptr(), name.as_ptr()) };
+    #[cfg(target_os = "macos")]
+    let ret = unsafe { libc::removexattr(path.as_ptr(), name.as_ptr(), libc::XATTR_NOFOLLOW) };
+
+    check(ret as libc::ssize_t).map(|_| ())
+}
@@ -1,7 +1,8 @@
 mod common;
 
-use common::{setup_test_dirs, MountGuard};
+use common::{get_xattr, is_fuse_mount, set_xattr, setup_test_dirs, MountGuard};
 use std::fs::{self, File};
+use std::io::Write;
 
 #[test]
 fn test_read_nonexistent_file() {
@@ -147,6 +148,120 @@ fn test_create_directory_that_exists() {
     assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
 }
 
+#[test]
+fn test_create_directory_in_missing_parent() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::create_dir(source.join("parent")).expect("Failed to create directory");
+    fs::create_dir(source.join("locked")).expect("Failed to create directory");
+    fs::set_permissions(source.join("locked"), fs::Permissions::from_mode(0o555))
+        .expect("Failed to chmod directory");
+
+    let guard = MountGuard::without_dac_override(&source, &mountpoint);
+
+    // The kernel still has the parent cached, so the mkdir reaches the source
+    assert!(mountpoint.join("parent").is_dir());
+    fs::remove_dir(source.join("parent")).expect("Failed to remove directory");
+    let result = fs::create_dir(mountpoint.join("parent/child"));
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::ENOENT));
+
+    let result = fs::create_dir(mountpoint.join("locked/child"));
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EACCES));
+
+    drop(guard);
+    fs::set_permissions(source.join("locked"), fs::Permissions::from_mode(0o755))
+        .expect("Failed to chmod directory");
+}
+
+#[test]
+fn test_exclusive_create_existing_file() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    fs::write(mountpoint.join("lockfile"), "held").expect("Failed to create file");
+
+    // O_CREAT | O_EXCL over an existing file must fail
+    let result = File::options()
+        .write(true)
+        .create_new(true)
+        .open(mountpoint.join("lockfile"));
+    assert!(
+        result.is_err(),
+        "Exclusive create succeeded on existing file"
+    );
+    assert_eq!(
+        result.unwrap_err().kind(),
+        std::io::ErrorKind::AlreadyExists
+    );
+
+    // The existing file is untouched
+    assert_eq!(fs::read_to_string(source.join("lockfile")).unwrap(), "held");
+
+    // A file created behind the mount's back is also detected
+    fs::write(source.join("external"), "external").expect("Failed to write test file");
+    let result = File::options()
+        .write(true)
+        .create_new(true)
+        .open(mountpoint.join("external"));
+    assert_eq!(
+        result.unwrap_err().kind(),
+        std::io::ErrorKind::AlreadyExists
+    );
+}
+
+#[test]
+fn test_create_in_unwritable_directory() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::create_dir(source.join("locked")).expect("Failed to create directory");
+    fs::write(source.join("locked/existing"), "").expect("Failed to write test file");
+    fs::set_permissions(source.join("locked"), fs::Permissions::from_mode(0o555))
+        .expect("Failed to chmod directory");
+
+    let guard = MountGuard::without_dac_override(&source, &mountpoint);
+    let locked = mountpoint.join("locked");
+
+    // Permission denied must not look like a missing file
+    let result = File::create(locked.join("new.txt"));
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EACCES));
+
+    let result = fs::remove_file(locked.join("existing"));
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EACCES));
+
+    let result = std::os::unix::fs::symlink("target", locked.join("link"));
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EACCES));
+
+    let result = fs::rename(locked.join("existing"), mountpoint.join("moved"));
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EACCES));
+
+    drop(guard);
+    fs::set_permissions(source.join("locked"), fs::Permissions::from_mode(0o755))
+        .expect("Failed to chmod directory");
+}
+
+#[test]
+fn test_errors_keep_their_cause() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("file.txt"), "").expect("Failed to write test file");
+    fs::create_dir(source.join("dir")).expect("Failed to create directory");
+    fs::write(source.join("dir/inner"), "").expect("Failed to write test file");
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    // A regular file used as a directory
+    let result = fs::metadata(mountpoint.join("file.txt/child"));
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::ENOTDIR));
+
+    let result = fs::remove_dir(mountpoint.join("dir"));
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::ENOTEMPTY));
+
+    let result = fs::remove_dir(mountpoint.join("file.txt"));
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::ENOTDIR));
+}
+
 #[test]
 fn test_read_symlink_to_nonexistent_target() {
     let (source, mountpoint, _temp_dir) = setup_test_dirs();
@@ -184,3 +299,387 @@ fn test_getattr_nonexistent_file() {
     let err = result.unwrap_err();
     assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
 }
+
+#[test]
+fn test_write_reports_enospc() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+
+    // Writes to /dev/full always fail with ENOSPC
+    std::os::unix::fs::symlink("/dev/full", source.join("full")).expect("Failed to create symlink");
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    let mut file = File::options()
+        .write(true)
+        .open(mountpoint.join("full"))
+        .expect("Failed to open file");
+    let result = file.write_all(b"no space left");
+    assert!(
+        result.is_err(),
+        "Expected error when writing to a full device"
+    );
+
+    let err = result.unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOSPC));
+}
+
+#[test]
+fn test_read_only_rejects_write() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("existing.txt"), "original").expect("Failed to write test file");
+
+    let _guard = MountGuard::with_args(&source, &mountpoint, &["--read-only"]);
+
+    // Reading still works
+    assert_eq!(
+        fs::read_to_string(mountpoint.join("existing.txt")).unwrap(),
+        "original"
+    );
+
+    let result = fs::OpenOptions::new()
+        .write(true)
+        .open(mountpoint.join("existing.txt"))
+        .and_then(|mut file| file.write_all(b"changed"));
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EROFS));
+
+    let result = fs::write(mountpoint.join("new.txt"), "data");
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EROFS));
+
+    assert_eq!(
+        fs::read_to_string(source.join("existing.txt")).unwrap(),
+        "original"
+    );
+    assert!(!source.join("new.txt").exists());
+}
+
+#[test]
+fn test_read_only_rejects_unlink() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("keep.txt"), "data").expect("Failed to write test file");
+
+    let _guard = MountGuard::with_args(&source, &mountpoint, &["--read-only"]);
+
+    let result = fs::remove_file(mountpoint.join("keep.txt"));
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EROFS));
+    assert!(source.join("keep.txt").exists());
+}
+
+#[test]
+fn test_read_only_rejects_metadata_changes() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("keep.txt"), "data").expect("Failed to write test file");
+    fs::create_dir(source.join("dir")).expect("Failed to create directory");
+
+    let _guard = MountGuard::with_args(&source, &mountpoint, &["--read-only"]);
+
+    use std::os::unix::fs::PermissionsExt;
+    let file = mountpoint.join("keep.txt");
+    let attempts: Vec<(&str, std::io::Result<()>)> = vec![
+        (
+            "chmod",
+            fs::set_permissions(&file, fs::Permissions::from_mode(0o600)),
+        ),
+        ("truncate", {
+            let path = std::ffi::CString::new(file.to_str().unwrap()).unwrap();
+            match unsafe { libc::truncate(path.as_ptr(), 0) } {
+                0 => Ok(()),
+                _ => Err(std::io::Error::last_os_error()),
+            }
+        }),
+        ("mkdir", fs::create_dir(mountpoint.join("new_dir"))),
+        ("rmdir", fs::remove_dir(mountpoint.join("dir"))),
+        ("rename", fs::rename(&file, mountpoint.join("moved.txt"))),
+        (
+            "symlink",
+            std::os::unix::fs::symlink("keep.txt", mountpoint.join("link")),
+        ),
+        ("link", fs::hard_link(&file, mountpoint.join("hard.txt"))),
+        ("setxattr", set_xattr(&file, "user.test", b"value")),
+    ];
+    for (operation, result) in attempts {
+        let err = result.expect_err(operation);
+        assert_eq!(err.raw_os_error(), Some(libc::EROFS), "{}", operation);
+    }
+
+    assert_eq!(fs::read_to_string(source.join("keep.txt")).unwrap(), "data");
+    assert!(source.join("dir").is_dir());
+}
+
+/// A size-limited tmpfs mounted over a directory for the life of the value
+struct SmallTmpfs(std::path::PathBuf);
+
+impl SmallTmpfs {
+    /// Mount a tmpfs over `path`, or None when not permitted (non-root)
+    fn mount(path: &std::path::Path, size: &str) -> Option<Self> {
+        let status = std::process::Command::new("mount")
+            .args(["-t", "tmpfs", "-o", &format!("size={}", size), "tmpfs"])
+            .arg(path)
+            .output()
+            .ok()?;
+        status
+            .status
+            .success()
+            .then(|| SmallTmpfs(path.to_path_buf()))
+    }
+}
+
+impl Drop for SmallTmpfs {
+    fn drop(&mut self) {
+        let _ = std::process::Command::new("umount").arg(&self.0).output();
+    }
+}
+
+#[test]
+fn test_full_source_reports_enospc() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+
+    let Some(_tmpfs) = SmallTmpfs::mount(&source, "256k") else {
+        eprintln!("Skipping: cannot mount a tmpfs for the source");
+        return;
+    };
+    let guard = MountGuard::new(&source, &mountpoint);
+
+    let mut file = File::create(mountpoint.join("big.bin")).expect("Failed to create file");
+
+    // The error may surface on write or on sync, but must not be lost
+    let result = file
+        .write_all(&vec![0u8; 1024 * 1024])
+        .and_then(|_| file.sync_all());
+    let err = result.expect_err("Filling the source did not report an error");
+    assert_eq!(err.raw_os_error(), Some(libc::ENOSPC));
+
+    drop(file);
+    drop(guard);
+}
+
+#[test]
+fn test_cross_device_rename_reports_exdev() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("file.txt"), "content").expect("Failed to write test file");
+    fs::create_dir(source.join("other")).expect("Failed to create directory");
+
+    let Some(tmpfs) = SmallTmpfs::mount(&source.join("other"), "1m") else {
+        eprintln!("Skipping: cannot mount a tmpfs inside the source");
+        return;
+    };
+    let guard = MountGuard::new(&source, &mountpoint);
+
+    // Both paths are on one FUSE mount, but not on one source filesystem
+    let result = fs::rename(
+        mountpoint.join("file.txt"),
+        mountpoint.join("other/file.txt"),
+    );
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EXDEV));
+
+    // The failed rename leaves the file reachable under its old name
+    assert_eq!(
+        fs::read_to_string(mountpoint.join("file.txt")).unwrap(),
+        "content"
+    );
+    assert!(!mountpoint.join("other/file.txt").exists());
+
+    drop(guard);
+    drop(tmpfs);
+}
+
+#[test]
+fn test_missing_source_reports_eio() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("file.txt"), "content").expect("Failed to write test file");
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+    assert!(mountpoint.join("file.txt").exists());
+
+    fs::remove_dir_all(&source).expect("Failed to remove source");
+
+    // Neither names nor listings look merely missing once the source is gone
+    let result = fs::metadata(mountpoint.join("other.txt"));
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EIO));
+    let result = fs::read_dir(&mountpoint);
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EIO));
+    let result = File::create(mountpoint.join("new.txt"));
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EIO));
+}
+
+#[test]
+fn test_write_to_read_only_handle() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("file.txt"), "content").expect("Failed to write test file");
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    let mut file = File::open(mountpoint.join("file.txt")).expect("Failed to open file");
+    let err = file
+        .write_all(b"oops")
+        .expect_err("Write to O_RDONLY handle succeeded");
+    assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+    assert_eq!(
+        fs::read_to_string(source.join("file.txt")).unwrap(),
+        "content"
+    );
+}
+
+#[test]
+fn test_allow_root_conflicts_with_allow_other() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_fuse-passthrough"))
+        .arg("-s")
+        .arg(&source)
+        .arg("-m")
+        .arg(&mountpoint)
+        .args(["--allow-other", "--allow-root"])
+        .output()
+        .expect("Failed to run fuse-passthrough");
+
+    assert!(!output.status.success(), "Both options were accepted");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("--allow-root") && stderr.contains("--allow-other"),
+        "Unclear error: {}",
+        stderr
+    );
+}
+
+#[test]
+fn test_check_validates_without_mounting() {
+    let (source, mountpoint, temp_dir) = setup_test_dirs();
+    let check = |source: &std::path::Path, mountpoint: &std::path::Path| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_fuse-passthrough"))
+            .arg("-s")
+            .arg(source)
+            .arg("-m")
+            .arg(mountpoint)
+            .arg("--check")
+            .output()
+            .expect("Failed to run fuse-passthrough")
+    };
+
+    let output = check(&source, &mountpoint);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "Valid setup rejected: {}", stdout);
+    assert!(!stdout.contains("error"), "{}", stdout);
+    assert!(
+        !is_fuse_mount(&mountpoint),
+        "--check mounted the filesystem"
+    );
+
+    let missing = temp_dir.path().join("missing");
+    let output = check(&missing, &mountpoint);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!output.status.success(), "Missing source accepted");
+    assert!(stdout.contains("error: source"), "{}", stdout);
+
+    // A directory something is mounted on already is refused
+    let _guard = MountGuard::new(&source, &mountpoint);
+    let output = check(&source, &mountpoint);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!output.status.success(), "Busy mountpoint accepted");
+    assert!(stdout.contains("error: mountpoint"), "{}", stdout);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_verify_checksums_detects_corruption() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    let args = ["--verify-checksums"];
+
+    {
+        let _guard = MountGuard::with_args(&source, &mountpoint, &args);
+        for name in ["good.txt", "bad.txt"] {
+            let mut file = File::create(mountpoint.join(name)).expect("Failed to create file");
+            file.write_all(b"first half, ").unwrap();
+            file.write_all(b"second half").unwrap();
+        }
+        let result = set_xattr(
+            &mountpoint.join("good.txt"),
+            "user.passthrough.sha256",
+            b"0",
+        );
+        assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EPERM));
+    }
+    assert!(get_xattr(&source.join("bad.txt"), "user.passthrough.sha256").is_ok());
+
+    // Flip a byte behind the mount's back, keeping the size
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .open(source.join("bad.txt"))
+        .unwrap();
+    file.write_all(b"F").unwrap();
+    drop(file);
+
+    let _guard = MountGuard::with_args(&source, &mountpoint, &args);
+    assert_eq!(
+        fs::read_to_string(mountpoint.join("good.txt")).unwrap(),
+        "first half, second half"
+    );
+    let result = fs::read(mountpoint.join("bad.txt"));
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EIO));
+}
+
+#[test]
+fn test_readlink_passes_errors_through() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    std::os::unix::fs::symlink("target", source.join("link")).expect("Failed to create symlink");
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+    assert!(fs::symlink_metadata(mountpoint.join("link")).is_ok());
+
+    // The kernel still knows the name as a symlink, so readlink reaches
+    // the source and fails there on a regular file
+    fs::remove_file(source.join("link")).unwrap();
+    fs::write(source.join("link"), "not a link").unwrap();
+    let result = fs::read_link(mountpoint.join("link"));
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EINVAL));
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_failed_chown_reports_error() {
+    use std::os::unix::io::AsRawFd;
+
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("file.txt"), "content").expect("Failed to write test file");
+
+    // Not even root may change the owner of an immutable file
+    const FS_IMMUTABLE_FL: libc::c_long = 0x10;
+    let set_flags = |flags: libc::c_long| {
+        let file = File::open(source.join("file.txt")).unwrap();
+        unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_SETFLAGS, &flags) }
+    };
+    if set_flags(FS_IMMUTABLE_FL) != 0 {
+        eprintln!("Skipping chown test, source does not support immutable files");
+        return;
+    }
+
+    let guard = MountGuard::new(&source, &mountpoint);
+    let result = std::os::unix::fs::chown(mountpoint.join("file.txt"), Some(1234), None);
+    drop(guard);
+    set_flags(0);
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EPERM));
+}
+
+#[test]
+fn test_unloadable_inode_db_fails_mount() {
+    let (source, mountpoint, temp_dir) = setup_test_dirs();
+
+    let mut config = fuse_passthrough::PassthroughConfig::new(&source);
+    config.inode_db = Some(temp_dir.path().join("missing/inodes"));
+    let err = fuse_passthrough::mount_with_config(config, &mountpoint)
+        .expect_err("Mounted without the inode database");
+    assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    assert!(!is_fuse_mount(&mountpoint));
+}
+
+#[test]
+fn test_invalid_name_pattern_fails_mount() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+
+    let mut config = fuse_passthrough::PassthroughConfig::new(&source);
+    config.exclude = vec!["[z-a]".to_string()];
+    let err = fuse_passthrough::mount_with_config(config, &mountpoint)
+        .expect_err("Mounted with a backwards range");
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("[z-a]"));
+    assert!(!is_fuse_mount(&mountpoint));
+}
@@ -1,11 +1,58 @@
 mod common;
 
 use common::{
-    setup_test_dirs, wait_for, wait_for_dir, wait_for_file, wait_for_file_gone, MountGuard,
+    access_unprivileged, append_unprivileged, get_xattr, is_fuse_mount, list_xattr, name_to_handle,
+    ofd_lock, ofd_lock_wait, open_by_handle, open_unprivileged, pass_fd, ready_pipe, renameat2,
+    set_xattr, setup_test_dirs, statvfs, wait_for, wait_for_dir, wait_for_file, wait_for_file_gone,
+    MountGuard,
 };
 use std::fs::{self, File};
 use std::io::{Read, Seek, SeekFrom, Write};
-use std::os::unix::fs::PermissionsExt;
+use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+#[test]
+fn test_clean_unmount() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+
+    let guard = MountGuard::new(&source, &mountpoint);
+    assert!(is_fuse_mount(&mountpoint), "Not mounted");
+
+    // Dropping the guard stops the binary, which must unmount on its own
+    drop(guard);
+    assert!(!is_fuse_mount(&mountpoint), "Stale mount left behind");
+}
+
+#[test]
+fn test_sigterm_unmounts() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+
+    let mut guard = MountGuard::new(&source, &mountpoint);
+    assert!(is_fuse_mount(&mountpoint), "Not mounted");
+
+    assert!(
+        guard.stop_with(libc::SIGTERM),
+        "Process did not exit on SIGTERM"
+    );
+    assert!(!is_fuse_mount(&mountpoint), "Stale mount left behind");
+}
+
+#[test]
+fn test_guard_cleans_up_after_killed_daemon() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+
+    let mut guard = MountGuard::new(&source, &mountpoint);
+    assert!(
+        guard.stop_with(libc::SIGKILL),
+        "Process did not exit on SIGKILL"
+    );
+
+    // Without fusermount's auto_unmount helper the dead mount stays
+    drop(guard);
+    assert!(!is_fuse_mount(&mountpoint), "Stale mount left behind");
+}
 
 #[test]
 fn test_read_file() {
@@ -88,6 +135,106 @@ fn test_list_directory() {
     );
 }
 
+#[test]
+fn test_list_directory_file_types() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+
+    fs::write(source.join("file.txt"), "").expect("Failed to write test file");
+    fs::create_dir(source.join("subdir")).expect("Failed to create directory");
+    std::os::unix::fs::symlink("file.txt", source.join("link")).expect("Failed to create symlink");
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    // The types come from readdir itself, without a lookup per entry
+    for entry in fs::read_dir(&mountpoint).expect("Failed to read directory") {
+        let entry = entry.expect("Failed to read entry");
+        let file_type = entry.file_type().expect("Failed to get file type");
+        match entry.file_name().to_str().unwrap() {
+            "file.txt" => assert!(file_type.is_file(), "file.txt not a regular file"),
+            "subdir" => assert!(file_type.is_dir(), "subdir not a directory"),
+            "link" => assert!(file_type.is_symlink(), "link not a symlink"),
+            name => panic!("Unexpected entry {}", name),
+        }
+    }
+
+    // Large directories still list every entry
+    let bulk = source.join("bulk");
+    fs::create_dir(&bulk).expect("Failed to create directory");
+    for i in 0..10_000 {
+        fs::write(bulk.join(format!("f{}", i)), "").expect("Failed to write test file");
+    }
+    let count = fs::read_dir(mountpoint.join("bulk"))
+        .expect("Failed to read directory")
+        .count();
+    assert_eq!(count, 10_000);
+}
+
+#[test]
+fn test_parallel_stat() {
+    use std::os::unix::fs::MetadataExt;
+
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    for i in 0..200 {
+        fs::write(source.join(format!("f{}", i)), "").expect("Failed to write test file");
+    }
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    let expected: Vec<u64> = (0..200)
+        .map(|i| {
+            fs::metadata(mountpoint.join(format!("f{}", i)))
+                .unwrap()
+                .ino()
+        })
+        .collect();
+
+    // Every thread sees the same inode for every file, however lookups interleave
+    std::thread::scope(|scope| {
+        for _ in 0..8 {
+            scope.spawn(|| {
+                for _ in 0..20 {
+                    for (i, &ino) in expected.iter().enumerate() {
+                        let metadata = fs::metadata(mountpoint.join(format!("f{}", i)))
+                            .expect("Failed to stat file");
+                        assert_eq!(metadata.ino(), ino);
+                    }
+                }
+            });
+        }
+    });
+}
+
+#[test]
+fn test_non_utf8_names() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+
+    // Latin-1 "café.txt", which is not valid UTF-8
+    let name = OsStr::from_bytes(b"caf\xe9.txt");
+    fs::write(source.join(name), "latin-1").expect("Failed to write test file");
+    let target = OsStr::from_bytes(b"target\xff");
+    std::os::unix::fs::symlink(target, source.join("link")).expect("Failed to create symlink");
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    let names: Vec<_> = fs::read_dir(&mountpoint)
+        .expect("Failed to read directory")
+        .map(|entry| entry.expect("Failed to read entry").file_name())
+        .collect();
+    assert!(names.iter().any(|n| n == name), "Name mangled: {:?}", names);
+
+    assert_eq!(
+        fs::read_to_string(mountpoint.join(name)).unwrap(),
+        "latin-1"
+    );
+    assert_eq!(
+        fs::read_link(mountpoint.join("link")).unwrap().as_os_str(),
+        target
+    );
+}
+
 #[test]
 fn test_create_directory() {
     let (source, mountpoint, _temp_dir) = setup_test_dirs();
@@ -204,204 +351,2825 @@ fn test_symlink() {
 }
 
 #[test]
-fn test_file_permissions() {
-    let (source, mountpoint, _temp_dir) = setup_test_dirs();
-
-    // Create a test file
-    fs::write(source.join("perm_test.txt"), "test").expect("Failed to write test file");
+fn test_no_follow_escapes_blocks_outside_symlinks() {
+    let (source, mountpoint, temp_dir) = setup_test_dirs();
+    let outside = temp_dir.path().join("outside");
+    fs::create_dir(&outside).expect("Failed to create outside directory");
+    fs::write(outside.join("secret.txt"), "secret").expect("Failed to write secret");
+    fs::write(source.join("inside.txt"), "inside").expect("Failed to write file");
+    std::os::unix::fs::symlink(outside.join("secret.txt"), source.join("absolute"))
+        .expect("Failed to create symlink");
+    std::os::unix::fs::symlink("../outside/secret.txt", source.join("relative"))
+        .expect("Failed to create symlink");
+    std::os::unix::fs::symlink("inside.txt", source.join("local"))
+        .expect("Failed to create symlink");
+    fs::create_dir(source.join("dir")).expect("Failed to create directory");
 
-    let _guard = MountGuard::new(&source, &mountpoint);
+    let _guard = MountGuard::with_args(&source, &mountpoint, &["--no-follow-escapes"]);
 
-    // Change permissions through mountpoint
-    let new_mode = 0o644;
-    fs::set_permissions(
-        mountpoint.join("perm_test.txt"),
-        fs::Permissions::from_mode(new_mode),
-    )
-    .expect("Failed to set permissions");
+    for name in ["absolute", "relative"] {
+        let err = fs::read(mountpoint.join(name)).expect_err("Followed an escaping symlink");
+        assert_eq!(err.raw_os_error(), Some(libc::EACCES), "{}", name);
+        assert!(fs::symlink_metadata(mountpoint.join(name)).is_ok());
+    }
+    assert_eq!(fs::read(mountpoint.join("local")).unwrap(), b"inside");
 
-    // Wait for permissions to be updated
-    let source_file = source.join("perm_test.txt");
+    // Swap a directory the mount already knows for a symlink leading out
+    assert!(mountpoint.join("dir").is_dir());
+    fs::remove_dir(source.join("dir")).expect("Failed to remove directory");
+    std::os::unix::fs::symlink(&outside, source.join("dir")).expect("Failed to create symlink");
+    let err = fs::read(mountpoint.join("dir/secret.txt")).expect_err("Read through a swapped dir");
     assert!(
-        wait_for(|| {
-            if let Ok(metadata) = fs::metadata(&source_file) {
-                (metadata.permissions().mode() & 0o777) == new_mode
-            } else {
-                false
-            }
-        }),
-        "Permissions not set correctly"
+        matches!(err.raw_os_error(), Some(libc::EACCES | libc::ENOTDIR)),
+        "Unexpected error: {:?}",
+        err
     );
 }
 
 #[test]
-fn test_truncate_file() {
+fn test_exclude_hides_matching_names() {
     let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("key.secret"), "hidden").expect("Failed to write file");
+    fs::write(source.join("visible.txt"), "shown").expect("Failed to write file");
+    fs::create_dir(source.join("sub")).expect("Failed to create dir");
+    fs::write(source.join("sub/nested.secret"), "hidden").expect("Failed to write file");
+    fs::write(source.join("sub/keep.secret"), "shown").expect("Failed to write file");
+
+    let _guard = MountGuard::with_args(
+        &source,
+        &mountpoint,
+        &["--exclude", "*.secret", "--include", "keep.*"],
+    );
 
-    // Create a test file with content
-    let original_content = "This is a long content that will be truncated";
-    fs::write(source.join("truncate.txt"), original_content).expect("Failed to write test file");
+    let names = |dir: &std::path::Path| {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .expect("Failed to read dir")
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    };
+    assert_eq!(names(&mountpoint), ["sub", "visible.txt"]);
+    assert_eq!(names(&mountpoint.join("sub")), ["keep.secret"]);
+
+    for path in [
+        mountpoint.join("key.secret"),
+        mountpoint.join("sub/nested.secret"),
+    ] {
+        let err = fs::metadata(&path).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT), "{:?}", path);
+        let err = File::open(&path).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT), "{:?}", path);
+    }
+    let err = fs::rename(
+        mountpoint.join("visible.txt"),
+        mountpoint.join("moved.secret"),
+    )
+    .unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+    assert!(fs::write(mountpoint.join("new.secret"), "").is_err());
+    assert!(!source.join("new.secret").exists());
+    assert_eq!(
+        fs::read_to_string(source.join("key.secret")).unwrap(),
+        "hidden"
+    );
+}
+
+#[test]
+fn test_lower_copies_up_on_write() {
+    let (source, mountpoint, temp_dir) = setup_test_dirs();
+    let lower = temp_dir.path().join("lower");
+    fs::create_dir_all(lower.join("dir")).expect("Failed to create lower directory");
+    fs::write(lower.join("dir/file.txt"), "lower").expect("Failed to write lower file");
+
+    let lower_arg = lower.to_str().unwrap();
+    let _guard = MountGuard::with_args(&source, &mountpoint, &["--lower", lower_arg]);
+
+    assert_eq!(fs::read(mountpoint.join("dir/file.txt")).unwrap(), b"lower");
+    fs::write(mountpoint.join("dir/file.txt"), "upper").expect("Failed to write through mount");
+
+    assert_eq!(fs::read(mountpoint.join("dir/file.txt")).unwrap(), b"upper");
+    assert_eq!(fs::read(source.join("dir/file.txt")).unwrap(), b"upper");
+    assert_eq!(fs::read(lower.join("dir/file.txt")).unwrap(), b"lower");
+
+    // New files land in the upper directory too
+    fs::write(mountpoint.join("dir/new.txt"), "new").expect("Failed to create file");
+    assert!(source.join("dir/new.txt").exists());
+    assert!(!lower.join("dir/new.txt").exists());
+}
+
+#[test]
+fn test_lower_whiteout_on_delete() {
+    let (source, mountpoint, temp_dir) = setup_test_dirs();
+    let lower = temp_dir.path().join("lower");
+    fs::create_dir_all(lower.join("dir/sub")).expect("Failed to create lower directory");
+    fs::write(lower.join("gone.txt"), "lower").expect("Failed to write lower file");
+    fs::write(lower.join("kept.txt"), "lower").expect("Failed to write lower file");
+
+    let lower_arg = lower.to_str().unwrap();
+    let _guard = MountGuard::with_args(&source, &mountpoint, &["--lower", lower_arg]);
+
+    fs::remove_file(mountpoint.join("gone.txt")).expect("Failed to remove lower file");
+    fs::remove_dir(mountpoint.join("dir/sub")).expect("Failed to remove lower directory");
+
+    assert!(lower.join("gone.txt").exists());
+    assert!(lower.join("dir/sub").is_dir());
+    assert!(source.join(".wh.gone.txt").exists());
+    assert!(!mountpoint.join("gone.txt").exists());
+    assert!(!mountpoint.join("dir/sub").exists());
+
+    let names: Vec<_> = fs::read_dir(&mountpoint)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert!(names.contains(&"kept.txt".into()), "{:?}", names);
+    assert!(!names.contains(&"gone.txt".into()), "{:?}", names);
+    assert!(names
+        .iter()
+        .all(|name| !name.to_string_lossy().starts_with(".wh.")));
+    assert_eq!(fs::read_dir(mountpoint.join("dir")).unwrap().count(), 0);
+
+    // Recreating the name shows the new file, not the lower one
+    fs::write(mountpoint.join("gone.txt"), "again").expect("Failed to recreate file");
+    assert_eq!(fs::read(mountpoint.join("gone.txt")).unwrap(), b"again");
+    assert!(!source.join(".wh.gone.txt").exists());
+}
+
+#[test]
+fn test_file_handles_go_stale_across_mounts() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("first.txt"), "first").expect("Failed to write file");
 
+    let guard = MountGuard::new(&source, &mountpoint);
+    let mut handle = name_to_handle(&mountpoint.join("first.txt")).expect("No file handle");
+    let root = File::open(&mountpoint).expect("Failed to open mountpoint");
+    let mut content = String::new();
+    open_by_handle(&root, &mut handle)
+        .expect("Failed to open by handle")
+        .read_to_string(&mut content)
+        .unwrap();
+    assert_eq!(content, "first");
+    drop(root);
+    drop(guard);
+
+    // The next mount gives the same inode number to another file
+    fs::remove_file(source.join("first.txt")).expect("Failed to remove file");
+    fs::write(source.join("second.txt"), "second").expect("Failed to write file");
     let _guard = MountGuard::new(&source, &mountpoint);
+    assert!(mountpoint.join("second.txt").exists());
 
-    // Truncate file through mountpoint
-    {
-        let file = File::options()
-            .write(true)
-            .open(mountpoint.join("truncate.txt"))
-            .expect("Failed to open file");
-        file.set_len(10).expect("Failed to truncate file");
-    }
+    let root = File::open(&mountpoint).expect("Failed to open mountpoint");
+    let err = open_by_handle(&root, &mut handle).expect_err("Old handle opened another file");
+    assert_eq!(err.raw_os_error(), Some(libc::ESTALE));
+}
 
-    // Wait for truncation to complete
-    let source_file = source.join("truncate.txt");
+#[test]
+fn test_poll_wakes_on_fifo_write() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    let fifo = std::ffi::CString::new(source.join("fifo").to_str().unwrap()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+    fs::write(source.join("file.txt"), "content").expect("Failed to write file");
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+    let poll = |file: &File, timeout_ms: i32| {
+        let mut pollfd = libc::pollfd {
+            fd: file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ready = unsafe { libc::poll(&mut pollfd, 1, timeout_ms) };
+        assert!(
+            ready >= 0,
+            "poll failed: {}",
+            std::io::Error::last_os_error()
+        );
+        pollfd.revents
+    };
+
+    // Regular files are always readable
+    let file = File::open(mountpoint.join("file.txt")).expect("Failed to open file");
+    assert_ne!(poll(&file, 1000) & libc::POLLIN, 0);
+
+    let reader = {
+        use std::os::unix::fs::OpenOptionsExt;
+        File::options()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(mountpoint.join("fifo"))
+            .expect("Failed to open fifo for reading")
+    };
+    let mut writer = File::options()
+        .write(true)
+        .open(mountpoint.join("fifo"))
+        .expect("Failed to open fifo for writing");
+    assert_eq!(poll(&reader, 0), 0, "Empty fifo polled readable");
+
+    let waiter = std::thread::spawn(move || {
+        let revents = poll(&reader, 5000);
+        (revents, reader)
+    });
+    std::thread::sleep(Duration::from_millis(100));
+    writer.write_all(b"wake").expect("Failed to write to fifo");
+
+    let (revents, mut reader) = waiter.join().unwrap();
+    assert_ne!(revents & libc::POLLIN, 0, "Poll did not wake up");
+    let mut buffer = [0u8; 4];
+    reader.read_exact(&mut buffer).unwrap();
+    assert_eq!(&buffer, b"wake");
+}
+
+#[test]
+fn test_ready_fd_signals_once_mounted() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("file.txt"), "content").expect("Failed to write file");
+
+    let (mut ready, writer) = ready_pipe();
+    let mut command = std::process::Command::new(env!("CARGO_BIN_EXE_fuse-passthrough"));
+    command
+        .arg("-s")
+        .arg(&source)
+        .arg("-m")
+        .arg(&mountpoint)
+        .arg("--ready-fd")
+        .arg(writer.as_raw_fd().to_string());
+    pass_fd(&mut command, writer.as_raw_fd());
+    let child = command.spawn().expect("Failed to start fuse-passthrough");
+    drop(writer);
+    let _guard = MountGuard::adopt(child, &mountpoint);
+
+    let mut signal = Vec::new();
+    ready
+        .read_to_end(&mut signal)
+        .expect("Failed to read ready fd");
+    assert_eq!(signal, b"\n", "Daemon exited without signalling readiness");
+
+    // The first operation after the signal already goes through the mount
+    assert!(is_fuse_mount(&mountpoint));
+    assert_eq!(
+        fs::read_to_string(mountpoint.join("file.txt")).expect("Failed to read file"),
+        "content"
+    );
+}
+
+#[test]
+fn test_op_timeout_fails_reads_from_hung_source() {
+    let (source, mountpoint, temp_dir) = setup_test_dirs();
+    fs::write(source.join("slow.txt"), "eventually").expect("Failed to write file");
+
+    // The outer mount's source is another mount whose daemon gets stopped
+    let outer = temp_dir.path().join("outer");
+    fs::create_dir(&outer).expect("Failed to create outer mountpoint");
+    let inner = MountGuard::new(&source, &mountpoint);
+    let _outer = MountGuard::with_args(&mountpoint, &outer, &["--op-timeout", "500"]);
+
+    let mut file = File::open(outer.join("slow.txt")).expect("Failed to open file");
+    unsafe { libc::kill(inner.pid() as libc::pid_t, libc::SIGSTOP) };
+    let start = Instant::now();
+    let result = file.read_to_end(&mut Vec::new());
+    let elapsed = start.elapsed();
+    unsafe { libc::kill(inner.pid() as libc::pid_t, libc::SIGCONT) };
+
+    let err = result.expect_err("Read from a hung source succeeded");
+    assert_eq!(err.raw_os_error(), Some(libc::ETIMEDOUT));
     assert!(
-        wait_for(|| {
-            if let Ok(metadata) = fs::metadata(&source_file) {
-                metadata.len() == 10
-            } else {
-                false
-            }
-        }),
-        "File not truncated"
+        elapsed < Duration::from_secs(5),
+        "Timed out after {:?}",
+        elapsed
     );
 
-    // Verify content
-    let content = fs::read_to_string(&source_file).expect("Failed to read file");
-    assert_eq!(content, "This is a ");
+    // The mount keeps working once the source recovers
+    assert!(wait_for(
+        || fs::read(outer.join("slow.txt")).is_ok_and(|data| data == b"eventually")
+    ));
 }
 
 #[test]
-fn test_rename_across_directories() {
+fn test_dangling_symlink_metadata() {
     let (source, mountpoint, _temp_dir) = setup_test_dirs();
 
-    // Create source directory structure
-    fs::create_dir(source.join("dir1")).expect("Failed to create dir1");
-    fs::create_dir(source.join("dir2")).expect("Failed to create dir2");
-    let test_content = "Moving file";
-    fs::write(source.join("dir1/file.txt"), test_content).expect("Failed to write test file");
+    // Create a dangling symlink in source
+    let target = "missing_target.txt";
+    std::os::unix::fs::symlink(target, source.join("dangling")).expect("Failed to create symlink");
 
     let _guard = MountGuard::new(&source, &mountpoint);
 
-    // Move file from dir1 to dir2 through mountpoint
-    fs::rename(
-        mountpoint.join("dir1/file.txt"),
-        mountpoint.join("dir2/file.txt"),
+    // The link itself must be reported, not its (missing) target
+    let metadata =
+        fs::symlink_metadata(mountpoint.join("dangling")).expect("Failed to get symlink metadata");
+    assert!(metadata.file_type().is_symlink(), "Not reported as symlink");
+    assert_eq!(metadata.len(), target.len() as u64, "Size mismatch");
+}
+
+#[test]
+fn test_hard_link() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+
+    // Create a target file
+    let test_content = "Linked content";
+    fs::write(source.join("original.txt"), test_content).expect("Failed to write test file");
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    // Create hardlink through mountpoint
+    fs::hard_link(
+        mountpoint.join("original.txt"),
+        mountpoint.join("hardlink.txt"),
     )
-    .expect("Failed to rename file across directories");
+    .expect("Failed to create hardlink");
 
-    // Wait for move to complete
+    // Wait for hardlink to appear
     assert!(
-        wait_for_file(&source.join("dir2/file.txt")),
-        "File not found in dir2"
-    );
-    assert!(
-        wait_for_file_gone(&source.join("dir1/file.txt")),
-        "File still exists in dir1"
+        wait_for_file(&source.join("hardlink.txt")),
+        "Hardlink not created in source"
     );
 
-    let content = fs::read_to_string(source.join("dir2/file.txt")).expect("Failed to read file");
+    // Both names must report the shared link count
+    let metadata =
+        fs::metadata(mountpoint.join("hardlink.txt")).expect("Failed to get hardlink metadata");
+    assert_eq!(metadata.nlink(), 2, "Link count mismatch");
+
+    let content =
+        fs::read_to_string(mountpoint.join("hardlink.txt")).expect("Failed to read hardlink");
     assert_eq!(content, test_content);
 }
 
 #[test]
-fn test_append_write() {
+fn test_hard_links_share_inode() {
     let (source, mountpoint, _temp_dir) = setup_test_dirs();
 
-    // Create initial file
-    let initial_content = "Initial content\n";
-    fs::write(source.join("append.txt"), initial_content).expect("Failed to write test file");
+    // Create two names for the same file in source
+    fs::write(source.join("first.txt"), "shared").expect("Failed to write test file");
+    fs::hard_link(source.join("first.txt"), source.join("second.txt"))
+        .expect("Failed to create hardlink");
 
     let _guard = MountGuard::new(&source, &mountpoint);
 
-    // Append to file through mountpoint
-    let append_content = "Appended content";
-    {
-        let mut file = File::options()
-            .append(true)
-            .open(mountpoint.join("append.txt"))
-            .expect("Failed to open file for append");
-        file.write_all(append_content.as_bytes())
-            .expect("Failed to append");
-        file.sync_all().expect("Failed to sync");
-    }
-
-    // Wait for append to complete
-    let expected_len = initial_content.len() + append_content.len();
-    let source_file = source.join("append.txt");
-    assert!(
-        wait_for(|| {
-            if let Ok(metadata) = fs::metadata(&source_file) {
-                metadata.len() == expected_len as u64
-            } else {
-                false
-            }
-        }),
-        "Append not completed"
+    let first = fs::metadata(mountpoint.join("first.txt")).expect("Failed to stat first.txt");
+    let second = fs::metadata(mountpoint.join("second.txt")).expect("Failed to stat second.txt");
+    assert_eq!(
+        first.ino(),
+        second.ino(),
+        "Hardlinks report different inodes"
     );
-
-    // Verify content
-    let content = fs::read_to_string(&source_file).expect("Failed to read file");
-    assert_eq!(content, format!("{}{}", initial_content, append_content));
 }
 
 #[test]
-fn test_large_file() {
+fn test_xattr_round_trip() {
     let (source, mountpoint, _temp_dir) = setup_test_dirs();
 
+    // Create a test file
+    fs::write(source.join("xattr.txt"), "test").expect("Failed to write test file");
+
+    // Skip when the backing filesystem has no user xattr support
+    if let Err(e) = set_xattr(&source.join("xattr.txt"), "user.probe", b"1") {
+        eprintln!("Skipping xattr test, source does not support xattrs: {}", e);
+        return;
+    }
+
     let _guard = MountGuard::new(&source, &mountpoint);
 
-    // Create a large file (1MB) through mountpoint
-    let size = 1024 * 1024; // 1MB
-    let data: Vec<u8> = (0..size).map(|i| (i % 256) as u8).collect();
+    // Set attribute through mountpoint
+    let value = b"passthrough value";
+    set_xattr(&mountpoint.join("xattr.txt"), "user.test", value).expect("Failed to set xattr");
 
-    {
-        let mut file = File::create(mountpoint.join("large.bin")).expect("Failed to create file");
-        file.write_all(&data).expect("Failed to write large file");
-        file.sync_all().expect("Failed to sync");
-    }
+    // Verify through both the mountpoint and the source
+    let mount_value =
+        get_xattr(&mountpoint.join("xattr.txt"), "user.test").expect("Failed to get xattr");
+    assert_eq!(mount_value, value);
+    let source_value =
+        get_xattr(&source.join("xattr.txt"), "user.test").expect("Failed to get source xattr");
+    assert_eq!(source_value, value);
 
-    // Wait for file to be fully written
-    let source_file = source.join("large.bin");
+    let names = list_xattr(&mountpoint.join("xattr.txt")).expect("Failed to list xattrs");
     assert!(
-        wait_for(|| {
-            if let Ok(metadata) = fs::metadata(&source_file) {
-                metadata.len() == size as u64
-            } else {
-                false
-            }
-        }),
-        "Large file not fully written"
+        names.contains(&"user.test".to_string()),
+        "user.test not found in {:?}",
+        names
     );
-
-    // Read back and verify content
-    let read_data = fs::read(mountpoint.join("large.bin")).expect("Failed to read large file");
-    assert_eq!(read_data.len(), size);
-    assert_eq!(read_data, data, "Content mismatch in large file");
 }
 
+/// Finder metadata lives in com.apple.* attributes, which must survive
+/// the mount byte for byte
+#[cfg(target_os = "macos")]
 #[test]
-fn test_seek_and_read() {
+fn test_finder_xattrs_round_trip() {
+    use std::ffi::CString;
+
     let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("finder.txt"), "test").expect("Failed to write test file");
+    let _guard = MountGuard::new(&source, &mountpoint);
 
-    // Create a test file
-    let test_content = "0123456789ABCDEFGHIJ";
-    fs::write(source.join("seek.txt"), test_content).expect("Failed to write test file");
+    let path = CString::new(mountpoint.join("finder.txt").to_str().unwrap()).unwrap();
+    let mut finder_info = [0u8; 32];
+    finder_info[..8].copy_from_slice(b"TEXTttxt");
+    for (name, value) in [
+        ("com.apple.metadata:kMDItemComment", &b"a comment"[..]),
+        ("com.apple.FinderInfo", &finder_info[..]),
+        ("com.apple.ResourceFork", &b"resource fork data"[..]),
+    ] {
+        let name = CString::new(name).unwrap();
+        let ret = unsafe {
+            libc::setxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr() as *const libc::c_void,
+                value.len(),
+                0,
+                0,
+            )
+        };
+        assert_eq!(
+            ret,
+            0,
+            "setxattr {:?}: {}",
+            name,
+            std::io::Error::last_os_error()
+        );
+
+        let mut buffer = vec![0u8; 256];
+        let len = unsafe {
+            libc::getxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                buffer.len(),
+                0,
+                0,
+            )
+        };
+        assert!(
+            len >= 0,
+            "getxattr {:?}: {}",
+            name,
+            std::io::Error::last_os_error()
+        );
+        assert_eq!(&buffer[..len as usize], value, "{:?}", name);
+    }
+}
+
+#[test]
+fn test_fstat_after_unlink() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("doomed.txt"), "twelve bytes").expect("Failed to write test file");
 
     let _guard = MountGuard::new(&source, &mountpoint);
 
-    // Open file and seek to middle, then read
-    let mut file = File::open(mountpoint.join("seek.txt")).expect("Failed to open file");
-    file.seek(SeekFrom::Start(10)).expect("Failed to seek");
+    let mut file = fs::File::open(mountpoint.join("doomed.txt")).expect("Failed to open file");
 
-    let mut buffer = [0u8; 5];
-    file.read_exact(&mut buffer).expect("Failed to read");
+    // Remove it behind the mount's back and let the cached attributes expire
+    fs::remove_file(source.join("doomed.txt")).expect("Failed to remove source file");
+    std::thread::sleep(Duration::from_millis(1500));
 
-    assert_eq!(&buffer, b"ABCDE");
+    // SEEK_END refreshes the size through getattr on the open handle
+    let end = file
+        .seek(SeekFrom::End(0))
+        .expect("getattr on open handle failed");
+    assert_eq!(end, 12);
+    let metadata = file.metadata().expect("fstat on open handle failed");
+    assert_eq!(metadata.len(), 12);
 }
 
 #[test]
-fn test_nested_directories() {
+fn test_unlinked_file_usable_until_close() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("scratch.txt"), "temporary").expect("Failed to write test file");
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    let mut file = fs::File::options()
+        .read(true)
+        .write(true)
+        .open(mountpoint.join("scratch.txt"))
+        .expect("Failed to open file");
+    fs::remove_file(mountpoint.join("scratch.txt")).expect("Failed to unlink file");
+    assert!(!mountpoint.join("scratch.txt").exists());
+
+    // The handle keeps working, including for requests made by inode
+    let mut content = String::new();
+    file.read_to_string(&mut content)
+        .expect("Failed to read unlinked file");
+    assert_eq!(content, "temporary");
+    file.write_all(b" data")
+        .expect("Failed to write unlinked file");
+    let name = std::ffi::CString::new("user.kept").unwrap();
+    let ret = unsafe {
+        libc::fsetxattr(
+            file.as_raw_fd(),
+            name.as_ptr(),
+            b"1".as_ptr() as *const libc::c_void,
+            1,
+            0,
+        )
+    };
+    assert_eq!(
+        ret,
+        0,
+        "fsetxattr failed: {}",
+        std::io::Error::last_os_error()
+    );
+
+    // The data lives on under a hidden name until the last close
+    let hidden = || {
+        fs::read_dir(&source)
+            .unwrap()
+            .filter(|entry| {
+                let entry = entry.as_ref().unwrap();
+                entry
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with(".fuse_hidden")
+            })
+            .count()
+    };
+    assert_eq!(hidden(), 1);
+    drop(file);
+    assert!(wait_for(|| hidden() == 0), "Hidden file left behind");
+}
+
+#[test]
+fn test_file_permissions() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+
+    // Create a test file
+    fs::write(source.join("perm_test.txt"), "test").expect("Failed to write test file");
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    // Change permissions through mountpoint
+    let new_mode = 0o644;
+    fs::set_permissions(
+        mountpoint.join("perm_test.txt"),
+        fs::Permissions::from_mode(new_mode),
+    )
+    .expect("Failed to set permissions");
+
+    // Wait for permissions to be updated
+    let source_file = source.join("perm_test.txt");
+    assert!(
+        wait_for(|| {
+            if let Ok(metadata) = fs::metadata(&source_file) {
+                (metadata.permissions().mode() & 0o777) == new_mode
+            } else {
+                false
+            }
+        }),
+        "Permissions not set correctly"
+    );
+}
+
+#[test]
+fn test_create_applies_umask() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    // The umask is process-wide, so apply it in a child rather than here
+    let create = |umask: &str, file: &str, dir: &str| {
+        let status = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!(
+                "umask {} && touch {} && mkdir {}",
+                umask, file, dir
+            ))
+            .current_dir(&mountpoint)
+            .status()
+            .expect("Failed to run shell");
+        assert!(status.success(), "Shell commands failed");
+    };
+
+    create("027", "masked.txt", "masked_dir");
+    let mode = |name: &str| {
+        fs::metadata(source.join(name))
+            .unwrap()
+            .permissions()
+            .mode()
+            & 0o777
+    };
+    assert_eq!(mode("masked.txt"), 0o640);
+    assert_eq!(mode("masked_dir"), 0o750);
+
+    // With no umask the full requested mode is kept, whatever the daemon's own umask
+    create("000", "open.txt", "open_dir");
+    assert_eq!(mode("open.txt"), 0o666);
+    assert_eq!(mode("open_dir"), 0o777);
+}
+
+#[test]
+fn test_access_honors_mask() {
+    let (source, mountpoint, temp_dir) = setup_test_dirs();
+    fs::write(source.join("readonly.txt"), "content").expect("Failed to write test file");
+    fs::set_permissions(
+        source.join("readonly.txt"),
+        fs::Permissions::from_mode(0o444),
+    )
+    .expect("Failed to set permissions");
+    // Let an unprivileged checker reach the mountpoint
+    fs::set_permissions(temp_dir.path(), fs::Permissions::from_mode(0o755))
+        .expect("Failed to set permissions");
+
+    let _guard = MountGuard::with_args(&source, &mountpoint, &["--allow-other"]);
+
+    let path = mountpoint.join("readonly.txt");
+    assert!(access_unprivileged(&path, libc::F_OK), "F_OK failed");
+    assert!(access_unprivileged(&path, libc::R_OK), "R_OK failed");
+    assert!(
+        !access_unprivileged(&path, libc::W_OK),
+        "W_OK succeeded on 0444 file"
+    );
+    assert!(
+        !access_unprivileged(&path, libc::X_OK),
+        "X_OK succeeded on 0444 file"
+    );
+}
+
+#[test]
+fn test_enforce_permissions_denies_other_users() {
+    let (source, mountpoint, temp_dir) = setup_test_dirs();
+    fs::write(source.join("readonly.txt"), "content").expect("Failed to write test file");
+    fs::set_permissions(
+        source.join("readonly.txt"),
+        fs::Permissions::from_mode(0o444),
+    )
+    .expect("Failed to set permissions");
+    fs::write(source.join("shared.txt"), "content").expect("Failed to write test file");
+    fs::set_permissions(source.join("shared.txt"), fs::Permissions::from_mode(0o666))
+        .expect("Failed to set permissions");
+    fs::set_permissions(temp_dir.path(), fs::Permissions::from_mode(0o755))
+        .expect("Failed to set permissions");
+
+    let _guard = MountGuard::with_args(
+        &source,
+        &mountpoint,
+        &["--allow-other", "--enforce-permissions"],
+    );
+
+    assert!(
+        !append_unprivileged(&mountpoint.join("readonly.txt")),
+        "Opened 0444 file for writing"
+    );
+    assert!(
+        append_unprivileged(&mountpoint.join("shared.txt")),
+        "Failed to open 0666 file for writing"
+    );
+}
+
+#[test]
+fn test_open_honors_access_mode() {
+    let (source, mountpoint, temp_dir) = setup_test_dirs();
+    for (name, mode) in [("readonly.txt", 0o444), ("writeonly.txt", 0o222)] {
+        fs::write(source.join(name), "content").expect("Failed to write test file");
+        fs::set_permissions(source.join(name), fs::Permissions::from_mode(mode))
+            .expect("Failed to set permissions");
+    }
+    fs::set_permissions(temp_dir.path(), fs::Permissions::from_mode(0o755))
+        .expect("Failed to set permissions");
+
+    // The writeback cache widens write-only opens to read pages back
+    let _guard = MountGuard::with_args(
+        &source,
+        &mountpoint,
+        &[
+            "--allow-other",
+            "--enforce-permissions",
+            "--writeback-cache",
+        ],
+    );
+
+    let readonly = mountpoint.join("readonly.txt");
+    let writeonly = mountpoint.join("writeonly.txt");
+    let err = open_unprivileged(&readonly, libc::O_RDWR).expect_err("Opened 0444 file O_RDWR");
+    assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+    open_unprivileged(&readonly, libc::O_RDONLY).expect("Failed to open 0444 file for reading");
+    open_unprivileged(&writeonly, libc::O_WRONLY).expect("Failed to open 0222 file for writing");
+    let err =
+        open_unprivileged(&writeonly, libc::O_RDONLY).expect_err("Opened 0222 file for reading");
+    assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+}
+
+#[test]
+fn test_refused_open_leaves_lower_file_alone() {
+    let (source, mountpoint, temp_dir) = setup_test_dirs();
+    let lower = temp_dir.path().join("lower");
+    fs::create_dir_all(&lower).expect("Failed to create lower directory");
+    fs::write(lower.join("readonly.txt"), "lower").expect("Failed to write lower file");
+    fs::set_permissions(
+        lower.join("readonly.txt"),
+        fs::Permissions::from_mode(0o444),
+    )
+    .expect("Failed to set permissions");
+    fs::set_permissions(temp_dir.path(), fs::Permissions::from_mode(0o755))
+        .expect("Failed to set permissions");
+
+    let lower_arg = lower.to_str().unwrap();
+    let _guard = MountGuard::with_args(
+        &source,
+        &mountpoint,
+        &[
+            "--lower",
+            lower_arg,
+            "--allow-other",
+            "--enforce-permissions",
+        ],
+    );
+
+    let err = open_unprivileged(&mountpoint.join("readonly.txt"), libc::O_RDWR)
+        .expect_err("Opened 0444 lower file O_RDWR");
+    assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+    assert!(
+        !source.join("readonly.txt").exists(),
+        "Refused open copied the file up"
+    );
+}
+
+#[test]
+fn test_default_permissions_denies_other_users() {
+    let (source, mountpoint, temp_dir) = setup_test_dirs();
+    fs::write(source.join("readonly.txt"), "content").expect("Failed to write test file");
+    fs::set_permissions(
+        source.join("readonly.txt"),
+        fs::Permissions::from_mode(0o444),
+    )
+    .expect("Failed to set permissions");
+    fs::set_permissions(temp_dir.path(), fs::Permissions::from_mode(0o755))
+        .expect("Failed to set permissions");
+
+    let _guard = MountGuard::with_args(
+        &source,
+        &mountpoint,
+        &["--allow-other", "--default-permissions"],
+    );
+
+    assert!(
+        !append_unprivileged(&mountpoint.join("readonly.txt")),
+        "Opened 0444 file for writing"
+    );
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_device_node_rdev() {
+    if unsafe { libc::geteuid() } != 0 {
+        // mknod of a device node needs root
+        return;
+    }
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    // A minor above 255 lands in the high bits of the kernel's encoding
+    let rdev = libc::makedev(240, 0x12345);
+    let path = std::ffi::CString::new(source.join("device").to_str().unwrap()).unwrap();
+    assert_eq!(
+        unsafe { libc::mknod(path.as_ptr(), libc::S_IFCHR | 0o600, rdev) },
+        0,
+        "mknod failed: {}",
+        std::io::Error::last_os_error()
+    );
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+    let metadata = fs::symlink_metadata(mountpoint.join("device")).expect("Failed to stat");
+    assert!(metadata.file_type().is_char_device());
+    assert_eq!(libc::major(metadata.rdev()), 240);
+    assert_eq!(libc::minor(metadata.rdev()), 0x12345);
+}
+
+#[test]
+fn test_map_uid_translates_ownership() {
+    if unsafe { libc::geteuid() } != 0 {
+        // chown to another user needs root
+        return;
+    }
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    let path = source.join("owned.txt");
+    fs::write(&path, "").expect("Failed to write test file");
+    std::os::unix::fs::chown(&path, Some(1000), Some(1000)).expect("Failed to chown file");
+    fs::write(source.join("other.txt"), "").expect("Failed to write test file");
+    std::os::unix::fs::chown(source.join("other.txt"), Some(2000), Some(2000))
+        .expect("Failed to chown file");
+
+    let _guard = MountGuard::with_args(
+        &source,
+        &mountpoint,
+        &["--map-uid", "1000:0", "--map-gid", "1000:0"],
+    );
+
+    let metadata = fs::metadata(mountpoint.join("owned.txt")).expect("Failed to stat file");
+    assert_eq!((metadata.uid(), metadata.gid()), (0, 0));
+    let metadata = fs::metadata(mountpoint.join("other.txt")).expect("Failed to stat file");
+    assert_eq!(
+        (metadata.uid(), metadata.gid()),
+        (2000, 2000),
+        "Unmapped ids changed"
+    );
+    let metadata = fs::metadata(&path).expect("Failed to stat source file");
+    assert_eq!((metadata.uid(), metadata.gid()), (1000, 1000));
+
+    // chown through the mount writes the source id
+    std::os::unix::fs::chown(mountpoint.join("other.txt"), Some(0), Some(0))
+        .expect("Failed to chown file");
+    let metadata = fs::metadata(source.join("other.txt")).expect("Failed to stat source file");
+    assert_eq!((metadata.uid(), metadata.gid()), (1000, 1000));
+}
+
+#[test]
+fn test_evicted_handles_reopen() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    for i in 0..16 {
+        fs::write(source.join(format!("f{}", i)), format!("content {}", i))
+            .expect("Failed to write test file");
+    }
+
+    let _guard = MountGuard::with_args(&source, &mountpoint, &["--max-open-files", "4"]);
+
+    let files: Vec<File> = (0..16)
+        .map(|i| {
+            fs::OpenOptions::new()
+                .read(true)
+                .append(true)
+                .open(mountpoint.join(format!("f{}", i)))
+                .expect("Failed to open file")
+        })
+        .collect();
+
+    // The oldest handles lost their backing files long ago
+    for (i, file) in files.iter().enumerate() {
+        let mut buffer = [0u8; 16];
+        let read = file.read_at(&mut buffer, 0).expect("Failed to read file");
+        assert_eq!(&buffer[..read], format!("content {}", i).as_bytes());
+    }
+    (&files[0]).write_all(b"!").expect("Failed to write file");
+    drop(files);
+    assert_eq!(fs::read_to_string(source.join("f0")).unwrap(), "content 0!");
+}
+
+#[test]
+fn test_idle_handles_release_backing_files() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("idle.txt"), "idle content").expect("Failed to write test file");
+
+    let guard = MountGuard::with_args(
+        &source,
+        &mountpoint,
+        &[
+            "--idle-handle-timeout",
+            "1",
+            "--idle-handle-scan-interval",
+            "1",
+        ],
+    );
+
+    let file = File::open(mountpoint.join("idle.txt")).expect("Failed to open file");
+    assert_eq!(guard.open_fds(&source.join("idle.txt")), 1);
+
+    assert!(
+        wait_for(|| guard.open_fds(&source.join("idle.txt")) == 0),
+        "Idle backing file was not closed"
+    );
+
+    // The handle reopens its file on the next read
+    let mut buffer = [0u8; 32];
+    let read = file.read_at(&mut buffer, 0).expect("Failed to read file");
+    assert_eq!(&buffer[..read], b"idle content");
+    assert_eq!(guard.open_fds(&source.join("idle.txt")), 1);
+}
+
+#[test]
+fn test_fsync_directory() {
+    let (source, mountpoint, temp_dir) = setup_test_dirs();
+    let log = temp_dir.path().join("fs.log");
+
+    {
+        let _guard = MountGuard::with_log(&source, &mountpoint, &log);
+
+        // The atomic save pattern: write a temporary file, rename it over
+        // the target, then sync the directory holding both names
+        fs::write(mountpoint.join("save.tmp"), "saved").expect("Failed to write file");
+        fs::rename(mountpoint.join("save.tmp"), mountpoint.join("save.txt"))
+            .expect("Failed to rename file");
+        File::open(&mountpoint)
+            .expect("Failed to open directory")
+            .sync_all()
+            .expect("Failed to sync directory");
+    }
+
+    let log = fs::read_to_string(&log).expect("Failed to read log");
+    assert!(
+        log.contains("fsyncdir:"),
+        "fsyncdir never reached the daemon"
+    );
+    assert_eq!(
+        fs::read_to_string(source.join("save.txt")).unwrap(),
+        "saved"
+    );
+}
+
+#[test]
+fn test_open_honors_nofollow_and_noatime() {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("file.txt"), "content").expect("Failed to write test file");
+    std::os::unix::fs::symlink("file.txt", source.join("link")).expect("Failed to create symlink");
+
+    // An atime older than the mtime would be updated by any read, even
+    // with relatime
+    let old_atime = UNIX_EPOCH + Duration::from_secs(1_000_000);
+    File::open(source.join("file.txt"))
+        .and_then(|file| file.set_times(fs::FileTimes::new().set_accessed(old_atime)))
+        .expect("Failed to set atime");
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    let err = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(mountpoint.join("link"))
+        .expect_err("Opened a symlink with O_NOFOLLOW");
+    assert_eq!(err.raw_os_error(), Some(libc::ELOOP));
+
+    let mut content = String::new();
+    fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOATIME)
+        .open(mountpoint.join("file.txt"))
+        .expect("Failed to open file")
+        .read_to_string(&mut content)
+        .expect("Failed to read file");
+    assert_eq!(content, "content");
+    let atime = fs::metadata(source.join("file.txt")).unwrap().atime();
+    assert_eq!(atime, 1_000_000, "Read updated the source atime");
+}
+
+#[test]
+fn test_writeback_cache_round_trip() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("file.txt"), "0123456789").expect("Failed to write file");
+    let _guard = MountGuard::with_args(&source, &mountpoint, &["--writeback-cache"]);
+
+    // Partial writes through a write-only handle make the kernel read the
+    // rest of the page back
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .open(mountpoint.join("file.txt"))
+        .expect("Failed to open file");
+    file.write_at(b"ab", 2).expect("Failed to write file");
+    file.write_at(b"xyz", 12).expect("Failed to write file");
+    drop(file);
+    assert_eq!(
+        fs::read(source.join("file.txt")).expect("Failed to read source"),
+        b"01ab456789\0\0xyz"
+    );
+
+    let mut file = fs::OpenOptions::new()
+        .append(true)
+        .open(mountpoint.join("file.txt"))
+        .expect("Failed to open file");
+    file.write_all(b"!").expect("Failed to append");
+    drop(file);
+    assert_eq!(
+        fs::read(mountpoint.join("file.txt")).expect("Failed to read file"),
+        b"01ab456789\0\0xyz!"
+    );
+
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .open(mountpoint.join("file.txt"))
+        .expect("Failed to open file");
+    file.set_len(4).expect("Failed to truncate");
+    drop(file);
+    assert_eq!(
+        fs::read(source.join("file.txt")).expect("Failed to read source"),
+        b"01ab"
+    );
+}
+
+/// Sequential write throughput with and without the writeback cache. Run
+/// with `cargo test -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn bench_writeback_cache_sequential_write() {
+    let size = 256 * 1024 * 1024;
+    let chunk = vec![0x5au8; 4096];
+    for args in [&[][..], &["--writeback-cache"][..]] {
+        let (source, mountpoint, _temp_dir) = setup_test_dirs();
+        let _guard = MountGuard::with_args(&source, &mountpoint, args);
+
+        let start = Instant::now();
+        let mut file = File::create(mountpoint.join("bench.bin")).expect("Failed to create file");
+        for _ in 0..size / chunk.len() {
+            file.write_all(&chunk).expect("Failed to write file");
+        }
+        file.sync_all().expect("Failed to sync file");
+        drop(file);
+        let elapsed = start.elapsed();
+
+        println!(
+            "{:?}: {:.1} MB/s",
+            args,
+            size as f64 / elapsed.as_secs_f64() / (1024.0 * 1024.0)
+        );
+    }
+}
+
+#[test]
+fn test_io_threads_serve_parallel_io() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    let _guard = MountGuard::with_args(&source, &mountpoint, &["--io-threads", "4"]);
+
+    let workers: Vec<_> = (0..8)
+        .map(|i| {
+            let path = mountpoint.join(format!("file{}.bin", i));
+            std::thread::spawn(move || {
+                let data = vec![i as u8; 1024 * 1024];
+                fs::write(&path, &data).expect("Failed to write file");
+                assert_eq!(fs::read(&path).expect("Failed to read file"), data);
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    assert_eq!(
+        fs::read(source.join("file7.bin")).unwrap(),
+        vec![7u8; 1024 * 1024]
+    );
+}
+
+#[test]
+fn test_io_threads_overlap_blocked_reads() {
+    let (source, middle, temp_dir) = setup_test_dirs();
+    let mountpoint = temp_dir.path().join("outer");
+    fs::create_dir(&mountpoint).expect("Failed to create mountpoint dir");
+    fs::write(source.join("stuck.bin"), vec![1u8; 4096]).expect("Failed to write test file");
+    fs::write(source.join("free.txt"), "free").expect("Failed to write test file");
+
+    // The outer mount reads from an inner one, which is stopped below so
+    // reads it has not cached block. Long TTLs let its kernel serve cached
+    // pages without asking the stopped daemon about attributes.
+    let inner = MountGuard::with_args(&source, &middle, &["--attr-ttl", "60", "--entry-ttl", "60"]);
+    let _outer = MountGuard::with_args(&middle, &mountpoint, &["--io-threads", "2", "--direct-io"]);
+    let stuck = File::open(mountpoint.join("stuck.bin")).expect("Failed to open file");
+    let free = File::open(mountpoint.join("free.txt")).expect("Failed to open file");
+    let mut buffer = [0u8; 4];
+    free.read_exact_at(&mut buffer, 0).unwrap();
+
+    let inner_pid = inner.pid() as libc::pid_t;
+    unsafe { libc::kill(inner_pid, libc::SIGSTOP) };
+    let blocked = std::thread::spawn(move || {
+        let mut buffer = [0u8; 4096];
+        stuck.read_exact_at(&mut buffer, 0).map(|()| buffer[0])
+    });
+    std::thread::sleep(Duration::from_millis(200));
+
+    // Served by the other worker while the first waits on the inner mount
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let mut buffer = [0u8; 4];
+        let _ = sender.send(free.read_exact_at(&mut buffer, 0).map(|()| buffer));
+    });
+    let result = receiver.recv_timeout(Duration::from_secs(5));
+    let overlapped = !blocked.is_finished();
+    unsafe { libc::kill(inner_pid, libc::SIGCONT) };
+
+    assert_eq!(
+        result.expect("Read queued behind a blocked one").unwrap(),
+        *b"free"
+    );
+    assert!(overlapped, "The first read never blocked");
+    assert_eq!(blocked.join().unwrap().unwrap(), 1);
+}
+
+/// Parallel reads of separate files with one I/O thread and with several.
+/// Run with `cargo test -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn bench_io_threads_parallel_read() {
+    let files = 8;
+    let size = 64 * 1024 * 1024;
+    for threads in ["1", "8"] {
+        let (source, mountpoint, _temp_dir) = setup_test_dirs();
+        for i in 0..files {
+            fs::write(source.join(format!("file{}.bin", i)), vec![0x5au8; size])
+                .expect("Failed to write file");
+        }
+        let _guard = MountGuard::with_args(&source, &mountpoint, &["--io-threads", threads]);
+
+        let start = Instant::now();
+        let readers: Vec<_> = (0..files)
+            .map(|i| {
+                let path = mountpoint.join(format!("file{}.bin", i));
+                std::thread::spawn(move || fs::read(path).expect("Failed to read file").len())
+            })
+            .collect();
+        let total: usize = readers
+            .into_iter()
+            .map(|reader| reader.join().unwrap())
+            .sum();
+        let elapsed = start.elapsed();
+
+        println!(
+            "--io-threads {}: {:.1} MB/s",
+            threads,
+            total as f64 / elapsed.as_secs_f64() / (1024.0 * 1024.0)
+        );
+    }
+}
+
+#[test]
+fn test_sparse_copy_stays_sparse() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    let size = 100 * 1024 * 1024;
+    let sparse = File::create(source.join("sparse.img")).expect("Failed to create file");
+    sparse.set_len(size).expect("Failed to extend file");
+    sparse
+        .write_at(b"data", size / 2)
+        .expect("Failed to write file");
+    drop(sparse);
+
+    let _guard = MountGuard::with_args(&source, &mountpoint, &["--detect-zero-holes"]);
+
+    // A plain read/write copy sees the holes as zeros
+    let mut input = File::open(mountpoint.join("sparse.img")).expect("Failed to open file");
+    let mut output = File::create(mountpoint.join("copy.img")).expect("Failed to create file");
+    let mut buffer = vec![0u8; 128 * 1024];
+    loop {
+        let read = input.read(&mut buffer).expect("Failed to read file");
+        if read == 0 {
+            break;
+        }
+        output
+            .write_all(&buffer[..read])
+            .expect("Failed to write file");
+    }
+    output.sync_all().expect("Failed to sync file");
+    drop(output);
+
+    let metadata = fs::metadata(source.join("copy.img")).expect("Failed to stat copy");
+    assert_eq!(metadata.len(), size);
+    assert!(
+        metadata.blocks() < 1024 * 2,
+        "Copy allocated {} blocks",
+        metadata.blocks()
+    );
+    let mut data = [0u8; 4];
+    File::open(source.join("copy.img"))
+        .and_then(|file| file.read_exact_at(&mut data, size / 2))
+        .expect("Failed to read copy");
+    assert_eq!(&data, b"data");
+}
+
+#[test]
+fn test_directory_nlink_counts_subdirectories() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::create_dir(source.join("parent")).expect("Failed to create directory");
+    for i in 0..3 {
+        fs::create_dir(source.join(format!("root{}", i))).expect("Failed to create directory");
+    }
+    fs::write(source.join("file.txt"), "").expect("Failed to write test file");
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    // The root reports the source root's own count: . and .. plus one per subdirectory
+    let root = fs::metadata(&mountpoint).expect("Failed to stat root");
+    assert_eq!(root.nlink(), 2 + 4);
+
+    let parent = mountpoint.join("parent");
+    assert_eq!(fs::metadata(&parent).unwrap().nlink(), 2);
+    for i in 0..5 {
+        fs::create_dir(parent.join(format!("sub{}", i))).expect("Failed to create directory");
+    }
+    fs::write(parent.join("file.txt"), "").expect("Failed to write test file");
+    assert_eq!(fs::metadata(&parent).unwrap().nlink(), 2 + 5);
+
+    fs::remove_dir(parent.join("sub0")).expect("Failed to remove directory");
+    assert_eq!(fs::metadata(&parent).unwrap().nlink(), 2 + 4);
+}
+
+#[test]
+fn test_rename_onto_same_file() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("file.txt"), "content").expect("Failed to write test file");
+    fs::hard_link(source.join("file.txt"), source.join("link.txt"))
+        .expect("Failed to create hard link");
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    let file = mountpoint.join("file.txt");
+    let ino = fs::metadata(&file).expect("Failed to stat file").ino();
+    fs::rename(&file, &file).expect("Failed to rename file onto itself");
+    assert_eq!(fs::metadata(&file).expect("File vanished").ino(), ino);
+
+    // Both links survive a rename of one onto the other
+    let link = mountpoint.join("link.txt");
+    fs::rename(&file, &link).expect("Failed to rename onto hard link");
+    assert_eq!(fs::metadata(&file).expect("File vanished").ino(), ino);
+    assert_eq!(fs::metadata(&link).expect("Link vanished").ino(), ino);
+    assert!(source.join("file.txt").exists() && source.join("link.txt").exists());
+    assert_eq!(fs::read_to_string(&file).unwrap(), "content");
+}
+
+#[test]
+fn test_inode_flags_ioctl() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("file.txt"), "content").expect("Failed to write test file");
+
+    let get_flags = |file: &File| -> std::io::Result<libc::c_int> {
+        let mut flags: libc::c_int = 0;
+        let ret = unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) };
+        if ret < 0 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(flags)
+        }
+    };
+    let source_file = File::open(source.join("file.txt")).expect("Failed to open file");
+    let Ok(initial) = get_flags(&source_file) else {
+        // The source filesystem has no inode flags
+        return;
+    };
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    let file = File::open(mountpoint.join("file.txt")).expect("Failed to open file");
+    assert_eq!(get_flags(&file).expect("FS_IOC_GETFLAGS failed"), initial);
+
+    // FS_NODUMP_FL from linux/fs.h, which needs no special capability
+    // unlike immutable
+    let flags: libc::c_int = initial | 0x40;
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_SETFLAGS, &flags) };
+    assert_eq!(
+        ret,
+        0,
+        "FS_IOC_SETFLAGS failed: {}",
+        std::io::Error::last_os_error()
+    );
+    assert_eq!(get_flags(&source_file).unwrap(), flags);
+    assert_eq!(get_flags(&file).unwrap(), flags);
+}
+
+/// FS_IOC_FSGETXATTR and FS_IOC_FSSETXATTR from linux/fs.h
+const FS_IOC_FSGETXATTR: libc::Ioctl = 0x801c581f;
+const FS_IOC_FSSETXATTR: libc::Ioctl = 0x401c5820;
+/// FS_XFLAG_NODUMP from linux/fs.h, which needs no special capability
+const FS_XFLAG_NODUMP: u32 = 0x80;
+
+/// Extended inode flags of `file`, as `xfs_io -c lsattr` reads them
+fn get_xflags(file: &File) -> std::io::Result<u32> {
+    let mut fsxattr = [0u8; 28];
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FSGETXATTR, fsxattr.as_mut_ptr()) };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(u32::from_ne_bytes(fsxattr[..4].try_into().unwrap()))
+}
+
+/// Add `flags` to the extended inode flags of `file`, as `xfs_io -c chattr`
+fn add_xflags(file: &File, flags: u32) -> std::io::Result<()> {
+    let mut fsxattr = [0u8; 28];
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FSGETXATTR, fsxattr.as_mut_ptr()) };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let xflags = u32::from_ne_bytes(fsxattr[..4].try_into().unwrap()) | flags;
+    fsxattr[..4].copy_from_slice(&xflags.to_ne_bytes());
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FSSETXATTR, fsxattr.as_ptr()) };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_read_only_refuses_fssetxattr() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("file.txt"), "content").expect("Failed to write test file");
+    let source_file = File::open(source.join("file.txt")).expect("Failed to open file");
+    let Ok(initial) = get_xflags(&source_file) else {
+        // The source filesystem has no extended inode flags
+        return;
+    };
+
+    // Mounted writable in the kernel, so only the daemon can refuse
+    let mut config = fuse_passthrough::PassthroughConfig::new(&source);
+    config.read_only = true;
+    let options: Vec<_> = config
+        .mount_options()
+        .into_iter()
+        .filter(|option| *option != fuse_passthrough::MountOption::RO)
+        .collect();
+    let fs = fuse_passthrough::PassthroughFS::with_config(config).unwrap();
+    let _mount = fuse_passthrough::mount_fs(fs, &mountpoint, &options).expect("Failed to mount");
+    assert!(wait_for(|| mountpoint.join("file.txt").exists()));
+
+    let file = File::open(mountpoint.join("file.txt")).expect("Failed to open file");
+    let err = add_xflags(&file, FS_XFLAG_NODUMP).expect_err("FS_IOC_FSSETXATTR succeeded");
+    assert_eq!(err.raw_os_error(), Some(libc::EROFS));
+    assert_eq!(get_xflags(&source_file).unwrap(), initial);
+}
+
+#[test]
+fn test_fssetxattr_copies_up_lower_files() {
+    let (source, mountpoint, temp_dir) = setup_test_dirs();
+    let lower = temp_dir.path().join("lower");
+    fs::create_dir(&lower).expect("Failed to create lower directory");
+    fs::write(lower.join("file.txt"), "lower").expect("Failed to write lower file");
+    let lower_file = File::open(lower.join("file.txt")).expect("Failed to open file");
+    let Ok(initial) = get_xflags(&lower_file) else {
+        // The source filesystem has no extended inode flags
+        return;
+    };
+
+    let lower_arg = lower.to_str().unwrap();
+    let _guard = MountGuard::with_args(&source, &mountpoint, &["--lower", lower_arg]);
+    let file = File::open(mountpoint.join("file.txt")).expect("Failed to open file");
+    add_xflags(&file, FS_XFLAG_NODUMP).expect("FS_IOC_FSSETXATTR failed");
+
+    assert_eq!(
+        get_xflags(&lower_file).unwrap(),
+        initial,
+        "Lower file changed"
+    );
+    let upper_file = File::open(source.join("file.txt")).expect("File not copied up");
+    assert_ne!(get_xflags(&upper_file).unwrap() & FS_XFLAG_NODUMP, 0);
+    assert_eq!(fs::read(source.join("file.txt")).unwrap(), b"lower");
+}
+
+#[test]
+fn test_chown_symlink_leaves_target() {
+    if unsafe { libc::geteuid() } != 0 {
+        // chown to another user needs root
+        return;
+    }
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("target.txt"), "").expect("Failed to write test file");
+    std::os::unix::fs::symlink("target.txt", source.join("link"))
+        .expect("Failed to create symlink");
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    // chown -h
+    std::os::unix::fs::lchown(mountpoint.join("link"), Some(1000), None)
+        .expect("Failed to chown symlink");
+
+    let link = fs::symlink_metadata(source.join("link")).expect("Failed to stat symlink");
+    assert_eq!((link.uid(), link.gid()), (1000, 0), "gid changed too");
+    let target = fs::metadata(source.join("target.txt")).expect("Failed to stat target");
+    assert_eq!(target.uid(), 0, "Target owner changed");
+    let link = fs::symlink_metadata(mountpoint.join("link")).expect("Failed to stat symlink");
+    assert_eq!(link.uid(), 1000);
+}
+
+#[test]
+fn test_chmod_keeps_special_bits() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("tool"), "#!/bin/sh\n").expect("Failed to write test file");
+    fs::create_dir(source.join("shared")).expect("Failed to create directory");
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    for (name, mode) in [("tool", 0o4755), ("tool", 0o2755), ("shared", 0o1777)] {
+        let path = mountpoint.join(name);
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode))
+            .expect("Failed to set permissions");
+        let through_mount = fs::metadata(&path).expect("Failed to stat file").mode() & 0o7777;
+        assert_eq!(
+            through_mount, mode,
+            "{} reported as {:o}",
+            name, through_mount
+        );
+        let in_source = fs::metadata(source.join(name)).unwrap().mode() & 0o7777;
+        assert_eq!(in_source, mode, "{} stored as {:o}", name, in_source);
+    }
+}
+
+#[test]
+fn test_set_mtime() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+
+    // Create a test file
+    fs::write(source.join("times.txt"), "test").expect("Failed to write test file");
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    // Set mtime to a fixed point in time through mountpoint
+    let mtime = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+    File::options()
+        .write(true)
+        .open(mountpoint.join("times.txt"))
+        .expect("Failed to open file")
+        .set_modified(mtime)
+        .expect("Failed to set mtime");
+
+    // Verify both the source and the mountpoint see the new mtime
+    let source_mtime = fs::metadata(source.join("times.txt"))
+        .expect("Failed to get metadata from source")
+        .modified()
+        .expect("Failed to read mtime");
+    assert_eq!(source_mtime, mtime, "Source mtime not updated");
+
+    assert!(
+        wait_for(|| {
+            fs::metadata(mountpoint.join("times.txt"))
+                .and_then(|metadata| metadata.modified())
+                .map(|t| t == mtime)
+                .unwrap_or(false)
+        }),
+        "Mountpoint mtime not updated"
+    );
+}
+
+/// Set atime and mtime of `file` with futimens
+fn futimens(file: &File, times: [libc::timespec; 2]) {
+    let ret = unsafe { libc::futimens(file.as_raw_fd(), times.as_ptr()) };
+    assert_eq!(
+        ret,
+        0,
+        "futimens failed: {}",
+        std::io::Error::last_os_error()
+    );
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_noatime_leaves_source_atime_alone() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("file.txt"), "content").expect("Failed to write test file");
+    // An atime older than the mtime is updated on the next read even
+    // under relatime
+    let old = libc::timespec {
+        tv_sec: 1_000_000_000,
+        tv_nsec: 0,
+    };
+    let now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: libc::UTIME_NOW,
+    };
+    futimens(&File::open(source.join("file.txt")).unwrap(), [old, now]);
+
+    let _guard = MountGuard::with_args(&source, &mountpoint, &["--noatime"]);
+
+    assert_eq!(
+        fs::read_to_string(mountpoint.join("file.txt")).unwrap(),
+        "content"
+    );
+    let atime = fs::metadata(source.join("file.txt")).unwrap().atime();
+    assert_eq!(atime, old.tv_sec, "Reading through the mount updated atime");
+}
+
+#[test]
+fn test_write_bps_delays_writes() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    let _guard = MountGuard::with_args(&source, &mountpoint, &["--write-bps", "131072"]);
+
+    // The first second's worth passes at once, the other three must wait
+    // for the bucket to refill
+    let data = vec![b'x'; 4 * 131072];
+    let start = Instant::now();
+    let mut file = File::create(mountpoint.join("large.bin")).expect("Failed to create file");
+    file.write_all(&data).expect("Failed to write");
+    file.sync_all().expect("Failed to sync");
+    let elapsed = start.elapsed();
+    drop(file);
+
+    assert!(
+        elapsed >= Duration::from_secs(3),
+        "Writing took {:?}, less than the rate allows",
+        elapsed
+    );
+    assert_eq!(fs::read(source.join("large.bin")).unwrap(), data);
+}
+
+#[test]
+fn test_read_bps_charges_bytes_returned() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("small.txt"), "0123456789").expect("Failed to write test file");
+    let _guard = MountGuard::with_args(&source, &mountpoint, &["--read-bps", "1000"]);
+
+    // The kernel asks for at least a page each time, which would take
+    // seconds to pay for, but only ten bytes come back
+    let start = Instant::now();
+    for _ in 0..5 {
+        assert_eq!(
+            fs::read_to_string(mountpoint.join("small.txt")).unwrap(),
+            "0123456789"
+        );
+    }
+    let elapsed = start.elapsed();
+    assert!(
+        elapsed < Duration::from_secs(2),
+        "Short reads took {:?}, charged for more than they read",
+        elapsed
+    );
+}
+
+#[test]
+fn test_case_insensitive_lookup() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("file.txt"), "content").expect("Failed to write test file");
+    fs::create_dir(source.join("Dir")).expect("Failed to create directory");
+    fs::write(source.join("Dir/b.txt"), "lower").unwrap();
+    fs::write(source.join("Dir/B.TXT"), "upper").unwrap();
+
+    let _guard = MountGuard::with_args(&source, &mountpoint, &["--case-insensitive"]);
+
+    assert_eq!(
+        fs::read_to_string(mountpoint.join("FILE.TXT")).unwrap(),
+        "content"
+    );
+    assert_eq!(
+        fs::metadata(mountpoint.join("File.Txt")).unwrap().ino(),
+        fs::metadata(mountpoint.join("file.txt")).unwrap().ino()
+    );
+    // Exact spellings win, and of several other spellings the first in
+    // byte order does
+    assert_eq!(
+        fs::read_to_string(mountpoint.join("dir/b.txt")).unwrap(),
+        "lower"
+    );
+    assert_eq!(
+        fs::read_to_string(mountpoint.join("DIR/b.Txt")).unwrap(),
+        "upper"
+    );
+
+    // Renaming onto another spelling replaces the entry rather than
+    // adding a second one
+    fs::write(source.join("new.txt"), "new").unwrap();
+    fs::rename(mountpoint.join("NEW.TXT"), mountpoint.join("FILE.TXT")).unwrap();
+    assert_eq!(fs::read_to_string(source.join("file.txt")).unwrap(), "new");
+    assert!(!source.join("FILE.TXT").exists());
+
+    fs::remove_file(mountpoint.join("File.txt")).unwrap();
+    assert!(!source.join("file.txt").exists());
+}
+
+#[test]
+fn test_inode_db_keeps_inodes_across_mounts() {
+    let (source, mountpoint, temp_dir) = setup_test_dirs();
+    fs::create_dir(source.join("dir")).expect("Failed to create directory");
+    fs::write(source.join("dir/a.txt"), "a").expect("Failed to write test file");
+    fs::write(source.join("b.txt"), "b").expect("Failed to write test file");
+    let db = temp_dir.path().join("inodes");
+    let args = ["--inode-db", db.to_str().unwrap()];
+
+    let inode = |path: &str| fs::metadata(mountpoint.join(path)).unwrap().ino();
+    let guard = MountGuard::with_args(&source, &mountpoint, &args);
+    let before = [inode("dir"), inode("dir/a.txt"), inode("b.txt")];
+    // Handles hold the number and generation lookups return
+    let mut handle = name_to_handle(&mountpoint.join("dir/a.txt")).expect("No file handle");
+    drop(guard);
+
+    // Looked up in another order, and after a new file, the numbers
+    // would come out differently if they were handed out afresh
+    fs::write(source.join("c.txt"), "c").expect("Failed to write test file");
+    let _guard = MountGuard::with_args(&source, &mountpoint, &args);
+    let new = inode("c.txt");
+    let after = [inode("dir"), inode("dir/a.txt"), inode("b.txt")];
+    assert_eq!(after, before);
+    assert!(!before.contains(&new));
+
+    // The generation is the same too, so handles survive the remount
+    assert_eq!(
+        name_to_handle(&mountpoint.join("dir/a.txt")).expect("No file handle"),
+        handle
+    );
+    let root = File::open(&mountpoint).expect("Failed to open mountpoint");
+    let mut content = String::new();
+    open_by_handle(&root, &mut handle)
+        .expect("Handle went stale across the remount")
+        .read_to_string(&mut content)
+        .unwrap();
+    assert_eq!(content, "a");
+}
+
+#[test]
+fn test_io_survives_signals_to_the_daemon() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    let guard = MountGuard::new(&source, &mountpoint);
+    let pid = guard.pid() as libc::pid_t;
+
+    // Keep stopping and resuming the daemon in the middle of its calls
+    let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let signaller = {
+        let done = Arc::clone(&done);
+        std::thread::spawn(move || {
+            while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                unsafe { libc::kill(pid, libc::SIGSTOP) };
+                std::thread::sleep(Duration::from_millis(1));
+                unsafe { libc::kill(pid, libc::SIGCONT) };
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        })
+    };
+
+    let data: Vec<u8> = (0..8 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let path = mountpoint.join("signalled.bin");
+    let mut file = File::create(&path).expect("Failed to create file");
+    file.write_all(&data).expect("Write failed under signals");
+    file.sync_all().expect("fsync failed under signals");
+    drop(file);
+    let read_back = fs::read(&path).expect("Read failed under signals");
+
+    done.store(true, std::sync::atomic::Ordering::Relaxed);
+    signaller.join().unwrap();
+    unsafe { libc::kill(pid, libc::SIGCONT) };
+    assert!(read_back == data, "Data changed under signals");
+}
+
+#[test]
+fn test_set_times_to_now() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("now.txt"), "test").expect("Failed to write test file");
+
+    let _guard = MountGuard::with_args(&source, &mountpoint, &["--attr-ttl", "0"]);
+    let file = File::options()
+        .write(true)
+        .open(mountpoint.join("now.txt"))
+        .expect("Failed to open file");
+    let now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: libc::UTIME_NOW,
+    };
+
+    // Both set to now resolve to the very same instant
+    futimens(&file, [now, now]);
+    let metadata = fs::metadata(mountpoint.join("now.txt")).expect("Failed to stat file");
+    assert_eq!(
+        (metadata.atime(), metadata.atime_nsec()),
+        (metadata.mtime(), metadata.mtime_nsec())
+    );
+
+    // Only atime is set to now when mtime is given explicitly
+    let mtime = libc::timespec {
+        tv_sec: 1_000_000_000,
+        tv_nsec: 5,
+    };
+    futimens(&file, [now, mtime]);
+    let metadata = fs::metadata(mountpoint.join("now.txt")).expect("Failed to stat file");
+    assert_eq!(
+        (metadata.mtime(), metadata.mtime_nsec()),
+        (1_000_000_000, 5)
+    );
+    assert!(metadata.atime() > 1_000_000_000);
+}
+
+#[test]
+fn test_nanosecond_timestamps() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("precise.txt"), "content").expect("Failed to write test file");
+
+    let mtime = UNIX_EPOCH + Duration::new(1_600_000_000, 123_456_789);
+    File::options()
+        .write(true)
+        .open(source.join("precise.txt"))
+        .and_then(|file| file.set_modified(mtime))
+        .expect("Failed to set mtime");
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    let mounted = fs::metadata(mountpoint.join("precise.txt")).expect("Failed to stat file");
+    assert_eq!(mounted.modified().unwrap(), mtime);
+    assert_eq!(mounted.mtime_nsec(), 123_456_789);
+
+    // ctime cannot be set, but its nanoseconds must survive as well
+    let original = fs::metadata(source.join("precise.txt")).expect("Failed to stat file");
+    assert_eq!(mounted.ctime(), original.ctime());
+    assert_eq!(mounted.ctime_nsec(), original.ctime_nsec());
+}
+
+/// Only macOS passes crtime through FUSE
+#[cfg(target_os = "macos")]
+#[test]
+fn test_crtime_is_birth_time() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    fs::write(mountpoint.join("born.txt"), "content").expect("Failed to write file");
+
+    let created = fs::metadata(mountpoint.join("born.txt"))
+        .and_then(|metadata| metadata.created())
+        .expect("Failed to get creation time");
+    let age = std::time::SystemTime::now()
+        .duration_since(created)
+        .expect("Creation time is in the future");
+    assert!(
+        age < Duration::from_secs(60),
+        "Creation time is {:?} old",
+        age
+    );
+}
+
+#[test]
+fn test_truncate_file() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+
+    // Create a test file with content
+    let original_content = "This is a long content that will be truncated";
+    fs::write(source.join("truncate.txt"), original_content).expect("Failed to write test file");
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    // Truncate file through mountpoint
+    {
+        let file = File::options()
+            .write(true)
+            .open(mountpoint.join("truncate.txt"))
+            .expect("Failed to open file");
+        file.set_len(10).expect("Failed to truncate file");
+    }
+
+    // Wait for truncation to complete
+    let source_file = source.join("truncate.txt");
+    assert!(
+        wait_for(|| {
+            if let Ok(metadata) = fs::metadata(&source_file) {
+                metadata.len() == 10
+            } else {
+                false
+            }
+        }),
+        "File not truncated"
+    );
+
+    // Verify content
+    let content = fs::read_to_string(&source_file).expect("Failed to read file");
+    assert_eq!(content, "This is a ");
+}
+
+#[test]
+fn test_ftruncate_open_handle() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("handle.txt"), "0123456789").expect("Failed to write test file");
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(mountpoint.join("handle.txt"))
+        .expect("Failed to open file");
+
+    // Revoke write permission on the path; the RDWR handle keeps working
+    fs::set_permissions(source.join("handle.txt"), fs::Permissions::from_mode(0o444))
+        .expect("Failed to set permissions");
+
+    file.set_len(4).expect("Failed to truncate through handle");
+    assert_eq!(file.metadata().unwrap().len(), 4);
+    assert_eq!(
+        fs::read_to_string(source.join("handle.txt")).unwrap(),
+        "0123"
+    );
+
+    // Growing also goes through the handle
+    file.set_len(8).expect("Failed to extend through handle");
+    assert_eq!(fs::metadata(source.join("handle.txt")).unwrap().len(), 8);
+}
+
+#[test]
+fn test_rename_across_directories() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+
+    // Create source directory structure
+    fs::create_dir(source.join("dir1")).expect("Failed to create dir1");
+    fs::create_dir(source.join("dir2")).expect("Failed to create dir2");
+    let test_content = "Moving file";
+    fs::write(source.join("dir1/file.txt"), test_content).expect("Failed to write test file");
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    // Move file from dir1 to dir2 through mountpoint
+    fs::rename(
+        mountpoint.join("dir1/file.txt"),
+        mountpoint.join("dir2/file.txt"),
+    )
+    .expect("Failed to rename file across directories");
+
+    // Wait for move to complete
+    assert!(
+        wait_for_file(&source.join("dir2/file.txt")),
+        "File not found in dir2"
+    );
+    assert!(
+        wait_for_file_gone(&source.join("dir1/file.txt")),
+        "File still exists in dir1"
+    );
+
+    let content = fs::read_to_string(source.join("dir2/file.txt")).expect("Failed to read file");
+    assert_eq!(content, test_content);
+}
+
+#[test]
+fn test_rename_directory_keeps_child_inodes() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+
+    // Create a directory containing a file
+    fs::create_dir(source.join("dir1")).expect("Failed to create dir1");
+    let test_content = "Child content";
+    fs::write(source.join("dir1/file.txt"), test_content).expect("Failed to write test file");
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    // Look up the child and keep a handle on its inode
+    let file = File::open(mountpoint.join("dir1/file.txt")).expect("Failed to open file");
+    let before = file.metadata().expect("Failed to stat file before rename");
+
+    // Rename the parent directory through mountpoint
+    fs::rename(mountpoint.join("dir1"), mountpoint.join("dir2"))
+        .expect("Failed to rename directory");
+
+    // Let the kernel attribute cache expire so getattr reaches the filesystem
+    std::thread::sleep(Duration::from_millis(1500));
+
+    let after = file.metadata().expect("Failed to stat file after rename");
+    assert_eq!(after.ino(), before.ino(), "Inode changed after rename");
+    assert_eq!(after.len(), test_content.len() as u64, "Size mismatch");
+
+    let by_path =
+        fs::metadata(mountpoint.join("dir2/file.txt")).expect("Failed to stat renamed path");
+    assert_eq!(
+        by_path.ino(),
+        before.ino(),
+        "Path resolves to a different inode"
+    );
+}
+
+#[test]
+fn test_rename_noreplace() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+
+    // Create both the file to move and an existing target
+    fs::write(source.join("from.txt"), "from").expect("Failed to write test file");
+    fs::write(source.join("to.txt"), "to").expect("Failed to write test file");
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    // RENAME_NOREPLACE must refuse to overwrite the target
+    let result = renameat2(
+        &mountpoint.join("from.txt"),
+        &mountpoint.join("to.txt"),
+        libc::RENAME_NOREPLACE,
+    );
+    assert!(result.is_err(), "Expected error when target exists");
+    assert_eq!(
+        result.unwrap_err().kind(),
+        std::io::ErrorKind::AlreadyExists
+    );
+
+    // Both files remain untouched
+    assert_eq!(fs::read_to_string(source.join("from.txt")).unwrap(), "from");
+    assert_eq!(fs::read_to_string(source.join("to.txt")).unwrap(), "to");
+
+    // Without a collision it behaves like a plain rename
+    renameat2(
+        &mountpoint.join("from.txt"),
+        &mountpoint.join("new.txt"),
+        libc::RENAME_NOREPLACE,
+    )
+    .expect("Failed to rename without collision");
+    assert_eq!(fs::read_to_string(source.join("new.txt")).unwrap(), "from");
+}
+
+#[test]
+fn test_rename_exchange() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+
+    fs::write(source.join("a.txt"), "content a").expect("Failed to write test file");
+    fs::write(source.join("b.txt"), "content b").expect("Failed to write test file");
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    let a_before = fs::metadata(mountpoint.join("a.txt")).expect("Failed to stat a.txt");
+    let b_before = fs::metadata(mountpoint.join("b.txt")).expect("Failed to stat b.txt");
+
+    // RENAME_EXCHANGE swaps both names atomically
+    renameat2(
+        &mountpoint.join("a.txt"),
+        &mountpoint.join("b.txt"),
+        libc::RENAME_EXCHANGE,
+    )
+    .expect("Failed to exchange files");
+
+    assert_eq!(
+        fs::read_to_string(source.join("a.txt")).unwrap(),
+        "content b"
+    );
+    assert_eq!(
+        fs::read_to_string(source.join("b.txt")).unwrap(),
+        "content a"
+    );
+
+    // Inodes follow their files, and reads go to the right backing file
+    let a_after = fs::metadata(mountpoint.join("a.txt")).expect("Failed to stat a.txt");
+    let b_after = fs::metadata(mountpoint.join("b.txt")).expect("Failed to stat b.txt");
+    assert_eq!(a_after.ino(), b_before.ino());
+    assert_eq!(b_after.ino(), a_before.ino());
+    assert_eq!(
+        fs::read_to_string(mountpoint.join("a.txt")).unwrap(),
+        "content b"
+    );
+    assert_eq!(
+        fs::read_to_string(mountpoint.join("b.txt")).unwrap(),
+        "content a"
+    );
+}
+
+#[test]
+fn test_readdir_snapshot_is_stable() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+
+    // Enough long names that the listing spans several readdir requests
+    let mut expected: Vec<String> = (0..200)
+        .map(|i| format!("entry_with_a_fairly_long_name_{:04}.txt", i))
+        .collect();
+    for name in &expected {
+        fs::write(source.join(name), "").expect("Failed to write test file");
+    }
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    let mut entries = fs::read_dir(&mountpoint).expect("Failed to read directory");
+    let first = entries
+        .next()
+        .expect("Directory is empty")
+        .expect("Failed to read entry");
+
+    // Modify the directory while the listing is in progress
+    fs::write(source.join("added_later.txt"), "").expect("Failed to write test file");
+
+    let mut names: Vec<String> = std::iter::once(first)
+        .chain(entries.map(|entry| entry.expect("Failed to read entry")))
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .collect();
+
+    names.sort();
+    expected.sort();
+    assert_eq!(names, expected);
+
+    // A fresh listing sees the new file
+    let fresh = fs::read_dir(&mountpoint)
+        .expect("Failed to read directory")
+        .count();
+    assert_eq!(fresh, 201);
+}
+
+#[test]
+fn test_readdir_resumes_from_saved_offset() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    let expected: Vec<String> = (0..500)
+        .map(|i| format!("entry_with_a_fairly_long_name_{:04}.txt", i))
+        .collect();
+    fs::create_dir(source.join("dir")).expect("Failed to create directory");
+    for name in &expected {
+        fs::write(source.join("dir").join(name), "").expect("Failed to write test file");
+    }
+
+    for args in [&[][..], &["--no-readdirplus"][..]] {
+        let _guard = MountGuard::with_args(&source, &mountpoint, args);
+        let path = std::ffi::CString::new(mountpoint.join("dir").to_str().unwrap()).unwrap();
+        let dir = unsafe { libc::opendir(path.as_ptr()) };
+        assert!(
+            !dir.is_null(),
+            "opendir failed: {}",
+            std::io::Error::last_os_error()
+        );
+
+        // Each name with the offset to resume from just after it
+        let read_rest = || {
+            let mut entries = Vec::new();
+            loop {
+                let entry = unsafe { libc::readdir(dir) };
+                if entry.is_null() {
+                    return entries;
+                }
+                let (name, ino) = unsafe {
+                    let name = std::ffi::CStr::from_ptr((*entry).d_name.as_ptr());
+                    (name.to_string_lossy().into_owned(), (*entry).d_ino)
+                };
+                entries.push((name, ino, unsafe { libc::telldir(dir) }));
+            }
+        };
+        let entries = read_rest();
+
+        let mut names: Vec<&str> = entries.iter().map(|(name, ..)| name.as_str()).collect();
+        assert_eq!(names.iter().filter(|name| **name == ".").count(), 1);
+        assert_eq!(names.iter().filter(|name| **name == "..").count(), 1);
+        names.retain(|name| *name != "." && *name != "..");
+        names.sort();
+        assert_eq!(names, expected, "Listing has gaps or duplicates");
+
+        // .. is the parent, the mount root
+        let root_ino = fs::metadata(&mountpoint).unwrap().ino();
+        let dotdot = entries.iter().find(|(name, ..)| name == "..").unwrap();
+        assert_eq!(dotdot.1, root_ino);
+
+        // Seeking back to a saved offset picks up exactly where it left off
+        let middle = entries.len() / 2;
+        unsafe { libc::seekdir(dir, entries[middle].2) };
+        let rest = read_rest();
+        assert_eq!(rest, entries[middle + 1..]);
+        unsafe { libc::closedir(dir) };
+    }
+}
+
+#[test]
+fn test_root_dotdot_is_root() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("file.txt"), "").expect("Failed to write test file");
+
+    for args in [&[][..], &["--no-readdirplus"][..]] {
+        let _guard = MountGuard::with_args(&source, &mountpoint, args);
+        let root_ino = fs::metadata(&mountpoint).unwrap().ino();
+
+        let path = std::ffi::CString::new(mountpoint.to_str().unwrap()).unwrap();
+        let dir = unsafe { libc::opendir(path.as_ptr()) };
+        assert!(!dir.is_null());
+        let mut dots = Vec::new();
+        loop {
+            let entry = unsafe { libc::readdir(dir) };
+            if entry.is_null() {
+                break;
+            }
+            let (name, ino) = unsafe {
+                let name = std::ffi::CStr::from_ptr((*entry).d_name.as_ptr());
+                (name.to_string_lossy().into_owned(), (*entry).d_ino)
+            };
+            if name == "." || name == ".." {
+                dots.push((name, ino));
+            }
+        }
+        unsafe { libc::closedir(dir) };
+
+        dots.sort();
+        assert_eq!(
+            dots,
+            [(".".to_string(), root_ino), ("..".to_string(), root_ino)]
+        );
+    }
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_o_direct_aligned_io() {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    #[repr(C, align(4096))]
+    struct Block([u8; 4096]);
+
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    let path = mountpoint.join("direct.bin");
+    let file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(&path)
+        .expect("Failed to open with O_DIRECT");
+
+    let blocks = [Block([0xa5; 4096]), Block([0x5a; 4096])];
+    for (i, block) in blocks.iter().enumerate() {
+        file.write_all_at(&block.0, i as u64 * 4096)
+            .expect("Aligned direct write failed");
+    }
+    let mut read_back = Block([0; 4096]);
+    file.read_exact_at(&mut read_back.0, 4096)
+        .expect("Aligned direct read failed");
+    assert!(read_back.0.iter().all(|&byte| byte == 0x5a));
+
+    // The source refuses unaligned direct I/O, as it would without the mount
+    let err = file.write_at(&blocks[0].0[..100], 1).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+
+    let mut contents = vec![0xa5u8; 4096];
+    contents.extend([0x5a; 4096]);
+    assert_eq!(fs::read(source.join("direct.bin")).unwrap(), contents);
+
+    // A reopened handle keeps bypassing the cache
+    drop(file);
+    let file = File::options()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(&path)
+        .expect("Failed to reopen with O_DIRECT");
+    file.read_exact_at(&mut read_back.0, 0)
+        .expect("Aligned direct read failed");
+    assert!(read_back.0.iter().all(|&byte| byte == 0xa5));
+}
+
+#[test]
+fn test_direct_io_sees_source_changes_at_once() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("file.txt"), "first").expect("Failed to write file");
+
+    let _guard = MountGuard::with_args(&source, &mountpoint, &["--direct-io"]);
+
+    // Reopening drops cached pages anyway, so one handle reads both times
+    let path = mountpoint.join("file.txt");
+    let mut file = File::open(&path).unwrap();
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).unwrap();
+    assert_eq!(contents, "first");
+    // Same length, so the cached size does not hide the change either
+    fs::write(source.join("file.txt"), "again").expect("Failed to write file");
+    contents.clear();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.read_to_string(&mut contents).unwrap();
+    assert_eq!(contents, "again");
+
+    // Nothing is cached for shared mappings to use
+    let mapped = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            5,
+            libc::PROT_READ,
+            libc::MAP_SHARED,
+            file.as_raw_fd(),
+            0,
+        )
+    };
+    assert_eq!(
+        mapped,
+        libc::MAP_FAILED,
+        "Shared mmap of a direct_io file succeeded"
+    );
+}
+
+#[test]
+fn test_open_truncate() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("long.txt"), "a much longer original content").expect("Failed to write");
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    // Read-only opens never truncate
+    drop(File::open(mountpoint.join("long.txt")).expect("Failed to open file"));
+    assert_eq!(
+        fs::read_to_string(source.join("long.txt")).unwrap(),
+        "a much longer original content"
+    );
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(mountpoint.join("long.txt"))
+        .expect("Failed to open file");
+    file.write_all(b"short").expect("Failed to write");
+    drop(file);
+
+    assert_eq!(
+        fs::read_to_string(source.join("long.txt")).unwrap(),
+        "short"
+    );
+    assert_eq!(
+        fs::read_to_string(mountpoint.join("long.txt")).unwrap(),
+        "short"
+    );
+}
+
+#[test]
+fn test_append_write() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+
+    // Create initial file
+    let initial_content = "Initial content\n";
+    fs::write(source.join("append.txt"), initial_content).expect("Failed to write test file");
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    // Append to file through mountpoint
+    let append_content = "Appended content";
+    {
+        let mut file = File::options()
+            .append(true)
+            .open(mountpoint.join("append.txt"))
+            .expect("Failed to open file for append");
+        file.write_all(append_content.as_bytes())
+            .expect("Failed to append");
+        file.sync_all().expect("Failed to sync");
+    }
+
+    // Wait for append to complete
+    let expected_len = initial_content.len() + append_content.len();
+    let source_file = source.join("append.txt");
+    assert!(
+        wait_for(|| {
+            if let Ok(metadata) = fs::metadata(&source_file) {
+                metadata.len() == expected_len as u64
+            } else {
+                false
+            }
+        }),
+        "Append not completed"
+    );
+
+    // Verify content
+    let content = fs::read_to_string(&source_file).expect("Failed to read file");
+    assert_eq!(content, format!("{}{}", initial_content, append_content));
+}
+
+#[test]
+fn test_fdatasync() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    let mut file = File::create(mountpoint.join("synced.txt")).expect("Failed to create file");
+    file.write_all(b"durable data").expect("Failed to write");
+    file.sync_data().expect("fdatasync failed");
+
+    assert_eq!(
+        fs::read_to_string(source.join("synced.txt")).unwrap(),
+        "durable data"
+    );
+
+    // A full fsync works as well
+    file.write_all(b" and more").expect("Failed to write");
+    file.sync_all().expect("fsync failed");
+    assert_eq!(
+        fs::read_to_string(source.join("synced.txt")).unwrap(),
+        "durable data and more"
+    );
+}
+
+#[test]
+fn test_large_file() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    // Create a large file (1MB) through mountpoint
+    let size = 1024 * 1024; // 1MB
+    let data: Vec<u8> = (0..size).map(|i| (i % 256) as u8).collect();
+
+    {
+        let mut file = File::create(mountpoint.join("large.bin")).expect("Failed to create file");
+        file.write_all(&data).expect("Failed to write large file");
+        file.sync_all().expect("Failed to sync");
+    }
+
+    // Wait for file to be fully written
+    let source_file = source.join("large.bin");
+    assert!(
+        wait_for(|| {
+            if let Ok(metadata) = fs::metadata(&source_file) {
+                metadata.len() == size as u64
+            } else {
+                false
+            }
+        }),
+        "Large file not fully written"
+    );
+
+    // Read back and verify content
+    let read_data = fs::read(mountpoint.join("large.bin")).expect("Failed to read large file");
+    assert_eq!(read_data.len(), size);
+    assert_eq!(read_data, data, "Content mismatch in large file");
+}
+
+#[test]
+fn test_concurrent_positioned_writes() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    // Several threads share one handle and each fill their own region
+    let threads = 8;
+    let chunk = 64 * 1024;
+    let file =
+        Arc::new(File::create(mountpoint.join("concurrent.bin")).expect("Failed to create file"));
+
+    let handles: Vec<_> = (0..threads)
+        .map(|i| {
+            let file = Arc::clone(&file);
+            std::thread::spawn(move || {
+                let data = vec![i as u8 + 1; chunk];
+                file.write_all_at(&data, (i * chunk) as u64)
+                    .expect("Failed to write chunk");
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("Writer thread panicked");
+    }
+    file.sync_all().expect("Failed to sync");
+
+    // Verify every region holds its own thread's bytes
+    let content = fs::read(source.join("concurrent.bin")).expect("Failed to read from source");
+    assert_eq!(content.len(), threads * chunk, "Size mismatch");
+    for (i, region) in content.chunks(chunk).enumerate() {
+        assert!(
+            region.iter().all(|&b| b == i as u8 + 1),
+            "Region {} corrupted",
+            i
+        );
+    }
+}
+
+#[test]
+fn test_multi_megabyte_write() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    // Write 8MB in a single write_all call
+    let size = 8 * 1024 * 1024;
+    let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+    {
+        let mut file = File::create(mountpoint.join("multi.bin")).expect("Failed to create file");
+        file.write_all(&data).expect("Failed to write file");
+        file.sync_all().expect("Failed to sync");
+    }
+
+    // Verify the source matches byte-for-byte
+    let content = fs::read(source.join("multi.bin")).expect("Failed to read from source");
+    assert_eq!(content.len(), size, "Size mismatch");
+    assert!(content == data, "Content mismatch in source file");
+}
+
+#[test]
+fn test_large_writes_use_big_requests() {
+    let (source, mountpoint, temp_dir) = setup_test_dirs();
+    let log = temp_dir.path().join("fs.log");
+
+    {
+        let _guard = MountGuard::with_log(&source, &mountpoint, &log);
+
+        let mut file = fs::File::create(mountpoint.join("big.bin")).expect("Failed to create file");
+        file.write_all(&vec![0x5au8; 4 * 1024 * 1024])
+            .expect("Failed to write file");
+        file.sync_all().expect("Failed to sync file");
+    }
+
+    // The kernel splits writes at the negotiated max_write, which without
+    // init stays at 128 KiB
+    let log = fs::read_to_string(&log).expect("Failed to read log");
+    let largest = log
+        .lines()
+        .filter(|line| line.contains("write: ino="))
+        .filter_map(|line| line.rsplit("size=").next()?.trim().parse::<usize>().ok())
+        .max()
+        .expect("No write requests logged");
+    assert!(
+        largest > 128 * 1024,
+        "Largest write request was only {} bytes",
+        largest
+    );
+}
+
+#[test]
+fn test_trace_log_names_caller() {
+    let (source, mountpoint, temp_dir) = setup_test_dirs();
+    let log = temp_dir.path().join("fs.log");
+
+    {
+        let _guard = MountGuard::with_trace_log(&source, &mountpoint, &log);
+        // Created after mounting so readdirplus has not already looked it up
+        fs::write(source.join("audited.txt"), "").expect("Failed to write test file");
+        fs::metadata(mountpoint.join("audited.txt")).expect("Failed to stat file");
+    }
+
+    let expected = format!(
+        "lookup from uid={} gid={} pid=",
+        unsafe { libc::getuid() },
+        unsafe { libc::getgid() }
+    );
+    let log = fs::read_to_string(&log).expect("Failed to read log");
+    assert!(
+        log.lines().any(|line| line.contains(&expected)),
+        "No caller logged for lookup"
+    );
+}
+
+/// Count the lookups `ls -l` sends for the entries of a fresh directory
+fn lookups_for_ls_l(args: &[&str]) -> usize {
+    let (source, mountpoint, temp_dir) = setup_test_dirs();
+    let log = temp_dir.path().join("fs.log");
+    fs::create_dir(source.join("dir")).expect("Failed to create directory");
+    for i in 0..50 {
+        fs::write(source.join(format!("dir/entry{}", i)), "").expect("Failed to write test file");
+    }
+
+    {
+        let _guard = MountGuard::with_log_and_args(&source, &mountpoint, &log, args);
+        let output = std::process::Command::new("ls")
+            .arg("-l")
+            .arg(mountpoint.join("dir"))
+            .output()
+            .expect("Failed to run ls");
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout).lines().count(), 51);
+    }
+
+    let log = fs::read_to_string(&log).expect("Failed to read log");
+    log.lines()
+        .filter(|line| line.contains("lookup: ") && line.contains("name=\"entry"))
+        .count()
+}
+
+#[test]
+fn test_readdirplus_saves_lookups() {
+    // Without readdirplus every entry is looked up before it can be stat'ed
+    assert_eq!(lookups_for_ls_l(&["--no-readdirplus"]), 50);
+    assert_eq!(lookups_for_ls_l(&[]), 0);
+}
+
+#[test]
+fn test_negative_lookups_are_cached() {
+    let (source, mountpoint, temp_dir) = setup_test_dirs();
+    let log = temp_dir.path().join("fs.log");
+    fs::write(source.join("present.txt"), "").expect("Failed to write test file");
+
+    {
+        let _guard =
+            MountGuard::with_log_and_args(&source, &mountpoint, &log, &["--negative-ttl", "60"]);
+
+        for _ in 0..10 {
+            assert!(!mountpoint.join("missing.h").exists());
+        }
+
+        // Names created or renamed into through the mount replace the miss
+        assert!(!mountpoint.join("created").exists());
+        fs::write(mountpoint.join("created"), "new").expect("Failed to create file");
+        assert_eq!(
+            fs::read_to_string(mountpoint.join("created")).unwrap(),
+            "new"
+        );
+
+        assert!(!mountpoint.join("dir").exists());
+        fs::create_dir(mountpoint.join("dir")).expect("Failed to create directory");
+        assert!(mountpoint.join("dir").is_dir());
+
+        assert!(!mountpoint.join("renamed.txt").exists());
+        fs::rename(
+            mountpoint.join("present.txt"),
+            mountpoint.join("renamed.txt"),
+        )
+        .expect("Failed to rename file");
+        assert!(mountpoint.join("renamed.txt").exists());
+    }
+
+    // Only the first miss reached the source
+    let log = fs::read_to_string(&log).expect("Failed to read log");
+    let misses = log
+        .lines()
+        .filter(|line| line.contains("lookup: parent=1, name=\"missing.h\""))
+        .count();
+    assert_eq!(misses, 1, "Every miss was looked up again");
+}
+
+#[test]
+fn test_single_large_read() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+
+    // Create a file smaller than the read request
+    let size = 300 * 1000;
+    let data: Vec<u8> = (0..size).map(|i| (i % 256) as u8).collect();
+    fs::write(source.join("short.bin"), &data).expect("Failed to write test file");
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    // A single 1MB read must return the whole file, not a short chunk
+    let mut buffer = vec![0u8; 1024 * 1024];
+    let bytes_read = File::open(mountpoint.join("short.bin"))
+        .expect("Failed to open file")
+        .read(&mut buffer)
+        .expect("Failed to read file");
+    assert_eq!(bytes_read, size, "Short read");
+    assert!(buffer[..bytes_read] == data[..], "Content mismatch");
+}
+
+#[test]
+fn test_fallocate_preallocates() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    let path = mountpoint.join("prealloc.bin");
+    let file = fs::File::create(&path).expect("Failed to create file");
+    let before = fs::metadata(&path).expect("Failed to stat file").blocks();
+
+    let ret = unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, 1024 * 1024) };
+    assert_eq!(
+        ret,
+        0,
+        "fallocate failed: {}",
+        std::io::Error::last_os_error()
+    );
+
+    let metadata = fs::metadata(&path).expect("Failed to stat file");
+    assert_eq!(metadata.len(), 1024 * 1024);
+    assert!(
+        metadata.blocks() >= before + 2048,
+        "st_blocks did not grow: {} -> {}",
+        before,
+        metadata.blocks()
+    );
+}
+
+#[test]
+fn test_fallocate_punch_hole() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    let path = mountpoint.join("punch.bin");
+    fs::write(&path, vec![0xabu8; 1024 * 1024]).expect("Failed to write file");
+
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .expect("Failed to open file");
+    let before = fs::metadata(&path).expect("Failed to stat file").blocks();
+
+    let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    let ret = unsafe { libc::fallocate(file.as_raw_fd(), mode, 0, 512 * 1024) };
+    assert_eq!(
+        ret,
+        0,
+        "fallocate failed: {}",
+        std::io::Error::last_os_error()
+    );
+
+    let metadata = fs::metadata(&path).expect("Failed to stat file");
+    assert_eq!(
+        metadata.len(),
+        1024 * 1024,
+        "Size changed despite KEEP_SIZE"
+    );
+    assert!(
+        metadata.blocks() < before,
+        "st_blocks did not drop: {} -> {}",
+        before,
+        metadata.blocks()
+    );
+
+    // The punched range reads back as zeros
+    let content = fs::read(&path).expect("Failed to read file");
+    assert!(content[..512 * 1024].iter().all(|&b| b == 0));
+    assert!(content[512 * 1024..].iter().all(|&b| b == 0xab));
+}
+
+/// `du -k` and `du -k --apparent-size` output for `path`
+fn du(path: &std::path::Path) -> (String, String) {
+    let run = |apparent: bool| {
+        let mut command = std::process::Command::new("du");
+        command.arg("-k");
+        if apparent {
+            command.arg("--apparent-size");
+        }
+        let output = command.arg(path).output().expect("Failed to run du");
+        assert!(output.status.success(), "du failed");
+        let output = String::from_utf8(output.stdout).unwrap();
+        output.split_whitespace().next().unwrap().to_string()
+    };
+    (run(false), run(true))
+}
+
+#[test]
+fn test_du_matches_source_for_sparse_file() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+
+    // 64 MiB apparent, a few blocks allocated
+    let sparse = File::create(source.join("sparse.img")).expect("Failed to create file");
+    sparse
+        .set_len(64 * 1024 * 1024)
+        .expect("Failed to extend file");
+    sparse
+        .write_at(&[1u8; 8192], 1024 * 1024)
+        .expect("Failed to write file");
+    sparse.sync_all().expect("Failed to sync file");
+    drop(sparse);
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+    let assert_parity = || {
+        let mounted = fs::metadata(mountpoint.join("sparse.img")).expect("Failed to stat file");
+        let backing = fs::metadata(source.join("sparse.img")).expect("Failed to stat source");
+        assert_eq!(mounted.blocks(), backing.blocks());
+        assert_eq!(mounted.blksize(), backing.blksize());
+        assert_eq!(
+            du(&mountpoint.join("sparse.img")),
+            du(&source.join("sparse.img"))
+        );
+    };
+    assert_parity();
+    let (used, apparent) = du(&mountpoint.join("sparse.img"));
+    assert_ne!(used, apparent, "File is not sparse");
+
+    // Allocating and punching through the mount show up straight away
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .open(mountpoint.join("sparse.img"))
+        .expect("Failed to open file");
+    file.write_at(&[2u8; 1024 * 1024], 8 * 1024 * 1024)
+        .expect("Failed to write file");
+    file.sync_all().expect("Failed to sync file");
+    assert_parity();
+
+    let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    let ret = unsafe { libc::fallocate(file.as_raw_fd(), mode, 8 * 1024 * 1024, 1024 * 1024) };
+    assert_eq!(
+        ret,
+        0,
+        "fallocate failed: {}",
+        std::io::Error::last_os_error()
+    );
+    assert_parity();
+}
+
+#[test]
+fn test_lseek_data_and_hole() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+
+    // Data at 0 and 512 KiB with holes around it, up to 1 MiB
+    let sparse = fs::File::create(source.join("sparse.bin")).expect("Failed to create file");
+    sparse.set_len(1024 * 1024).expect("Failed to set length");
+    sparse
+        .write_all_at(&[1u8; 4096], 0)
+        .expect("Failed to write");
+    sparse
+        .write_all_at(&[2u8; 4096], 512 * 1024)
+        .expect("Failed to write");
+    sparse.sync_all().expect("Failed to sync");
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    let file = fs::File::open(mountpoint.join("sparse.bin")).expect("Failed to open file");
+    let fd = file.as_raw_fd();
+
+    let hole = unsafe { libc::lseek(fd, 0, libc::SEEK_HOLE) };
+    assert_eq!(hole, 4096, "First hole not found");
+
+    let data = unsafe { libc::lseek(fd, hole, libc::SEEK_DATA) };
+    assert_eq!(data, 512 * 1024, "Second data region not found");
+
+    let next_hole = unsafe { libc::lseek(fd, data, libc::SEEK_HOLE) };
+    assert_eq!(next_hole, 512 * 1024 + 4096);
+
+    // No data after the last region
+    let past = unsafe { libc::lseek(fd, next_hole, libc::SEEK_DATA) };
+    assert_eq!(past, -1);
+    assert_eq!(
+        std::io::Error::last_os_error().raw_os_error(),
+        Some(libc::ENXIO)
+    );
+
+    // Plain whence values still work
+    assert_eq!(unsafe { libc::lseek(fd, 0, libc::SEEK_END) }, 1024 * 1024);
+    assert_eq!(unsafe { libc::lseek(fd, 10, libc::SEEK_SET) }, 10);
+    assert_eq!(unsafe { libc::lseek(fd, 5, libc::SEEK_CUR) }, 15);
+}
+
+#[test]
+fn test_lock_conflict_between_handles() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("locked.txt"), "data").expect("Failed to write test file");
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    let open = || {
+        fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(mountpoint.join("locked.txt"))
+            .expect("Failed to open file")
+    };
+    let first = open();
+    let second = open();
+
+    ofd_lock(&first, libc::F_WRLCK).expect("Failed to take exclusive lock");
+
+    let result = ofd_lock(&second, libc::F_WRLCK);
+    assert_eq!(
+        result.unwrap_err().raw_os_error(),
+        Some(libc::EAGAIN),
+        "Conflicting lock was granted"
+    );
+
+    // Once released the other handle can take it
+    ofd_lock(&first, libc::F_UNLCK).expect("Failed to release lock");
+    ofd_lock(&second, libc::F_WRLCK).expect("Failed to take released lock");
+}
+
+#[test]
+fn test_lock_shared_with_source() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("locked.txt"), "data").expect("Failed to write test file");
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    // A lock held directly on the source is visible through the mount
+    let direct = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(source.join("locked.txt"))
+        .expect("Failed to open source file");
+    ofd_lock(&direct, libc::F_WRLCK).expect("Failed to lock source file");
+
+    let mounted = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(mountpoint.join("locked.txt"))
+        .expect("Failed to open file");
+    let result = ofd_lock(&mounted, libc::F_RDLCK);
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EAGAIN));
+
+    drop(direct);
+    ofd_lock(&mounted, libc::F_RDLCK).expect("Failed to lock after release");
+}
+
+#[test]
+fn test_blocking_lock_waiters_are_capped() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("locked.txt"), "data").expect("Failed to write test file");
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    let direct = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(source.join("locked.txt"))
+        .expect("Failed to open source file");
+    ofd_lock(&direct, libc::F_WRLCK).expect("Failed to lock source file");
+
+    let open = || fs::File::open(mountpoint.join("locked.txt")).expect("Failed to open file");
+    // As many as the daemon lets wait at once
+    let waiters: Vec<_> = (0..64)
+        .map(|_| {
+            let file = open();
+            std::thread::spawn(move || ofd_lock_wait(&file, libc::F_RDLCK))
+        })
+        .collect();
+    std::thread::sleep(Duration::from_secs(1));
+
+    let file = open();
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || sender.send(ofd_lock_wait(&file, libc::F_RDLCK)));
+    let result = receiver
+        .recv_timeout(Duration::from_secs(5))
+        .expect("A waiter past the cap was left waiting");
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::ENOLCK));
+
+    // The waiters within the cap get the lock once it is released
+    drop(direct);
+    for waiter in waiters {
+        waiter
+            .join()
+            .unwrap()
+            .expect("Failed to lock after release");
+    }
+}
+
+// flock(2) is not implemented: fuser 0.15 drops FUSE_LK_FLOCK, so the
+// daemon cannot tell flock from fcntl requests and FUSE_FLOCK_LOCKS stays
+// off. These locks never reach the daemon; the kernel keeps them for the
+// mount alone, and holders on the source are not seen.
+#[test]
+fn test_flock_stays_in_the_kernel() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("flocked.txt"), "data").expect("Failed to write test file");
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    let direct = fs::File::open(source.join("flocked.txt")).expect("Failed to open source file");
+    let ret = unsafe { libc::flock(direct.as_raw_fd(), libc::LOCK_EX) };
+    assert_eq!(ret, 0, "flock failed: {}", std::io::Error::last_os_error());
+
+    let first = fs::File::open(mountpoint.join("flocked.txt")).expect("Failed to open file");
+    let second = fs::File::open(mountpoint.join("flocked.txt")).expect("Failed to open file");
+
+    // Granted despite the holder on the source
+    let ret = unsafe { libc::flock(first.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    assert_eq!(ret, 0, "flock failed: {}", std::io::Error::last_os_error());
+
+    // A second exclusive lock on another description must not be granted
+    let ret = unsafe { libc::flock(second.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    assert_eq!(ret, -1, "Conflicting flock was granted");
+    assert_eq!(
+        std::io::Error::last_os_error().raw_os_error(),
+        Some(libc::EWOULDBLOCK)
+    );
+
+    // Closing the holder releases the lock
+    drop(first);
+    let ret = unsafe { libc::flock(second.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    assert_eq!(ret, 0, "flock failed: {}", std::io::Error::last_os_error());
+}
+
+#[test]
+fn test_seek_and_read() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+
+    // Create a test file
+    let test_content = "0123456789ABCDEFGHIJ";
+    fs::write(source.join("seek.txt"), test_content).expect("Failed to write test file");
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    // Open file and seek to middle, then read
+    let mut file = File::open(mountpoint.join("seek.txt")).expect("Failed to open file");
+    file.seek(SeekFrom::Start(10)).expect("Failed to seek");
+
+    let mut buffer = [0u8; 5];
+    file.read_exact(&mut buffer).expect("Failed to read");
+
+    assert_eq!(&buffer, b"ABCDE");
+}
+
+#[test]
+fn test_nested_directories() {
     let (source, mountpoint, _temp_dir) = setup_test_dirs();
 
     let _guard = MountGuard::new(&source, &mountpoint);
@@ -456,3 +3224,300 @@ fn test_file_metadata() {
         "Permissions mismatch"
     );
 }
+
+#[test]
+fn test_read_only_config() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("existing.txt"), "content").expect("Failed to write test file");
+
+    let mut config = fuse_passthrough::PassthroughConfig::new(&source);
+    config.read_only = true;
+    config.attr_ttl = Duration::ZERO;
+    let _session =
+        fuse_passthrough::mount_with_config(config, &mountpoint).expect("Failed to mount");
+    assert!(wait_for(|| mountpoint.join("existing.txt").exists()));
+
+    assert_eq!(
+        fs::read_to_string(mountpoint.join("existing.txt")).unwrap(),
+        "content"
+    );
+
+    let result = fs::write(mountpoint.join("new.txt"), "data");
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EROFS));
+    assert!(!source.join("new.txt").exists());
+}
+
+#[test]
+fn test_zero_ttl_sees_source_changes_immediately() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("file.txt"), "short").expect("Failed to write test file");
+
+    let _guard = MountGuard::with_args(
+        &source,
+        &mountpoint,
+        &["--attr-ttl", "0", "--entry-ttl", "0"],
+    );
+    let path = mountpoint.join("file.txt");
+    assert_eq!(fs::metadata(&path).unwrap().len(), 5);
+
+    fs::write(source.join("file.txt"), "much longer").expect("Failed to write test file");
+    assert_eq!(fs::metadata(&path).unwrap().len(), 11);
+    assert_eq!(fs::read_to_string(&path).unwrap(), "much longer");
+
+    fs::rename(source.join("file.txt"), source.join("moved.txt")).expect("Failed to rename");
+    assert!(!path.exists(), "Stale entry for a renamed file");
+    assert_eq!(
+        fs::read_to_string(mountpoint.join("moved.txt")).unwrap(),
+        "much longer"
+    );
+
+    fs::create_dir(source.join("dir")).expect("Failed to create directory");
+    assert!(mountpoint.join("dir").is_dir());
+    fs::remove_dir(source.join("dir")).expect("Failed to remove directory");
+    assert!(
+        !mountpoint.join("dir").exists(),
+        "Stale entry for a removed directory"
+    );
+}
+
+#[test]
+fn test_watch_source_invalidates_kernel_caches() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("file.txt"), "short").expect("Failed to write test file");
+    fs::create_dir(source.join("dir")).expect("Failed to create directory");
+
+    // Long enough that only an invalidation can explain a fresh answer
+    let _guard = MountGuard::with_args(
+        &source,
+        &mountpoint,
+        &["--watch-source", "--attr-ttl", "60", "--entry-ttl", "60"],
+    );
+    let path = mountpoint.join("file.txt");
+    assert_eq!(fs::read_to_string(&path).unwrap(), "short");
+
+    fs::write(source.join("file.txt"), "much longer").expect("Failed to write test file");
+    assert!(
+        wait_for(|| fs::metadata(&path).unwrap().len() == 11),
+        "Stale size served"
+    );
+    assert_eq!(fs::read_to_string(&path).unwrap(), "much longer");
+
+    // Directories created after mounting are watched too
+    assert!(!mountpoint.join("dir/new/nested.txt").exists());
+    fs::create_dir(source.join("dir/new")).expect("Failed to create directory");
+    fs::write(source.join("dir/new/nested.txt"), "a").expect("Failed to write file");
+    assert!(wait_for(|| mountpoint.join("dir/new/nested.txt").exists()));
+    fs::write(source.join("dir/new/nested.txt"), "abc").expect("Failed to write file");
+    assert!(wait_for(|| fs::metadata(
+        mountpoint.join("dir/new/nested.txt")
+    )
+    .is_ok_and(|metadata| metadata.len() == 3)));
+
+    fs::remove_file(source.join("file.txt")).expect("Failed to remove file");
+    assert!(
+        wait_for(|| !path.exists()),
+        "Stale entry for a removed file"
+    );
+}
+
+#[test]
+fn test_watch_source_keeps_pages_written_through_mount() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("file.bin"), vec![b'a'; 4096]).expect("Failed to write test file");
+
+    let _guard = MountGuard::with_args(&source, &mountpoint, &["--watch-source"]);
+    let file = File::options()
+        .read(true)
+        .write(true)
+        .open(mountpoint.join("file.bin"))
+        .unwrap();
+    let mut page = vec![0u8; 4096];
+    file.read_exact_at(&mut page, 0).unwrap();
+    file.write_all_at(&[b'b'; 4096], 0).unwrap();
+    // Give the watcher time to see the write
+    std::thread::sleep(Duration::from_secs(1));
+
+    let mapped = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            4096,
+            libc::PROT_READ,
+            libc::MAP_SHARED,
+            file.as_raw_fd(),
+            0,
+        )
+    };
+    assert_ne!(mapped, libc::MAP_FAILED);
+    let mut resident = 0u8;
+    let ret = unsafe { libc::mincore(mapped, 4096, &mut resident) };
+    unsafe { libc::munmap(mapped, 4096) };
+    assert_eq!(ret, 0);
+    assert_eq!(
+        resident & 1,
+        1,
+        "Page written through the mount was dropped"
+    );
+
+    file.read_exact_at(&mut page, 0).unwrap();
+    assert_eq!(page, vec![b'b'; 4096]);
+}
+
+#[test]
+fn test_unmount_through_api() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+
+    let config = fuse_passthrough::PassthroughConfig::new(&source);
+    let mount = fuse_passthrough::mount_with_config(config, &mountpoint).expect("Failed to mount");
+    assert_eq!(mount.mountpoint(), mountpoint);
+    assert!(wait_for(|| is_fuse_mount(&mountpoint)), "Not mounted");
+
+    fs::write(mountpoint.join("file.txt"), "content").expect("Failed to write file");
+
+    mount.unmount();
+    assert!(!is_fuse_mount(&mountpoint), "Still mounted after unmount");
+    assert_eq!(
+        fs::read_to_string(source.join("file.txt")).unwrap(),
+        "content"
+    );
+    fs::remove_dir(&mountpoint).expect("Mountpoint still busy");
+}
+
+#[test]
+fn test_attr_cache_serves_until_ttl() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    fs::write(source.join("file.txt"), "short").expect("Failed to write test file");
+
+    // The kernel asks every time, so only the daemon cache can answer stale
+    let mut config = fuse_passthrough::PassthroughConfig::new(&source);
+    config.attr_ttl = Duration::ZERO;
+    config.entry_ttl = Duration::ZERO;
+    config.attr_cache_ttl = Some(Duration::from_secs(2));
+    let _session =
+        fuse_passthrough::mount_with_config(config, &mountpoint).expect("Failed to mount");
+    assert!(wait_for(|| mountpoint.join("file.txt").exists()));
+
+    let path = mountpoint.join("file.txt");
+    assert_eq!(fs::metadata(&path).unwrap().len(), 5);
+
+    // Changed behind the mount's back: the cached size is still served
+    fs::write(source.join("file.txt"), "much longer").expect("Failed to write test file");
+    assert_eq!(fs::metadata(&path).unwrap().len(), 5);
+
+    // Then refreshed once the TTL runs out
+    assert!(wait_for(|| fs::metadata(&path).unwrap().len() == 11));
+
+    // Writes through the mount invalidate the entry immediately
+    fs::write(&path, "x").expect("Failed to write through mount");
+    assert_eq!(fs::metadata(&path).unwrap().len(), 1);
+}
+
+#[test]
+fn test_allow_root_mount_option() {
+    use fuse_passthrough::{MountOption, PassthroughConfig};
+
+    let mut config = PassthroughConfig::new("/");
+    config.allow_root = true;
+    let options = config.mount_options();
+    assert!(options.contains(&MountOption::AllowRoot));
+    assert!(!options.contains(&MountOption::AllowOther));
+
+    // allow_other already includes root
+    config.allow_other = true;
+    let options = config.mount_options();
+    assert!(options.contains(&MountOption::AllowOther));
+    assert!(!options.contains(&MountOption::AllowRoot));
+}
+
+#[test]
+fn test_fsname_and_subtype_in_mount_table() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+    let mount_entry = |mountpoint: &std::path::Path| {
+        let mountpoint = mountpoint.canonicalize().unwrap();
+        fs::read_to_string("/proc/mounts")
+            .unwrap()
+            .lines()
+            .map(|line| {
+                line.split_whitespace()
+                    .map(str::to_string)
+                    .collect::<Vec<_>>()
+            })
+            .find(|fields| fields[1] == mountpoint.to_str().unwrap())
+            .expect("Mount not listed")
+    };
+
+    {
+        let _guard = MountGuard::new(&source, &mountpoint);
+        let fields = mount_entry(&mountpoint);
+        assert_eq!(fields[0], "source", "Default fsname is not the source name");
+    }
+
+    let _guard = MountGuard::with_args(
+        &source,
+        &mountpoint,
+        &["--fsname", "backup-mirror", "--subtype", "passthrough"],
+    );
+    let fields = mount_entry(&mountpoint);
+    assert_eq!(fields[0], "backup-mirror");
+    assert_eq!(fields[2], "fuse.passthrough");
+}
+
+#[test]
+fn test_stats_count_operations() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+
+    let config = fuse_passthrough::PassthroughConfig::new(&source);
+    let options = config.mount_options();
+    let fs = fuse_passthrough::PassthroughFS::with_config(config).unwrap();
+    let stats = fs.stats_handle();
+    let _session = fuse_passthrough::mount_fs(fs, &mountpoint, &options).expect("Failed to mount");
+    assert!(wait_for(|| mountpoint.read_dir().is_ok()));
+    let before = stats.stats();
+
+    fs::write(mountpoint.join("counted.txt"), "0123456789").expect("Failed to write file");
+    assert_eq!(
+        fs::read_to_string(mountpoint.join("counted.txt")).unwrap(),
+        "0123456789"
+    );
+    fs::create_dir(mountpoint.join("dir")).expect("Failed to create directory");
+    fs::rename(
+        mountpoint.join("counted.txt"),
+        mountpoint.join("dir/moved.txt"),
+    )
+    .expect("Failed to rename file");
+    fs::remove_file(mountpoint.join("dir/moved.txt")).expect("Failed to remove file");
+    fs::remove_dir(mountpoint.join("dir")).expect("Failed to remove directory");
+
+    let after = stats.stats();
+    assert!(after.lookup > before.lookup);
+    assert_eq!(after.create - before.create, 1);
+    assert!(after.write > before.write);
+    assert_eq!(after.write_bytes - before.write_bytes, 10);
+    assert!(after.open > before.open);
+    assert_eq!(after.read_bytes - before.read_bytes, 10);
+    assert_eq!(after.mkdir - before.mkdir, 1);
+    assert_eq!(after.rename - before.rename, 1);
+    assert_eq!(after.unlink - before.unlink, 1);
+    assert_eq!(after.rmdir - before.rmdir, 1);
+}
+
+#[test]
+fn test_statfs() {
+    let (source, mountpoint, _temp_dir) = setup_test_dirs();
+
+    let _guard = MountGuard::new(&source, &mountpoint);
+
+    let mount_stat = statvfs(&mountpoint).expect("Failed to statvfs mountpoint");
+    let source_stat = statvfs(&source).expect("Failed to statvfs source");
+
+    // Totals are fixed, so they must match the source filesystem exactly
+    assert!(mount_stat.f_blocks > 0, "Total blocks reported as zero");
+    assert_eq!(
+        mount_stat.f_blocks as u64 * mount_stat.f_frsize as u64,
+        source_stat.f_blocks as u64 * source_stat.f_frsize as u64,
+        "Total size mismatch"
+    );
+    assert_eq!(
+        mount_stat.f_files, source_stat.f_files,
+        "Inode count mismatch"
+    );
+}